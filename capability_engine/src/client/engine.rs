@@ -1,14 +1,16 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, fmt, ops::ControlFlow, rc::Rc};
 
 use crate::core::domain::{Field, Status};
 use crate::core::memory_region::Attributes;
 use crate::{
     core::{
-        capability::{CapaError, CapaRef, Capability, Ownership},
+        capability::{same_capa, CapaError, CapaRef, Capability, Ownership},
         domain::{
             CapaWrapper, Domain, FieldType, InterruptPolicy, LocalCapa, MonitorAPI, Policies,
+            VectorPolicy,
         },
         memory_region::{Access, MemoryRegion, RegionKind, Remapped, Rights},
+        parser::Parser,
     },
     CallInterface, EngineInterface,
 };
@@ -24,9 +26,36 @@ pub enum ClientError {
     FailedAttest,
     FailedRevoke,
     FailedCreate,
+    FailedSwitch,
     CapaError(CapaError),
 }
 
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::CapaError(e) => write!(f, "capability error: {}", e),
+            other => write!(f, "unexpected server response: {:?}", other),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::CapaError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<CapaError> for ClientError {
+    fn from(e: CapaError) -> Self {
+        ClientError::CapaError(e)
+    }
+}
+
+pub type ClientResultT<T> = Result<T, ClientError>;
+
 #[derive(Debug)]
 pub enum ClientResult {
     SingleValue(u64),
@@ -35,25 +64,17 @@ pub enum ClientResult {
 }
 
 impl ClientResult {
-    pub fn wrap_empty(input: Result<(), CapaError>) -> Result<ClientResult, ClientError> {
-        match input {
-            Ok(()) => Ok(Self::EmptyValue),
-            Err(e) => Err(ClientError::CapaError(e)),
-        }
+    pub fn wrap_empty(input: Result<(), CapaError>) -> ClientResultT<ClientResult> {
+        input?;
+        Ok(Self::EmptyValue)
     }
 
-    pub fn wrap_value(input: Result<u64, CapaError>) -> Result<ClientResult, ClientError> {
-        match input {
-            Ok(v) => Ok(Self::SingleValue(v)),
-            Err(e) => Err(ClientError::CapaError(e)),
-        }
+    pub fn wrap_value(input: Result<u64, CapaError>) -> ClientResultT<ClientResult> {
+        Ok(Self::SingleValue(input?))
     }
 
-    pub fn wrap_string(input: Result<String, CapaError>) -> Result<ClientResult, ClientError> {
-        match input {
-            Ok(s) => Ok(Self::StringValue(s)),
-            Err(e) => Err(ClientError::CapaError(e)),
-        }
+    pub fn wrap_string(input: Result<String, CapaError>) -> ClientResultT<ClientResult> {
+        Ok(Self::StringValue(input?))
     }
 }
 
@@ -83,7 +104,7 @@ impl<T: CommunicationInterface> Engine<T> {
     ) -> Result<LocalCapa, CapaError> {
         let local_handle = {
             let dom = &mut domain.borrow_mut();
-            dom.data.install(CapaWrapper::Region(region.clone()))
+            dom.data.install(CapaWrapper::Region(region.clone()))?
         };
         let reg = &mut region.borrow_mut();
         reg.owned = Ownership::new(Rc::downgrade(domain), local_handle);
@@ -235,36 +256,14 @@ impl<T: CommunicationInterface> EngineInterface for Engine<T> {
 
         match res {
             ClientResult::SingleValue(child) => {
-                // Now set the interrutps.
-                for (i, v) in interrupts.vectors.iter().enumerate() {
-                    let args = [
-                        child,
-                        0,
-                        FieldType::InterruptVisibility as u64,
-                        i as u64,
-                        v.visibility.bits() as u64,
-                        0,
-                    ];
-                    self.platform.send(CallInterface::SET, &args)?;
-                    let args = [
-                        child,
-                        0,
-                        FieldType::InterruptRead as u64,
-                        i as u64,
-                        v.read_set as u64,
-                        0,
-                    ];
-                    self.platform.send(CallInterface::SET, &args)?;
-                    let args = [
-                        child,
-                        0,
-                        FieldType::InterruptWrite as u64,
-                        i as u64,
-                        v.write_set as u64,
-                        0,
-                    ];
-                    self.platform.send(CallInterface::SET, &args)?;
-                }
+                // The server starts from default_none, only send the runs that differ.
+                let base = InterruptPolicy::default_none();
+                let runs: Vec<(usize, usize, VectorPolicy)> = interrupts
+                    .runs()
+                    .into_iter()
+                    .filter(|(first, _, policy)| *policy != base.vectors[*first])
+                    .collect();
+                self.send_interrupts(child, &runs)?;
                 return Ok(child as LocalCapa);
             }
             _ => return Err(ClientError::FailedCreate),
@@ -291,9 +290,25 @@ impl<T: CommunicationInterface> EngineInterface for Engine<T> {
     fn switch(
         &mut self,
         _domain: Self::CapaReference,
-        _capa: Self::OwnedCapa,
+        capa: Self::OwnedCapa,
     ) -> Result<(), Self::CapabilityError> {
-        todo!()
+        let args: [u64; 6] = [capa, 0, 0, 0, 0, 0];
+        let res = self.platform.send(CallInterface::SWITCH, &args)?;
+        match res {
+            ClientResult::EmptyValue => {
+                // The active domain changed, follow it locally.
+                let next = self
+                    .current
+                    .borrow()
+                    .data
+                    .capabilities
+                    .get(&capa)?
+                    .as_domain()?;
+                self.current = next;
+                Ok(())
+            }
+            _ => Err(ClientError::FailedSwitch),
+        }
     }
 
     fn revoke(
@@ -397,10 +412,10 @@ impl<T: CommunicationInterface> Engine<T> {
         region: &CapaRef<MemoryRegion>,
         start: u64,
         size: u64,
-        rights: u8,
+        rights: Rights,
     ) -> Result<CapaRef<MemoryRegion>, ClientError> {
         let local = region.borrow().owned.handle;
-        let access = Access::new(start, size, Rights::from_bits_truncate(rights));
+        let access = Access::new(start, size, rights);
         let alias = self.alias(self.current.clone(), local, &access)?;
         Ok(self.add_region(alias, region, &access, RegionKind::Alias))
     }
@@ -410,10 +425,10 @@ impl<T: CommunicationInterface> Engine<T> {
         region: &CapaRef<MemoryRegion>,
         start: u64,
         size: u64,
-        rights: u8,
+        rights: Rights,
     ) -> Result<CapaRef<MemoryRegion>, ClientError> {
         let local = region.borrow().owned.handle;
-        let access = Access::new(start, size, Rights::from_bits_truncate(rights));
+        let access = Access::new(start, size, rights);
         let carve = self.carve(self.current.clone(), local, &access)?;
         // Now make sure we update the state.
         Ok(self.add_region(carve, region, &access, RegionKind::Carve))
@@ -450,26 +465,93 @@ impl<T: CommunicationInterface> Engine<T> {
         self.attest(self.current.clone(), idx)
     }
 
-    pub fn r_revoke_region(&mut self, child: &CapaRef<MemoryRegion>) -> Result<(), ClientError> {
+    // Transmit the policy run-length encoded, returns the number of calls.
+    fn send_interrupts(
+        &mut self,
+        child: LocalCapa,
+        runs: &[(usize, usize, VectorPolicy)],
+    ) -> ClientResultT<usize> {
+        for (first, count, vector) in runs.iter() {
+            let args = [
+                child,
+                *first as u64,
+                *count as u64,
+                vector.visibility.bits() as u64,
+                vector.read_set,
+                vector.write_set,
+            ];
+            self.platform.send(CallInterface::INTERRUPTS, &args)?;
+        }
+        Ok(runs.len())
+    }
+
+    /// Replace the child's interrupt policy, returns the number of calls issued.
+    pub fn r_set_interrupts(
+        &mut self,
+        child: &CapaRef<Domain>,
+        policy: &InterruptPolicy,
+    ) -> ClientResultT<usize> {
+        let local = child.borrow().owned.handle;
+        let calls = self.send_interrupts(local, &policy.runs())?;
+        child.borrow_mut().data.policies.interrupts = *policy;
+        Ok(calls)
+    }
+
+    /// Attach to a platform whose server is already set up.
+    /// The local state is fetched from the server.
+    pub fn attach(platform: T) -> Result<Self, ClientError> {
+        let placeholder = Domain::new(Policies::new(
+            0,
+            MonitorAPI::empty(),
+            InterruptPolicy::default_none(),
+        ));
+        let mut engine = Self {
+            platform,
+            current: Rc::new(RefCell::new(Capability::<Domain>::new(placeholder))),
+        };
+        engine.resync()?;
+        Ok(engine)
+    }
+
+    /// Rebuild the local state from the server's attestation.
+    pub fn resync(&mut self) -> Result<(), ClientError> {
+        let attestation = self.attest(self.current.clone(), None)?;
+        let mut parser = Parser::new();
+        parser.parse_attestation(attestation)?;
+        let td0 = parser.domains.get("td0").ok_or(CapaError::ParserDomain)?;
+        self.current = td0.clone();
+        Ok(())
+    }
+
+    pub fn r_enumerate(&mut self, region: &CapaRef<MemoryRegion>) -> Result<String, ClientError> {
+        let local = region.borrow().owned.handle;
+        self.enumerate(self.current.clone(), local)
+    }
+
+    pub fn r_enumerate_domain(&mut self, child: &CapaRef<Domain>) -> Result<String, ClientError> {
+        let local = child.borrow().owned.handle;
+        self.enumerate(self.current.clone(), local)
+    }
+
+    pub fn r_revoke_region(&mut self, child: &CapaRef<MemoryRegion>) -> ClientResultT<()> {
         let parent = child
             .borrow()
             .parent
             .upgrade()
             .ok_or(ClientError::FailedRevoke)?;
         // Check it belongs to us.
-        if parent
+        let owner = parent
             .borrow()
             .owned
             .owner
             .upgrade()
-            .ok_or(ClientError::FailedRevoke)?
-            != self.current
-        {
+            .ok_or(ClientError::FailedRevoke)?;
+        if !same_capa(&owner, &self.current) {
             return Err(ClientError::FailedRevoke);
         }
         let mut idx = -1;
         for (i, c) in parent.borrow().children.iter().enumerate() {
-            if c == child {
+            if same_capa(c, child) {
                 idx = i as i32;
                 break;
             }
@@ -488,39 +570,29 @@ impl<T: CommunicationInterface> Engine<T> {
         // It got revoked, time to update.
         parent
             .borrow_mut()
-            .revoke_child(&child, &mut Self::revoke_region_handler)
-            .map_err(|e| ClientError::CapaError(e))?;
+            .revoke_child(&child, &mut Self::revoke_region_handler)?;
         Ok(())
     }
 
-    pub fn r_revoke_child(&mut self, child: &CapaRef<Domain>) -> Result<(), ClientError> {
+    pub fn r_revoke_child(&mut self, child: &CapaRef<Domain>) -> ClientResultT<()> {
         let local = child.borrow().owned.handle;
         self.revoke(self.current.clone(), local, 0)?;
         let dom = &mut self.current.borrow_mut();
-        let d = dom
-            .data
-            .capabilities
-            .get(&local)
-            .map_err(|e| ClientError::CapaError(e))?
-            .as_domain()
-            .map_err(|e| ClientError::CapaError(e))?;
+        let d = dom.data.capabilities.get(&local)?.as_domain()?;
 
         dom.revoke_child(&d, &mut |c: &mut Capability<Domain>| {
             c.data.status = crate::core::domain::Status::Revoked;
             c.data
                 .capabilities
                 .foreach_region_mut(|c: &CapaRef<MemoryRegion>| {
-                    Capability::<MemoryRegion>::revoke_node(c.clone(), &mut |_c| Ok(()))
+                    Capability::<MemoryRegion>::revoke_node(c.clone(), &mut |_c| Ok(()))?;
+                    Ok(ControlFlow::Continue(()))
                 })?;
             c.data.capabilities.reset();
             Ok(())
-        })
-        .map_err(|e| ClientError::CapaError(e))?;
+        })?;
         // Remove the handle
-        dom.data
-            .capabilities
-            .remove(&local)
-            .map_err(|e| ClientError::CapaError(e))?;
+        dom.data.capabilities.remove(&local)?;
         Ok(())
     }
 
@@ -559,7 +631,7 @@ impl<T: CommunicationInterface> Engine<T> {
             let dest_capa = child
                 .borrow_mut()
                 .data
-                .install(CapaWrapper::Region(region.clone()));
+                .install(CapaWrapper::Region(region.clone()))?;
             region.borrow_mut().owned = Ownership::new(Rc::downgrade(&child), dest_capa);
         }
         Ok(())
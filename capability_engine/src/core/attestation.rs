@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+
+use crate::core::capability::{CapaRef, Capability};
+use crate::core::domain::{CapaKind, CapaWrapper, Domain, LocalCapa, Policies, Status};
+use crate::core::memory_region::{
+    Access, Attributes, MemoryRegion, RegionKind, Remapped, Status as MStatus,
+};
+
+// Prefix of the line carrying the checksum of a signed attestation.
+pub const CHECKSUM_PREFIX: &str = "|checksum: ";
+
+/// 64-bit FNV-1a hash, cheap tamper evidence rather than a cryptographic hash.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    bytes
+        .iter()
+        .fold(OFFSET, |hash, b| (hash ^ *b as u64).wrapping_mul(PRIME))
+}
+
+/// Append the checksum line to an attestation.
+pub fn sign_attestation(mut attestation: String) -> String {
+    let checksum = fnv1a(attestation.as_bytes());
+    attestation.push_str(&format!("{}0x{:x}\n", CHECKSUM_PREFIX, checksum));
+    attestation
+}
+
+// Structured counterpart of the string attestation.
+// Domains are numbered like in the display: 0 is the attested domain,
+// its children follow in creation order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttestationReport {
+    pub domains: Vec<DomainReport>,
+    pub regions: Vec<RegionReport>,
+    pub indices: BTreeMap<LocalCapa, CapaKind>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainReport {
+    pub id: usize,
+    pub status: Status,
+    pub policies: Policies,
+    pub nb_regions: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionReport {
+    // Index of the owner in the report's domains.
+    pub owner: usize,
+    pub handle: LocalCapa,
+    pub kind: RegionKind,
+    pub status: MStatus,
+    pub access: Access,
+    pub remapped: Remapped,
+    pub attributes: Attributes,
+}
+
+impl Capability<Domain> {
+    pub fn attest_structured(&self) -> AttestationReport {
+        let mut report = AttestationReport {
+            domains: Vec::new(),
+            regions: Vec::new(),
+            indices: self.indices(),
+        };
+        report.add_domain(self);
+        for child in &self.children {
+            report.add_domain(&child.borrow());
+        }
+        report
+    }
+
+    /// The display attestation split in chunks of at most `max_len` bytes.
+    /// Chunks end on line boundaries unless a single line is longer than
+    /// `max_len`, concatenating them gives back the full attestation.
+    pub fn attest_chunks(&self, max_len: usize) -> impl Iterator<Item = String> {
+        let max_len = max_len.max(1);
+        let full = format!("{}", self);
+        let mut chunks: Vec<String> = Vec::new();
+        let mut current = String::new();
+        for line in full.split_inclusive('\n') {
+            if current.len() + line.len() > max_len && !current.is_empty() {
+                chunks.push(std::mem::take(&mut current));
+            }
+            if line.len() <= max_len {
+                current.push_str(line);
+                continue;
+            }
+            // Too long for one chunk, cut it on char boundaries.
+            let mut rest = line;
+            while rest.len() > max_len {
+                let mut cut = max_len;
+                while cut > 0 && !rest.is_char_boundary(cut) {
+                    cut -= 1;
+                }
+                if cut == 0 {
+                    cut = rest.chars().next().map_or(1, char::len_utf8);
+                }
+                chunks.push(rest[..cut].to_string());
+                rest = &rest[cut..];
+            }
+            current.push_str(rest);
+        }
+        if !current.is_empty() {
+            chunks.push(current);
+        }
+        chunks.into_iter()
+    }
+}
+
+impl AttestationReport {
+    fn add_domain(&mut self, domain: &Capability<Domain>) {
+        let id = self.domains.len();
+        let regions: Vec<(LocalCapa, CapaRef<MemoryRegion>)> = domain
+            .data
+            .capabilities
+            .capabilities
+            .iter()
+            .filter_map(|(h, c)| match c {
+                CapaWrapper::Region(r) => Some((*h, r.clone())),
+                _ => None,
+            })
+            .collect();
+        self.domains.push(DomainReport {
+            id,
+            status: domain.data.status,
+            policies: domain.data.policies,
+            nb_regions: regions.len(),
+        });
+        for (handle, r) in regions {
+            let region = &r.borrow().data;
+            self.regions.push(RegionReport {
+                owner: id,
+                handle,
+                kind: region.kind,
+                status: region.status,
+                access: region.access,
+                remapped: region.remapped,
+                attributes: region.attributes,
+            });
+        }
+    }
+}
+
+// Structured counterpart of a region enumeration, including its subtree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionNode {
+    pub kind: RegionKind,
+    pub access: Access,
+    pub remapped: Remapped,
+    pub attributes: Attributes,
+    pub children: Vec<RegionNode>,
+}
+
+impl Capability<MemoryRegion> {
+    pub fn enumerate_tree(&self) -> RegionNode {
+        RegionNode {
+            kind: self.data.kind,
+            access: self.data.access,
+            remapped: self.data.remapped,
+            attributes: self.data.attributes,
+            children: self
+                .children
+                .iter()
+                .map(|c| c.borrow().enumerate_tree())
+                .collect(),
+        }
+    }
+}
@@ -1,10 +1,11 @@
 use crate::core::domain::{
-    CapaWrapper, Domain, Field, FieldType, LocalCapa, MonitorAPI, Status as DStatus,
+    CapaKind, CapaWrapper, Domain, Field, FieldType, LocalCapa, MonitorAPI, Status as DStatus,
 };
 use crate::core::memory_region::{
-    Access, Attributes, MemoryRegion, RegionKind, Remapped, Status, ViewRegion,
+    Access, Attributes, MemoryRegion, RegionKind, Rights, Status, ViewRegion,
 };
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::rc::{Rc, Weak};
 
 use super::update::{OperationUpdate, Update};
@@ -13,6 +14,11 @@ pub type CapaRef<T> = Rc<RefCell<Capability<T>>>;
 
 pub type WeakRef<T> = Weak<RefCell<Capability<T>>>;
 
+/// Identity of capabilities, `PartialEq` on `Capability` compares the data instead.
+pub fn same_capa<T>(a: &CapaRef<T>, b: &CapaRef<T>) -> bool {
+    Rc::ptr_eq(a, b)
+}
+
 #[derive(Debug)]
 pub struct Ownership {
     pub owner: WeakRef<Domain>,
@@ -58,6 +64,15 @@ pub enum CapaError {
     IncompatibleRemap,
     InvalidField,
     InvalidValue,
+    NoCoresAssigned,
+    CarveOfAlias,
+    TooManyCapabilities,
+    QuotaExceeded,
+    CycleDetected,
+    DomainRevoked,
+    InvalidRemap,
+    MaxDepthExceeded,
+    SelfSend,
     // For parsing
     ParserDomain,
     ParserRegion,
@@ -86,6 +101,18 @@ where
         self.children.push(child)
     }
 
+    /// Walks up the parents looking for other.
+    pub fn has_ancestor(&self, other: &CapaRef<T>) -> bool {
+        let mut current = self.parent.upgrade();
+        while let Some(p) = current {
+            if same_capa(&p, other) {
+                return true;
+            }
+            current = p.borrow().parent.upgrade();
+        }
+        false
+    }
+
     pub fn revoke_node<F>(node: CapaRef<T>, on_revoke: &mut F) -> Result<(), CapaError>
     where
         F: FnMut(&mut Capability<T>) -> Result<(), CapaError>,
@@ -110,7 +137,7 @@ where
     where
         F: FnMut(&mut Capability<T>) -> Result<(), CapaError>,
     {
-        if let Some(pos) = self.children.iter().position(|c| Rc::ptr_eq(c, child)) {
+        if let Some(pos) = self.children.iter().position(|c| same_capa(c, child)) {
             // Safely remove the child and pass it for revocation
             let child = self.children.remove(pos);
             // Remove the backward edge to the parent.
@@ -140,9 +167,14 @@ where
     where
         F: FnMut(&Capability<T>) -> Result<(), CapaError>,
     {
+        // Explicit worklist so deep delegation chains cannot overflow the stack.
+        // Children are pushed in reverse to keep the pre-order.
         visit(self)?;
-        for c in &self.children {
-            c.borrow().dfs(visit)?;
+        let mut stack: Vec<CapaRef<T>> = self.children.iter().rev().cloned().collect();
+        while let Some(current) = stack.pop() {
+            let node = current.borrow();
+            visit(&node)?;
+            stack.extend(node.children.iter().rev().cloned());
         }
         Ok(())
     }
@@ -160,42 +192,57 @@ impl Capability<MemoryRegion> {
     }
 
     pub fn alias(&mut self, access: &Access) -> Result<CapaRef<MemoryRegion>, CapaError> {
-        self.alias_carve_logic(access, RegionKind::Alias)
+        self.alias_carve_logic(access, RegionKind::Alias, false)
     }
 
     pub fn carve(&mut self, access: &Access) -> Result<CapaRef<MemoryRegion>, CapaError> {
-        self.alias_carve_logic(access, RegionKind::Carve)
+        self.alias_carve_logic(access, RegionKind::Carve, false)
     }
 
     pub fn alias_carve_logic(
         &mut self,
         access: &Access,
         kind_op: RegionKind,
+        inherit_attributes: bool,
     ) -> Result<CapaRef<MemoryRegion>, CapaError> {
-        //TODO: bug should not be able to carve an aliased region.
+        if !access.is_valid() {
+            return Err(CapaError::InvalidAccess);
+        }
+        // Shared memory cannot provide exclusive access.
+        if kind_op == RegionKind::Carve && self.data.status == Status::Aliased {
+            return Err(CapaError::CarveOfAlias);
+        }
+        // A child never gets rights the parent does not have.
+        if !access.rights.is_subset_of(&self.data.access.rights) {
+            return Err(CapaError::InvalidAccess);
+        }
         if !self.contained(access, kind_op == RegionKind::Carve) {
             return Err(CapaError::InvalidAccess);
         }
         // Compute the remapping
-        let remapping = match self.data.remapped {
-            Remapped::Identity => Remapped::Identity,
-            Remapped::Remapped(s) => {
-                Remapped::Remapped(s + (access.start - self.data.access.start))
-            }
-        };
-        // Compute the status: alias -> aliased, carve inherit
-        let status_obtained = if kind_op == RegionKind::Alias {
-            Status::Aliased
-        } else {
-            self.data.status
+        let remapping = self
+            .data
+            .remapped
+            .offset(access.start - self.data.access.start);
+        // Compute the status, it never upgrades along a chain: an alias is
+        // aliased and a carve inherits, carves of aliased regions are rejected above.
+        let status_obtained = match kind_op {
+            RegionKind::Alias => Status::Aliased,
+            RegionKind::Carve => self.data.status,
         };
+        debug_assert!(self.data.status == Status::Exclusive || status_obtained == Status::Aliased);
         // Create the region
         let region = MemoryRegion {
             kind: kind_op,
             status: status_obtained,
             access: *access,
-            // A new region has no attributes.
-            attributes: Attributes::NONE,
+            // A new region has no attributes, except an inherited measurement.
+            // VITAL and CLEAN describe how the parent was sent, never inherited.
+            attributes: if inherit_attributes {
+                self.data.attributes & Attributes::HASH
+            } else {
+                Attributes::NONE
+            },
             remapped: remapping,
         };
         let new_capa = Self::new(region);
@@ -205,12 +252,7 @@ impl Capability<MemoryRegion> {
     }
 
     pub fn view(&self) -> Vec<ViewRegion> {
-        let mut views = Vec::new();
-        // This is the range we consider.
-        let mut start = self.data.access.start;
-
-        // Constants.
-        let base = self.data.access.start;
+        let mut views = vec![ViewRegion::new(self.data.access, self.data.remapped)];
 
         // Children are sorted.
         let mut sorted = self.children.clone();
@@ -228,39 +270,76 @@ impl Capability<MemoryRegion> {
                 continue;
             }
             // It is a carve, the segment loses access.
-            if start <= c_borrow.data.access.start {
-                let r = match self.data.remapped {
-                    Remapped::Identity => Remapped::Identity,
-                    Remapped::Remapped(x) => Remapped::Remapped(x + (start - base)),
-                };
-                if c_borrow.data.access.start != start {
-                    views.push(ViewRegion {
-                        access: Access {
-                            start,
-                            size: (c_borrow.data.access.start - start),
-                            rights: self.data.access.rights,
-                        },
-                        remap: r,
-                    });
-                }
-                start = c_borrow.data.access.end();
-            }
+            views = views
+                .iter()
+                .flat_map(|v| v.subtract(&c_borrow.data.access))
+                .collect();
         }
-        if start < self.data.access.end() {
-            let r = match self.data.remapped {
-                Remapped::Identity => Remapped::Identity,
-                Remapped::Remapped(x) => Remapped::Remapped(x + (start - base)),
-            };
-            views.push(ViewRegion {
-                access: Access {
-                    start,
-                    size: self.data.access.end() - start,
-                    rights: self.data.access.rights,
-                },
-                remap: r,
-            });
+
+        views
+    }
+
+    /// Whether the guest range is fully backed by the region, carved holes excluded.
+    pub fn contains_gva(&self, gva_start: u64, size: u64) -> bool {
+        let Some(end) = gva_start.checked_add(size) else {
+            return false;
+        };
+        if size == 0 {
+            return false;
         }
+        let mut views = self.view();
+        views.sort_by_key(|v| v.active_start());
+        // Advance a cursor over contiguous guest fragments.
+        let mut cursor = gva_start;
+        for v in views.iter() {
+            if v.contains_gva(cursor) {
+                cursor = v.active_end();
+            }
+            if cursor >= end {
+                return true;
+            }
+        }
+        false
+    }
 
+    /// Same as `view` (only carves are removed) but fragments are also split
+    /// at alias boundaries, so every aliased range shows up as its own entry.
+    /// The flag is set on the fragments covered by an alias child.
+    pub fn view_with_aliases(&self) -> Vec<(ViewRegion, bool)> {
+        let mut views: Vec<(ViewRegion, bool)> =
+            self.view().into_iter().map(|v| (v, false)).collect();
+        for c in self.children.iter() {
+            let c_borrow = c.borrow();
+            if c_borrow.data.kind != RegionKind::Alias {
+                continue;
+            }
+            let alias = c_borrow.data.access;
+            views = views
+                .iter()
+                .flat_map(|(v, aliased)| {
+                    if !v.access.intersect(&alias) {
+                        return vec![(*v, *aliased)];
+                    }
+                    // Keep the outside fragments and add back the overlap.
+                    let start = u64::max(v.access.start, alias.start);
+                    let end = u64::min(v.access.end(), alias.end());
+                    let mut parts: Vec<(ViewRegion, bool)> = v
+                        .subtract(&alias)
+                        .into_iter()
+                        .map(|p| (p, *aliased))
+                        .collect();
+                    parts.push((
+                        ViewRegion::new(
+                            Access::new(start, end - start, v.access.rights),
+                            v.remap.offset(start - v.access.start),
+                        ),
+                        true,
+                    ));
+                    parts
+                })
+                .collect();
+        }
+        views.sort_by_key(|(v, _)| v.access.start);
         views
     }
 
@@ -270,6 +349,8 @@ impl Capability<MemoryRegion> {
         vec![ViewRegion::new(self.data.access, self.data.remapped)]
     }
 
+    // Carve children are always exclusive, so no alias or carve may overlap them.
+    // Strict additionally forbids overlapping aliases (used for carves).
     pub fn contained(&self, access: &Access, strict: bool) -> bool {
         // Easy case, it's not even contained without considering children.
         if !access.contained(&self.data.access) {
@@ -288,6 +369,45 @@ impl Capability<MemoryRegion> {
         return true;
     }
 
+    /// Detach an exclusive carve from its parent and make it a root region.
+    /// Every ancestor gives up the range for good, so the carve must sit at
+    /// an edge of each of them, otherwise they would be left with a hole.
+    pub fn promote(capa: &CapaRef<MemoryRegion>) -> Result<(), CapaError> {
+        let (access, parent) = {
+            let current = capa.borrow();
+            if current.data.kind != RegionKind::Carve || current.data.status != Status::Exclusive {
+                return Err(CapaError::InvalidAccess);
+            }
+            let parent = current.parent.upgrade().ok_or(CapaError::InvalidValue)?;
+            (current.data.access, parent)
+        };
+        // Validate the whole chain before modifying anything.
+        let mut ancestors = Vec::new();
+        let mut current = Some(parent.clone());
+        while let Some(ancestor) = current {
+            let anc = ancestor.borrow();
+            let at_edge =
+                anc.data.access.start == access.start || anc.data.access.end() == access.end();
+            if !at_edge || anc.data.access.size <= access.size {
+                return Err(CapaError::InvalidAccess);
+            }
+            current = anc.parent.upgrade();
+            drop(anc);
+            ancestors.push(ancestor);
+        }
+        for ancestor in ancestors {
+            let region = &mut ancestor.borrow_mut().data;
+            if region.access.start == access.start {
+                region.access.start = access.end();
+                region.remapped = region.remapped.offset(access.size);
+            }
+            region.access.size -= access.size;
+        }
+        parent.borrow_mut().children.retain(|c| !same_capa(c, capa));
+        capa.borrow_mut().parent = WeakRef::new();
+        Ok(())
+    }
+
     // We should implement two on_revoke.
     // One will do the dfs, the other will consider local changes
     pub fn on_revoke(&self, operation: &mut OperationUpdate) -> Result<(), CapaError> {
@@ -312,13 +432,18 @@ impl Capability<MemoryRegion> {
             // We lose this access.
             operation.add(Update::ChangeMemory {
                 dom: capa.owned.owner.clone(),
+                range: capa.data.access,
+                rights: capa.data.access.rights,
             });
 
             // For a carve the parent is affected as well.
             if capa.data.kind == RegionKind::Carve {
                 if let Some(parent) = capa.parent.upgrade() {
+                    // Report what the carve held so the parent can restore its own rights.
                     operation.add(Update::ChangeMemory {
                         dom: parent.borrow().owned.owner.clone(),
+                        range: capa.data.access,
+                        rights: capa.data.access.rights,
                     });
                 } else {
                     return Err(CapaError::InvalidValue);
@@ -376,6 +501,16 @@ impl Capability<Domain> {
     }
 
     pub fn seal(&self, child: LocalCapa) -> Result<(), CapaError> {
+        let domain = self.check_seal(child)?;
+        domain.borrow_mut().data.status = DStatus::Sealed;
+
+        //TODO: should we generate anything now?
+
+        Ok(())
+    }
+
+    /// Checks of `seal` without sealing, returns the child.
+    pub fn check_seal(&self, child: LocalCapa) -> Result<CapaRef<Domain>, CapaError> {
         if !self.data.operation_allowed(MonitorAPI::SEAL) {
             return Err(CapaError::CallNotAllowed);
         }
@@ -387,11 +522,11 @@ impl Capability<Domain> {
         if domain.borrow().data.is_sealed() {
             return Err(CapaError::DomainSealed);
         }
-        domain.borrow_mut().data.status = DStatus::Sealed;
-
-        //TODO: should we generate anything now?
-
-        Ok(())
+        // A domain without cores can never be scheduled.
+        if domain.borrow().data.policies.cores == 0 {
+            return Err(CapaError::NoCoresAssigned);
+        }
+        Ok(domain)
     }
 
     pub fn attest(&self, child: LocalCapa) -> Result<String, CapaError> {
@@ -406,6 +541,48 @@ impl Capability<Domain> {
         return Ok(attestation);
     }
 
+    /// The direct child domains, prefer this over accessing `children`.
+    pub fn child_domains(&self) -> Vec<CapaRef<Domain>> {
+        self.children.clone()
+    }
+
+    /// All installed handles and the kind of capability they point to.
+    pub fn indices(&self) -> BTreeMap<LocalCapa, CapaKind> {
+        self.data
+            .capabilities
+            .capabilities
+            .iter()
+            .map(|(k, c)| (*k, c.kind()))
+            .collect()
+    }
+
+    // Regions are merged in physical order, so two distinct physical ranges
+    // remapped onto the same guest range go unnoticed, catch them here.
+    pub fn validate_view(regions: &[ViewRegion]) -> Result<(), CapaError> {
+        let mut by_gva: Vec<&ViewRegion> = regions.iter().collect();
+        by_gva.sort_by_key(|v| v.active_start());
+        // Compare against the region reaching furthest so far, an overlap can
+        // skip over shorter regions in between.
+        let mut furthest: Option<&ViewRegion> = None;
+        for second in by_gva {
+            let Some(first) = furthest else {
+                furthest = Some(second);
+                continue;
+            };
+            if second.active_start() < first.active_end() {
+                // Overlapping guest ranges must translate to the same physical ones.
+                let offset = second.active_start() - first.active_start();
+                if first.access.start.wrapping_add(offset) != second.access.start {
+                    return Err(CapaError::DoubleRemapping);
+                }
+            }
+            if second.active_end() > first.active_end() {
+                furthest = Some(second);
+            }
+        }
+        Ok(())
+    }
+
     pub fn coalesce_view_regions(regions: &mut Vec<ViewRegion>) -> Result<(), CapaError> {
         let mut curr: usize = 0;
         while curr < regions.len() {
@@ -433,10 +610,84 @@ impl Capability<Domain> {
 
         // Now go through it and merge.
         Self::coalesce_view_regions(&mut regions)?;
+        Self::validate_view(&regions)?;
+
+        Ok(regions)
+    }
+
+    /// Check a guest access at `gva`, e.g., when handling a fault.
+    pub fn check_access(&self, gva: u64, needed: Rights) -> Result<(), CapaError> {
+        let view = self.gva_view()?;
+        let region = view
+            .iter()
+            .find(|v| v.contains_gva(gva))
+            .ok_or(CapaError::InvalidAccess)?;
+        if !needed.is_subset_of(&region.access.rights) {
+            return Err(CapaError::InsufficientRights);
+        }
+        Ok(())
+    }
+
+    /// The region held by the domain whose access is exactly `access`.
+    pub fn find_region_by_access(
+        &self,
+        access: &Access,
+    ) -> Option<(LocalCapa, CapaRef<MemoryRegion>)> {
+        self.data
+            .capabilities
+            .capabilities
+            .iter()
+            .find_map(|(handle, c)| match c {
+                CapaWrapper::Region(r) if r.borrow().data.access == *access => {
+                    Some((*handle, r.clone()))
+                }
+                _ => None,
+            })
+    }
+
+    /// Every held region whose physical range intersects `access`, with the overlap.
+    pub fn regions_intersecting(&self, access: &Access) -> Vec<(LocalCapa, Access)> {
+        self.data
+            .capabilities
+            .capabilities
+            .iter()
+            .filter_map(|(handle, c)| match c {
+                CapaWrapper::Region(r) => {
+                    r.borrow().data.access.overlap(access).map(|o| (*handle, o))
+                }
+                _ => None,
+            })
+            .collect()
+    }
 
+    /// Same as `view` but sorted by guest address.
+    pub fn gva_view(&self) -> Result<Vec<ViewRegion>, CapaError> {
+        let mut regions = self.view()?;
+        regions.sort_by_key(|v| v.active_start());
         Ok(regions)
     }
 
+    /// Number of bytes the domain can access, overlaps are only counted once.
+    pub fn footprint(&self) -> Result<u64, CapaError> {
+        Ok(self.view()?.iter().map(|v| v.access.size).sum())
+    }
+
+    /// Fails if receiving the region would exceed the domain's quota.
+    pub fn check_quota(&self, region: &Capability<MemoryRegion>) -> Result<(), CapaError> {
+        let Some(max) = self.data.max_footprint else {
+            return Ok(());
+        };
+        let mut regions = self.view()?;
+        regions.extend(region.view());
+        regions.sort_by_key(|c| c.access.start);
+        Self::coalesce_view_regions(&mut regions)?;
+        let footprint: u64 = regions.iter().map(|v| v.access.size).sum();
+        if footprint > max {
+            return Err(CapaError::QuotaExceeded);
+        }
+        Ok(())
+    }
+
     pub fn gva_view_raw(&self) -> Result<Vec<ViewRegion>, CapaError> {
         let mut regions: Vec<ViewRegion> = self
             .data
@@ -456,7 +707,15 @@ impl Capability<Domain> {
     pub fn check_conflict(&self, view: &ViewRegion) -> Result<(), CapaError> {
         // Ensure there is no ambiguity when we map a gva.
         let effective = self.gva_view_raw()?;
+        // Fast path, e.g., a freshly created domain.
+        if effective.is_empty() {
+            return Ok(());
+        }
         for r in effective.iter() {
+            // Regions in a gap of the guest space do not conflict.
+            if !r.intersect_remap(view) {
+                continue;
+            }
             // Check that they are mapping to the same thing.
             if !r.compatible(view) {
                 return Err(CapaError::IncompatibleRemap);
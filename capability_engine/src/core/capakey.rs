@@ -4,7 +4,7 @@ use std::{
     rc::{Rc, Weak},
 };
 
-use super::capability::{CapaRef, Capability, WeakRef};
+use super::capability::{same_capa, CapaRef, Capability, WeakRef};
 
 // Identity wrapper
 #[derive(Clone)]
@@ -12,7 +12,7 @@ pub struct CapaKey<T>(pub CapaRef<T>);
 
 impl<T> PartialEq for CapaKey<T> {
     fn eq(&self, other: &Self) -> bool {
-        Rc::ptr_eq(&self.0, &other.0)
+        same_capa(&self.0, &other.0)
     }
 }
 
@@ -1,8 +1,108 @@
 use super::{
     capability::CapaError,
-    memory_region::{Access, ViewRegion},
+    memory_region::{Access, Rights, ViewRegion},
 };
 
+/// A single change between two views of the same domain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViewDelta {
+    Added(ViewRegion),
+    Removed(ViewRegion),
+    // The range carries the new rights.
+    RightsChanged {
+        range: ViewRegion,
+        from: Rights,
+        to: Rights,
+    },
+}
+
+/// Compute the changes to go from `old` to `new`, both as produced by `view()`.
+/// Removals come first, then rights changes, then additions, each by address.
+pub fn diff_views(old: &[ViewRegion], new: &[ViewRegion]) -> Vec<ViewDelta> {
+    // Split both views on every boundary so that each segment is uniform.
+    let mut bounds: Vec<u64> = old
+        .iter()
+        .chain(new.iter())
+        .flat_map(|v| [v.access.start, v.access.end()])
+        .collect();
+    bounds.sort();
+    bounds.dedup();
+
+    let mut removed: Vec<ViewDelta> = Vec::new();
+    let mut changed: Vec<ViewDelta> = Vec::new();
+    let mut added: Vec<ViewDelta> = Vec::new();
+    for w in bounds.windows(2) {
+        let before = segment(old, w[0], w[1]);
+        let after = segment(new, w[0], w[1]);
+        match (before, after) {
+            (Some(b), Some(a)) if b.remap == a.remap => {
+                if b.access.rights != a.access.rights {
+                    push_delta(
+                        &mut changed,
+                        ViewDelta::RightsChanged {
+                            range: a,
+                            from: b.access.rights,
+                            to: a.access.rights,
+                        },
+                    );
+                }
+            }
+            (b, a) => {
+                if let Some(b) = b {
+                    push_delta(&mut removed, ViewDelta::Removed(b));
+                }
+                if let Some(a) = a {
+                    push_delta(&mut added, ViewDelta::Added(a));
+                }
+            }
+        }
+    }
+    removed.extend(changed);
+    removed.extend(added);
+    removed
+}
+
+// The part of the view covering [start, end), if any.
+fn segment(views: &[ViewRegion], start: u64, end: u64) -> Option<ViewRegion> {
+    views
+        .iter()
+        .find(|v| v.access.start <= start && end <= v.access.end())
+        .map(|v| {
+            ViewRegion::new(
+                Access::new(start, end - start, v.access.rights),
+                v.remap.offset(start - v.access.start),
+            )
+        })
+}
+
+// Extend the last delta if the new one continues it.
+fn push_delta(deltas: &mut Vec<ViewDelta>, delta: ViewDelta) {
+    let merged = match (deltas.last_mut(), &delta) {
+        (Some(ViewDelta::Added(l)), ViewDelta::Added(r))
+        | (Some(ViewDelta::Removed(l)), ViewDelta::Removed(r))
+            if l.can_merge(r) =>
+        {
+            l.access.size += r.access.size;
+            true
+        }
+        (
+            Some(ViewDelta::RightsChanged { range, from, to }),
+            ViewDelta::RightsChanged {
+                range: r,
+                from: f,
+                to: t,
+            },
+        ) if from == f && to == t && range.can_merge(r) => {
+            range.access.size += r.access.size;
+            true
+        }
+        _ => false,
+    };
+    if !merged {
+        deltas.push(delta);
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CoalescedView {
     pub regions: Vec<ViewRegion>,
@@ -87,7 +187,7 @@ impl CoalescedView {
                 let end = u64::min(current.access.end(), region.access.end());
                 let m = ViewRegion::new(
                     Access::new(start, end - start, rights),
-                    current.remap.shift(start - current.access.start),
+                    current.remap.offset(start - current.access.start),
                 );
                 replace.push(m);
             }
@@ -102,7 +202,7 @@ impl CoalescedView {
                     ),
                     current
                         .remap
-                        .shift(region.access.end() - current.access.start),
+                        .offset(region.access.end() - current.access.start),
                 );
                 replace.push(r);
             }
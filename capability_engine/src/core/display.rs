@@ -1,8 +1,9 @@
 use crate::core::capability::*;
 use crate::core::domain::{
-    CapaWrapper, Domain, InterruptPolicy, Policies, VectorPolicy, VectorVisibility, NB_INTERRUPTS,
+    CapaKind, CapaWrapper, Domain, Policies, SizedInterruptPolicy, VectorPolicy, VectorVisibility,
 };
 use crate::core::memory_region::{Access, MemoryRegion, Remapped, Rights, ViewRegion};
+use crate::CallInterface;
 use core::fmt;
 use std::collections::HashMap;
 
@@ -41,6 +42,20 @@ impl fmt::Display for Rights {
     }
 }
 
+// Same as Display so `{:?}` and `{}` agree.
+impl fmt::Debug for Rights {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+// Compact `start..end RWX` form.
+impl fmt::Debug for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}..{:#x} {}", self.start, self.end(), self.rights)
+    }
+}
+
 impl fmt::Display for Remapped {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -51,8 +66,12 @@ impl fmt::Display for Remapped {
     }
 }
 
+// The alternate form `{:#}` prints the size instead of the end.
 impl fmt::Display for Access {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if f.alternate() {
+            return write!(f, "{:#x} {:#x} {}", self.start, self.size, self.rights);
+        }
         write!(
             f,
             "{:#x} {:#x} with {}",
@@ -137,6 +156,73 @@ impl fmt::Display for Capability<MemoryRegion> {
     }
 }
 
+impl fmt::Display for CapaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            CapaError::InvalidAccess => "invalid memory access",
+            CapaError::InvalidAttributes => "invalid region attributes",
+            CapaError::ChildNotFound => "child not found",
+            CapaError::InvalidLocalCapa => "invalid local capability handle",
+            CapaError::WrongCapaType => "wrong capability type",
+            CapaError::CallNotAllowed => "call not allowed",
+            CapaError::DomainUnsealed => "domain is not sealed",
+            CapaError::DomainSealed => "domain is already sealed",
+            CapaError::InsufficientRights => "insufficient rights",
+            CapaError::InvalidChildCapa => "invalid child capability",
+            CapaError::CapaNotOwned => "capability is not owned",
+            CapaError::RevokeOnRootCapa => "cannot revoke a root capability",
+            CapaError::DoubleRemapping => "address remapped twice",
+            CapaError::IncompatibleRemap => "incompatible remapping",
+            CapaError::InvalidField => "invalid field",
+            CapaError::InvalidValue => "invalid value",
+            CapaError::NoCoresAssigned => "no cores assigned",
+            CapaError::CarveOfAlias => "cannot carve an aliased region",
+            CapaError::TooManyCapabilities => "too many capabilities",
+            CapaError::QuotaExceeded => "memory quota exceeded",
+            CapaError::CycleDetected => "destination is an ancestor",
+            CapaError::DomainRevoked => "domain has been revoked",
+            CapaError::InvalidRemap => "region or remap is not page aligned",
+            CapaError::MaxDepthExceeded => "domain tree is too deep",
+            CapaError::SelfSend => "cannot send to oneself",
+            CapaError::ParserDomain => "malformed domain",
+            CapaError::ParserRegion => "malformed region",
+            CapaError::ParserStatus => "malformed status",
+            CapaError::ParserMonitor => "malformed monitor api",
+            CapaError::ParserCapability => "malformed capability",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+impl std::error::Error for CapaError {}
+
+impl fmt::Display for CallInterface {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let verb = match self {
+            CallInterface::CREATE => "create",
+            CallInterface::SET => "set",
+            CallInterface::GET => "get",
+            CallInterface::SEAL => "seal",
+            CallInterface::ATTEST => "attest",
+            CallInterface::ENUMERATE => "enumerate",
+            CallInterface::SWITCH => "switch",
+            CallInterface::ALIAS => "alias",
+            CallInterface::CARVE => "carve",
+            CallInterface::REVOKE => "revoke",
+            CallInterface::SEND => "send",
+            CallInterface::INTERRUPTS => "interrupts",
+            CallInterface::TRANSFER => "transfer",
+            CallInterface::RESTRICT => "restrict",
+            CallInterface::RECLAIM => "reclaim",
+            CallInterface::DUPLICATE => "duplicate",
+            CallInterface::PROMOTE => "promote",
+            CallInterface::ATTRIBUTES => "attributes",
+            CallInterface::INHERIT => "inherit",
+        };
+        write!(f, "{}", verb)
+    }
+}
+
 impl fmt::Display for ViewRegion {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{} mapped {}", self.access, self.remap)
@@ -206,7 +292,7 @@ impl PrintWithNames<Domain> for Capability<Domain> {
         let as_sorted_vector: Vec<_> = self.data.capabilities.capabilities.iter().collect();
         let tds: Vec<_> = as_sorted_vector
             .iter()
-            .filter(|(_, x)| matches!(x, CapaWrapper::Domain(_)))
+            .filter(|(_, x)| x.kind() == CapaKind::Domain)
             .collect();
 
         // Insert names for the "tds" domains into the HashMap
@@ -377,12 +463,12 @@ impl fmt::Display for Policies {
     }
 }
 
-impl fmt::Display for InterruptPolicy {
+impl<const N: usize> fmt::Display for SizedInterruptPolicy<N> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut start = 0;
         let mut vector = &self.vectors[0];
 
-        for i in 1..NB_INTERRUPTS {
+        for i in 1..N {
             if &self.vectors[i] == vector {
                 continue;
             }
@@ -400,10 +486,10 @@ impl fmt::Display for InterruptPolicy {
         }
 
         // Print the final range
-        if start == NB_INTERRUPTS - 1 {
+        if start == N - 1 {
             writeln!(f, "|vec{}: {}", start, vector)?;
         } else {
-            writeln!(f, "|vec{}-{}: {}", start, NB_INTERRUPTS - 1, vector)?;
+            writeln!(f, "|vec{}-{}: {}", start, N - 1, vector)?;
         }
 
         Ok(())
@@ -422,12 +508,6 @@ impl fmt::Display for VectorPolicy {
 
 impl fmt::Display for VectorVisibility {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self.bits() {
-            0 => write!(f, "NOT REPORTED"),
-            1 => write!(f, "ALLOWED"),
-            2 => write!(f, "VISIBLE"),
-            3 => write!(f, "ALLOWED|VISIBLE"),
-            _ => write!(f, "INVALID({:#b})", self.bits()),
-        }
+        write!(f, "{}", self.describe())
     }
 }
@@ -1,4 +1,5 @@
 use std::collections::{BTreeMap, VecDeque};
+use std::ops::ControlFlow;
 
 use crate::core::capability::{CapaError, CapaRef};
 use crate::core::memory_region::MemoryRegion;
@@ -41,6 +42,20 @@ bitflags! {
     }
 }
 
+impl VectorVisibility {
+    /// Canonical name, as displayed and parsed back in attestations.
+    pub fn describe(&self) -> &'static str {
+        match self.bits() {
+            0 => "NOT REPORTED",
+            1 => "ALLOWED",
+            2 => "VISIBLE",
+            3 => "ALLOWED|VISIBLE",
+            // Values only come from `from_bits`, unknown bits are rejected there.
+            _ => unreachable!("invalid vector visibility {:#b}", self.bits()),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Status {
     Unsealed,
@@ -73,9 +88,18 @@ impl FieldType {
     }
 }
 
+impl TryFrom<u64> for FieldType {
+    type Error = CapaError;
+
+    fn try_from(v: u64) -> Result<Self, Self::Error> {
+        Self::from_u64(v).ok_or(CapaError::InvalidValue)
+    }
+}
+
 /// Define the type for field here
 pub type Field = u64;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Policies {
     pub cores: u64,
     pub api: MonitorAPI,
@@ -97,8 +121,30 @@ impl Policies {
             && self.api.contains(other.api)
             && self.interrupts.contains(&other.interrupts)
     }
+
+    /// What is allowed by both.
+    pub fn intersection(&self, other: &Policies) -> Policies {
+        Policies::new(
+            self.cores & other.cores,
+            self.api & other.api,
+            self.interrupts.intersection(&other.interrupts),
+        )
+    }
+
+    /// What is allowed by either.
+    pub fn union(&self, other: &Policies) -> Policies {
+        Policies::new(
+            self.cores | other.cores,
+            self.api | other.api,
+            self.interrupts.union(&other.interrupts),
+        )
+    }
 }
 
+/// Policy for one interrupt vector.
+/// `read_set` and `write_set` are restriction masks: a set bit forbids access
+/// to the register, so `0` is unrestricted (default_all) and `!0` is fully
+/// restricted (default_none). A child must restrict at least what its parent does.
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct VectorPolicy {
     pub visibility: VectorVisibility,
@@ -107,41 +153,66 @@ pub struct VectorPolicy {
 }
 
 impl VectorPolicy {
+    /// Is the other a subset of self.
+    /// Read and write sets are restriction masks (default_all is 0, default_none is !0),
+    /// so the other must restrict at least the registers self restricts.
     pub fn contains(&self, other: &VectorPolicy) -> bool {
-        //TODO: do we care about register sets?
         self.visibility.contains(other.visibility)
+            && is_core_subset(other.read_set, self.read_set)
+            && is_core_subset(other.write_set, self.write_set)
+    }
+
+    // Restriction masks are combined the opposite way of the visibility.
+    pub fn intersection(&self, other: &VectorPolicy) -> VectorPolicy {
+        VectorPolicy {
+            visibility: self.visibility & other.visibility,
+            read_set: self.read_set | other.read_set,
+            write_set: self.write_set | other.write_set,
+        }
+    }
+
+    pub fn union(&self, other: &VectorPolicy) -> VectorPolicy {
+        VectorPolicy {
+            visibility: self.visibility | other.visibility,
+            read_set: self.read_set & other.read_set,
+            write_set: self.write_set & other.write_set,
+        }
     }
 }
 
 pub const NB_INTERRUPTS: usize = 256;
 
-#[derive(Clone, Copy)]
-pub struct InterruptPolicy {
-    pub vectors: [VectorPolicy; NB_INTERRUPTS],
+// Platforms with another number of vectors can pick their own `N`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SizedInterruptPolicy<const N: usize> {
+    pub vectors: [VectorPolicy; N],
 }
 
-impl InterruptPolicy {
+// The policy used throughout the engine, with `NB_INTERRUPTS` vectors.
+pub type InterruptPolicy = SizedInterruptPolicy<NB_INTERRUPTS>;
+
+impl<const N: usize> SizedInterruptPolicy<N> {
     pub fn default_none() -> Self {
-        InterruptPolicy {
+        SizedInterruptPolicy {
             vectors: [VectorPolicy {
                 visibility: VectorVisibility::empty(),
                 read_set: !(0 as u64),
                 write_set: !(0 as u64),
-            }; 256],
+            }; N],
         }
     }
     pub fn default_all() -> Self {
-        InterruptPolicy {
+        SizedInterruptPolicy {
             vectors: [VectorPolicy {
                 visibility: VectorVisibility::all(),
                 read_set: 0,
                 write_set: 0,
-            }; 256],
+            }; N],
         }
     }
 
-    pub fn contains(&self, other: &InterruptPolicy) -> bool {
-        for i in 0..NB_INTERRUPTS {
+    pub fn contains(&self, other: &SizedInterruptPolicy<N>) -> bool {
+        for i in 0..N {
             if !self.vectors[i].contains(&other.vectors[i]) {
                 return false;
             }
@@ -149,8 +220,69 @@ impl InterruptPolicy {
         return true;
     }
 
+    pub fn intersection(&self, other: &SizedInterruptPolicy<N>) -> SizedInterruptPolicy<N> {
+        SizedInterruptPolicy {
+            vectors: std::array::from_fn(|i| self.vectors[i].intersection(&other.vectors[i])),
+        }
+    }
+
+    pub fn union(&self, other: &SizedInterruptPolicy<N>) -> SizedInterruptPolicy<N> {
+        SizedInterruptPolicy {
+            vectors: std::array::from_fn(|i| self.vectors[i].union(&other.vectors[i])),
+        }
+    }
+
+    /// Run-length encoding of the vectors as (first vector, count, policy).
+    pub fn runs(&self) -> Vec<(usize, usize, VectorPolicy)> {
+        let mut runs: Vec<(usize, usize, VectorPolicy)> = Vec::new();
+        for (i, v) in self.vectors.iter().enumerate() {
+            match runs.last_mut() {
+                Some((_, count, policy)) if policy == v => *count += 1,
+                _ => runs.push((i, 1, *v)),
+            }
+        }
+        runs
+    }
+
+    /// The vectors that differ from `base`, as (vector, policy) in order.
+    pub fn diff(&self, base: &SizedInterruptPolicy<N>) -> Vec<(usize, VectorPolicy)> {
+        self.vectors
+            .iter()
+            .zip(base.vectors.iter())
+            .enumerate()
+            .filter(|(_, (v, b))| v != b)
+            .map(|(i, (v, _))| (i, *v))
+            .collect()
+    }
+
+    /// Inverse of `diff`: `base.apply_diff(&p.diff(&base))` gives back `p`.
+    pub fn apply_diff(&mut self, diff: &[(usize, VectorPolicy)]) -> Result<(), CapaError> {
+        for (vector, policy) in diff {
+            *self
+                .vectors
+                .get_mut(*vector)
+                .ok_or(CapaError::InvalidField)? = *policy;
+        }
+        Ok(())
+    }
+
+    /// Set `count` vectors starting at `first` to the same policy.
+    pub fn set_range(
+        &mut self,
+        first: usize,
+        count: usize,
+        policy: VectorPolicy,
+    ) -> Result<(), CapaError> {
+        let end = first.checked_add(count).ok_or(CapaError::InvalidField)?;
+        if end > N {
+            return Err(CapaError::InvalidField);
+        }
+        self.vectors[first..end].fill(policy);
+        Ok(())
+    }
+
     pub fn set(&mut self, tpe: FieldType, field: u64, value: u64) -> Result<(), CapaError> {
-        if field as usize >= NB_INTERRUPTS {
+        if field as usize >= N {
             return Err(CapaError::InvalidField);
         }
         match tpe {
@@ -171,9 +303,74 @@ impl InterruptPolicy {
     }
 }
 
+/// Builder for interrupt policies, starting from default_none.
+/// Ranges are [start, end) pairs of vectors, an out-of-range pair makes build fail.
+pub struct InterruptPolicyBuilder {
+    policy: InterruptPolicy,
+    error: Option<CapaError>,
+}
+
+impl InterruptPolicyBuilder {
+    pub fn new() -> Self {
+        InterruptPolicyBuilder {
+            policy: InterruptPolicy::default_none(),
+            error: None,
+        }
+    }
+
+    pub fn visible(self, range: (usize, usize)) -> Self {
+        self.apply(range, |v| v.visibility.insert(VectorVisibility::VISIBLE))
+    }
+
+    pub fn allowed(self, range: (usize, usize)) -> Self {
+        self.apply(range, |v| v.visibility.insert(VectorVisibility::ALLOWED))
+    }
+
+    pub fn read(self, range: (usize, usize), mask: u64) -> Self {
+        self.apply(range, |v| v.read_set = mask)
+    }
+
+    pub fn write(self, range: (usize, usize), mask: u64) -> Self {
+        self.apply(range, |v| v.write_set = mask)
+    }
+
+    pub fn build(self) -> Result<InterruptPolicy, CapaError> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.policy),
+        }
+    }
+
+    fn apply<F>(mut self, range: (usize, usize), op: F) -> Self
+    where
+        F: Fn(&mut VectorPolicy),
+    {
+        let (start, end) = range;
+        if start > end || end > NB_INTERRUPTS {
+            self.error.get_or_insert(CapaError::InvalidField);
+            return self;
+        }
+        self.policy.vectors[start..end].iter_mut().for_each(op);
+        self
+    }
+}
+
+impl Default for InterruptPolicyBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// For the moment define a handle
 pub type LocalCapa = u64;
 
+/// The kind of capability a handle points to.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CapaKind {
+    Region,
+    Domain,
+}
+
 /// The structure to manipulate capabilities.
 pub enum CapaWrapper {
     Region(CapaRef<MemoryRegion>),
@@ -181,6 +378,14 @@ pub enum CapaWrapper {
 }
 
 impl CapaWrapper {
+    /// The kind of the capability, without touching the inner reference.
+    pub fn kind(&self) -> CapaKind {
+        match self {
+            CapaWrapper::Region(_) => CapaKind::Region,
+            CapaWrapper::Domain(_) => CapaKind::Domain,
+        }
+    }
+
     pub fn as_domain(&self) -> Result<CapaRef<Domain>, CapaError> {
         if let CapaWrapper::Domain(d) = self {
             return Ok(d.clone());
@@ -195,22 +400,41 @@ impl CapaWrapper {
     }
 }
 
+// Default limit on the number of live capabilities a domain can hold.
+pub const MAX_HANDLES: usize = 1024;
+
 pub struct CapabilityStore {
     pub capabilities: BTreeMap<LocalCapa, CapaWrapper>,
     pub next_handle: LocalCapa,
     pub free_handles: VecDeque<LocalCapa>,
+    pub max_handles: usize,
+    // Never reuse a freed handle, see `new_monotonic`.
+    pub monotonic: bool,
 }
 
 impl CapabilityStore {
-    pub fn new() -> Self {
+    pub fn new(max_handles: usize) -> Self {
         CapabilityStore {
             capabilities: BTreeMap::new(),
             next_handle: 1,
             free_handles: VecDeque::new(),
+            max_handles,
+            monotonic: false,
+        }
+    }
+
+    /// Handles are never recycled, so a stale `LocalCapa` cannot silently
+    /// point to a newer capability. Trades handle space for safety.
+    pub fn new_monotonic(max_handles: usize) -> Self {
+        CapabilityStore {
+            monotonic: true,
+            ..Self::new(max_handles)
         }
     }
-    pub fn install_capability(&mut self, cap: CapaWrapper) -> LocalCapa {
+    pub fn install_capability(&mut self, cap: CapaWrapper) -> Result<LocalCapa, CapaError> {
+        self.check_room(1)?;
         let handle = if let Some(recycled) = self.free_handles.pop_front() {
+            debug_assert!(!self.contains(&recycled));
             recycled
         } else {
             let h = self.next_handle;
@@ -218,16 +442,40 @@ impl CapabilityStore {
             h
         };
         self.capabilities.insert(handle, cap);
-        handle
+        Ok(handle)
     }
 
     pub fn install_capabilitiy_at(&mut self, cap: CapaWrapper, idx: LocalCapa) {
+        // Keep the handle allocator coherent with the explicit index.
+        self.free_handles.retain(|h| *h != idx);
+        self.next_handle = LocalCapa::max(self.next_handle, idx + 1);
         self.capabilities.insert(idx, cap);
     }
 
+    pub fn contains(&self, handle: &LocalCapa) -> bool {
+        self.capabilities.contains_key(handle)
+    }
+
+    /// Fails if `count` more capabilities cannot be installed, so callers can
+    /// check before modifying anything: a carve or alias that fails to install
+    /// its result would otherwise leave an orphan child in the region tree.
+    pub fn check_room(&self, count: usize) -> Result<(), CapaError> {
+        if self.capabilities.len() + count > self.max_handles {
+            return Err(CapaError::TooManyCapabilities);
+        }
+        Ok(())
+    }
+
+    /// Once removed, the handle may be recycled by the next install (unless
+    /// `monotonic`), a stale copy of it then designates the new capability.
+    /// Callers must drop their handle, use `contains` before reusing one.
     pub fn remove(&mut self, handle: &LocalCapa) -> Result<CapaWrapper, CapaError> {
         if let Some(cap) = self.capabilities.remove(handle) {
-            self.free_handles.push_back(*handle);
+            // A live handle can never be free at the same time.
+            debug_assert!(!self.free_handles.contains(handle));
+            if !self.monotonic {
+                self.free_handles.push_back(*handle);
+            }
             return Ok(cap);
         }
         Err(CapaError::InvalidLocalCapa)
@@ -239,16 +487,18 @@ impl CapabilityStore {
             .ok_or(CapaError::InvalidLocalCapa)
     }
 
-    pub fn foreach_region_mut<F>(&mut self, op: F) -> Result<(), CapaError>
+    // Stops at the first region for which op breaks.
+    pub fn foreach_region_mut<F>(&mut self, mut op: F) -> Result<(), CapaError>
     where
-        F: Fn(&CapaRef<MemoryRegion>) -> Result<(), CapaError>,
+        F: FnMut(&CapaRef<MemoryRegion>) -> Result<ControlFlow<()>, CapaError>,
     {
         for (_k, c) in &mut self.capabilities {
-            if c.as_region().is_err() {
+            let CapaWrapper::Region(region) = c else {
                 continue;
+            };
+            if op(region)?.is_break() {
+                break;
             }
-            let region = c.as_region()?;
-            op(&region)?;
         }
         Ok(())
     }
@@ -258,11 +508,10 @@ impl CapabilityStore {
         F: FnMut(&CapaRef<MemoryRegion>) -> Result<(), CapaError>,
     {
         for (_k, c) in &self.capabilities {
-            if c.as_region().is_err() {
+            let CapaWrapper::Region(region) = c else {
                 continue;
-            }
-            let region = c.as_region()?;
-            op(&region)?;
+            };
+            op(region)?;
         }
         Ok(())
     }
@@ -279,6 +528,8 @@ pub struct Domain {
     pub status: Status,
     pub capabilities: CapabilityStore,
     pub policies: Policies,
+    // Maximum number of bytes the domain can access, if any.
+    pub max_footprint: Option<u64>,
 }
 
 impl PartialEq for Domain {
@@ -292,14 +543,20 @@ impl Domain {
         Domain {
             id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
             status: Status::Unsealed,
-            capabilities: CapabilityStore::new(),
+            capabilities: CapabilityStore::new(MAX_HANDLES),
             policies,
+            max_footprint: None,
         }
     }
     pub fn is_sealed(&self) -> bool {
         return self.status == Status::Sealed;
     }
-    pub fn install(&mut self, capa: CapaWrapper) -> LocalCapa {
+
+    /// Whether the two domains may run on a common core.
+    pub fn shares_cores(&self, other: &Domain) -> bool {
+        (self.policies.cores & other.policies.cores) != 0
+    }
+    pub fn install(&mut self, capa: CapaWrapper) -> Result<LocalCapa, CapaError> {
         self.capabilities.install_capability(capa)
     }
 
@@ -308,23 +565,23 @@ impl Domain {
     }
 
     pub fn is_domain(&self, capa: LocalCapa) -> Result<bool, CapaError> {
-        if let CapaWrapper::Domain(_) = self.capabilities.get(&capa)? {
-            return Ok(true);
-        }
-        Ok(false)
+        Ok(self.capabilities.get(&capa)?.kind() == CapaKind::Domain)
     }
 
     pub fn is_region(&self, capa: LocalCapa) -> Result<bool, CapaError> {
-        if let CapaWrapper::Region(_) = self.capabilities.get(&capa)? {
-            return Ok(true);
-        }
-        return Ok(false);
+        Ok(self.capabilities.get(&capa)?.kind() == CapaKind::Region)
     }
 
     pub fn operation_allowed(&self, apicall: MonitorAPI) -> bool {
         self.policies.api.contains(apicall)
     }
 
+    /// An unsealed domain is still being set up by its parent and can receive
+    /// freely, a sealed one needs RECEIVE in its api policy.
+    pub fn can_receive(&self) -> bool {
+        !self.is_sealed() || self.operation_allowed(MonitorAPI::RECEIVE)
+    }
+
     pub fn set_policy(&mut self, tpe: FieldType, field: u64, value: u64) -> Result<(), CapaError> {
         if self.is_sealed() {
             return Err(CapaError::DomainSealed);
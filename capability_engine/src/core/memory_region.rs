@@ -4,20 +4,21 @@ use bitflags::bitflags;
 
 use crate::core::capability::CapaError;
 
-#[derive(PartialEq, Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum RegionKind {
     Carve,
     Alias,
 }
 
-#[derive(PartialEq, Clone, Copy, Debug)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum Status {
     Exclusive,
     Aliased,
 }
 
 bitflags! {
-    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    // Debug is implemented in display.rs, it matches the Display.
+    #[derive(PartialEq, Eq, Clone, Copy)]
     pub struct Rights: u8 {
         const READ    = 0b001;
         const WRITE   = 0b010;
@@ -35,6 +36,34 @@ bitflags! {
     }
 }
 
+impl Rights {
+    /// Same as `is_core_subset` for rights: every bit of self is in other.
+    pub fn is_subset_of(&self, other: &Rights) -> bool {
+        other.contains(*self)
+    }
+
+    /// Inverse of the display, expects exactly `R|_`, `W|_`, `X|_`.
+    pub fn from_display(input: &str) -> Result<Self, CapaError> {
+        let flags = [
+            ('R', Rights::READ),
+            ('W', Rights::WRITE),
+            ('X', Rights::EXECUTE),
+        ];
+        if input.chars().count() != flags.len() {
+            return Err(CapaError::ParserRegion);
+        }
+        let mut rights = Rights::empty();
+        for (c, (expected, flag)) in input.chars().zip(flags) {
+            if c == expected {
+                rights |= flag;
+            } else if c != '_' {
+                return Err(CapaError::ParserRegion);
+            }
+        }
+        Ok(rights)
+    }
+}
+
 #[derive(PartialEq, Debug, Clone, Copy, Eq)]
 pub enum Remapped {
     Identity,
@@ -42,15 +71,28 @@ pub enum Remapped {
 }
 
 impl Remapped {
-    pub fn shift(&self, x: u64) -> Self {
+    /// Remapping of an address `delta` bytes further in the region.
+    /// Saturates rather than wrapping around the address space.
+    pub fn offset(&self, delta: u64) -> Self {
         match self {
             Self::Identity => Self::Identity,
-            Self::Remapped(y) => Self::Remapped(x + y),
+            Self::Remapped(base) => Self::Remapped(base.saturating_add(delta)),
+        }
+    }
+
+    /// Translate an address in a region starting at `region_start`.
+    pub fn translate(&self, addr_in_region: u64, region_start: u64) -> u64 {
+        match self {
+            Self::Identity => addr_in_region,
+            Self::Remapped(base) => {
+                base.saturating_add(addr_in_region.saturating_sub(region_start))
+            }
         }
     }
 }
 
-#[derive(PartialEq, Clone, Copy, Debug, Eq)]
+// Debug is implemented in display.rs, it matches the Display.
+#[derive(PartialEq, Clone, Copy, Eq)]
 pub struct Access {
     pub start: u64,
     pub size: u64,
@@ -67,21 +109,58 @@ impl Access {
     }
     pub fn contained(&self, other: &Self) -> bool {
         self.start >= other.start
-            && self.start + self.size <= other.start + other.size
-            && other.rights.contains(self.rights)
+            && self.end() <= other.end()
+            && self.rights.is_subset_of(&other.rights)
     }
 
     pub fn intersect(&self, other: &Self) -> bool {
-        let case_1 = self.start <= other.start && other.start < self.start + self.size;
-        let case_2 = other.start <= self.start && self.start < other.start + other.size;
+        let case_1 = self.start <= other.start && other.start < self.end();
+        let case_2 = other.start <= self.start && self.start < other.end();
         case_1 || case_2
     }
+
+    /// The common range, if any, with the rights of self.
+    pub fn overlap(&self, other: &Self) -> Option<Access> {
+        if !self.intersect(other) {
+            return None;
+        }
+        let start = u64::max(self.start, other.start);
+        let end = u64::min(self.end(), other.end());
+        Some(Access::new(start, end - start, self.rights))
+    }
+
+    // Saturates on overflow, use is_valid to reject such accesses.
     pub fn end(&self) -> u64 {
-        self.start + self.size
+        self.start.saturating_add(self.size)
+    }
+
+    /// Non-empty and does not wrap around the address space.
+    pub fn is_valid(&self) -> bool {
+        self.size != 0 && self.start.checked_add(self.size).is_some()
+    }
+
+    /// A guard reserves address space without granting any access.
+    pub fn is_guard(&self) -> bool {
+        self.rights.is_empty()
+    }
+}
+
+impl PartialOrd for Access {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
-#[derive(PartialEq, Debug, Clone)]
+impl Ord for Access {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.start
+            .cmp(&other.start)
+            .then(self.size.cmp(&other.size))
+            .then(self.rights.bits().cmp(&other.rights.bits()))
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub struct MemoryRegion {
     pub kind: RegionKind,
     pub status: Status,
@@ -90,6 +169,13 @@ pub struct MemoryRegion {
     pub remapped: Remapped,
 }
 
+impl MemoryRegion {
+    pub fn with_attributes(mut self, attributes: Attributes) -> Self {
+        self.attributes = attributes;
+        self
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct ViewRegion {
     pub access: Access,
@@ -112,18 +198,30 @@ impl ViewRegion {
         self.active_start() + self.access.size
     }
 
+    pub fn contains_gva(&self, gva: u64) -> bool {
+        self.active_start() <= gva && gva < self.active_end()
+    }
+
     pub fn contains_remap(&self, other: &ViewRegion) -> bool {
         self.active_start() <= other.active_start()
             && other.active_end() <= self.active_end()
             && self.access.rights.contains(other.access.rights)
     }
 
+    pub fn rights(&self) -> Rights {
+        self.access.rights
+    }
+
     pub fn contiguous(&self, other: &ViewRegion) -> bool {
-        // They must be contiguous in remaps and non remaps
-        // and have the same access rights
-        self.active_end() == other.active_start()
-            && self.access.end() == other.access.start
-            && self.access.rights == other.access.rights
+        // They must be contiguous in remaps and non remaps.
+        self.active_end() == other.active_start() && self.access.end() == other.access.start
+    }
+
+    /// Two views merge only if `other` directly follows `self`, both in
+    /// physical and remapped space, with the same access rights.
+    /// This also ensures a guard never merges with an accessible neighbor.
+    pub fn can_merge(&self, other: &ViewRegion) -> bool {
+        self.rights() == other.rights() && self.contiguous(other)
     }
 
     pub fn overlap_remap(&self, other: &ViewRegion) -> bool {
@@ -136,6 +234,36 @@ impl ViewRegion {
         //&& self.access.end() < other.access.end()
     }
 
+    /// Punch the hole out of the view, returns the remaining fragments (0, 1, or 2).
+    /// The hole is expressed in physical addresses, remaps are shifted accordingly.
+    pub fn subtract(&self, hole: &Access) -> Vec<ViewRegion> {
+        if !self.access.intersect(hole) {
+            return vec![*self];
+        }
+        let mut fragments = Vec::new();
+        if self.access.start < hole.start {
+            fragments.push(ViewRegion::new(
+                Access::new(
+                    self.access.start,
+                    hole.start - self.access.start,
+                    self.access.rights,
+                ),
+                self.remap,
+            ));
+        }
+        if hole.end() < self.access.end() {
+            fragments.push(ViewRegion::new(
+                Access::new(
+                    hole.end(),
+                    self.access.end() - hole.end(),
+                    self.access.rights,
+                ),
+                self.remap.offset(hole.end() - self.access.start),
+            ));
+        }
+        fragments
+    }
+
     // Check if they intersect.
     pub fn intersect_remap(&self, other: &ViewRegion) -> bool {
         self.overlap_remap(other) || other.overlap_remap(self)
@@ -176,6 +304,10 @@ impl ViewRegion {
         }
     }
 
+    /// Merge the region at `curr` with the next one, regions are sorted by start.
+    /// A contained region with fewer rights is absorbed, adjacent regions are
+    /// joined only if `can_merge`, overlaps are split and left for the next round.
+    /// Returns the index to process next.
     pub fn merge_at(curr: usize, regions: &mut Vec<Self>) -> Result<usize, CapaError> {
         if curr == regions.len() - 1 {
             return Ok(regions.len());
@@ -197,8 +329,8 @@ impl ViewRegion {
             return Ok(curr);
         }
 
-        // Case 2: contiguous
-        if current.contiguous(&other) {
+        // Case 2: adjacent with the same rights, see `can_merge`.
+        if current.can_merge(&other) {
             current = ViewRegion::new(
                 Access::new(
                     current.access.start,
@@ -219,12 +351,9 @@ impl ViewRegion {
                 return Err(CapaError::DoubleRemapping);
             }
             // Split the overlap and let the next round merge contiguous.
-            let middle_remap = match current.remap {
-                Remapped::Identity => Remapped::Identity,
-                Remapped::Remapped(x) => {
-                    Remapped::Remapped(other.access.start - current.access.start + x)
-                }
-            };
+            let middle_remap = current
+                .remap
+                .offset(other.access.start - current.access.start);
             let middle = ViewRegion::new(
                 Access::new(
                     other.access.start,
@@ -245,12 +374,7 @@ impl ViewRegion {
             other.access.start = middle.access.end();
             other.access.size = remainder - other.access.start;
             other.access.rights = rights;
-            let other_remap = match other.remap {
-                Remapped::Identity => Remapped::Identity,
-                Remapped::Remapped(x) => Remapped::Remapped(x + middle.access.size),
-            };
-
-            other.remap = other_remap;
+            other.remap = other.remap.offset(middle.access.size);
             // Commit the changes before inserting the new view.
             regions[curr] = current;
             regions[curr + 1] = other;
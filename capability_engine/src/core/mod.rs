@@ -1,3 +1,4 @@
+pub mod attestation;
 pub mod capability;
 pub mod capakey;
 pub mod coalesced;
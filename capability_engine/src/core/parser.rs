@@ -2,10 +2,12 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
 
+use crate::core::attestation::{fnv1a, CHECKSUM_PREFIX};
 use crate::core::capability::{CapaRef, Capability, Ownership};
 
 use crate::core::domain::{
-    CapaWrapper, CapabilityStore, InterruptPolicy, Policies, VectorPolicy, VectorVisibility,
+    CapaWrapper, CapabilityStore, InterruptPolicy, Policies, SizedInterruptPolicy, VectorPolicy,
+    VectorVisibility, MAX_HANDLES,
 };
 use crate::core::memory_region::{
     Access, Attributes, RegionKind, Remapped, Rights, Status as MStatus,
@@ -54,16 +56,16 @@ impl Parser {
         }
 
         let line = line.trim_start_matches("|indices: ");
-        line.split_whitespace().for_each(|e| {
-            let sp: Vec<&str> = e.split("->").collect();
-            if sp.len() != 2 {
-                panic!("Invalid length {}", sp.len());
+        for e in line.split_whitespace() {
+            let (handle, name) = e.split_once("->").ok_or(CapaError::ParserCapability)?;
+            let handle = handle
+                .parse::<LocalCapa>()
+                .map_err(|_| CapaError::ParserCapability)?;
+            if !name.starts_with("td") && !name.starts_with("r") {
+                return Err(CapaError::ParserCapability);
             }
-            self.indicies.insert(
-                sp[1].to_string(),
-                LocalCapa::from_str_radix(sp[0], 10).unwrap(),
-            );
-        });
+            self.indicies.insert(name.to_string(), handle);
+        }
         Ok(())
     }
 
@@ -73,6 +75,9 @@ impl Parser {
         }
         // Should be tdX = STATUS domain(CAPAS)
         let header: Vec<&str> = lines[0].split_whitespace().collect();
+        if header.len() < 4 {
+            return Err(CapaError::ParserDomain);
+        }
         let name = header[0].trim();
         let status = Status::from_string(header[2].to_string())?;
         let capabilities: Vec<&str> = {
@@ -120,8 +125,9 @@ impl Parser {
         let domain = Domain {
             id: 0,
             status,
-            capabilities: CapabilityStore::new(),
+            capabilities: CapabilityStore::new(MAX_HANDLES),
             policies: Policies::new(cores, api, inter_policy),
+            max_footprint: None,
         };
         // Add the domain.
         self.domains.insert(
@@ -132,29 +138,24 @@ impl Parser {
     }
 
     pub fn parse_rights(input: &str) -> Result<Rights, CapaError> {
-        let mut rights = Rights::empty();
-        if input.contains("R") {
-            rights |= Rights::READ;
-        }
-        if input.contains("W") {
-            rights |= Rights::WRITE;
-        }
-        if input.contains("W") {
-            rights |= Rights::EXECUTE;
-        }
-        Ok(rights)
+        Rights::from_display(input.trim())
     }
 
+    /// Inverse of the display: `Identity` or `Remapped(0x...)`.
     pub fn parse_remapped(input: &str) -> Result<Remapped, CapaError> {
-        if input.trim().contains("Identity") {
+        let trimmed = input.trim();
+        if trimmed == "Identity" {
             return Ok(Remapped::Identity);
         }
-        if !input.contains("Remapped") {
+        let open = trimmed.find('(').ok_or(CapaError::ParserRegion)?;
+        let close = trimmed.rfind(')').ok_or(CapaError::ParserRegion)?;
+        if &trimmed[..open] != "Remapped" || close != trimmed.len() - 1 || close < open {
             return Err(CapaError::ParserRegion);
         }
-        let mut trimmed = input.trim().trim_start_matches("Remapped(0x");
-        trimmed = &trimmed[0..trimmed.len() - 1];
-        let addr = u64::from_str_radix(trimmed, 16).map_err(|_| CapaError::ParserRegion)?;
+        let hex = trimmed[open + 1..close]
+            .strip_prefix("0x")
+            .ok_or(CapaError::ParserRegion)?;
+        let addr = u64::from_str_radix(hex, 16).map_err(|_| CapaError::ParserRegion)?;
         Ok(Remapped::Remapped(addr))
     }
 
@@ -276,6 +277,24 @@ impl Parser {
         self.parse_attestation_internal(&lines)
     }
 
+    /// Same as `parse_attestation` but first checks the trailing checksum line,
+    /// if present, against the rest of the attestation.
+    pub fn parse_signed_attestation(&mut self, attestation: String) -> Result<(), CapaError> {
+        let Some(pos) = attestation.rfind(CHECKSUM_PREFIX) else {
+            return self.parse_attestation(attestation);
+        };
+        let (body, line) = attestation.split_at(pos);
+        let value = line[CHECKSUM_PREFIX.len()..].trim_end();
+        let expected = value
+            .strip_prefix("0x")
+            .and_then(|v| u64::from_str_radix(v, 16).ok())
+            .ok_or(CapaError::ParserCapability)?;
+        if fnv1a(body.as_bytes()) != expected {
+            return Err(CapaError::ParserCapability);
+        }
+        self.parse_attestation(body.to_string())
+    }
+
     fn parse_attestation_internal(&mut self, lines: &Vec<&str>) -> Result<(), CapaError> {
         let mut i: usize = 0;
         while i < lines.len() {
@@ -319,6 +338,12 @@ impl Parser {
             }
         }
         let td0 = self.domains.get("td0").ok_or(CapaError::ParserDomain)?;
+        // Every name referenced by the indices must have been parsed.
+        for k in self.indicies.keys() {
+            if !self.domains.contains_key(k) && !self.regions.contains_key(k) {
+                return Err(CapaError::ParserCapability);
+            }
+        }
         // Now use the indicies to set ownership.
         for (k, v) in self.indicies.iter() {
             let capa_wrapper = if k.starts_with("td") {
@@ -344,28 +369,20 @@ impl Parser {
                 // We already did it
                 continue;
             }
-            let dom_owner = self
-                .domains
-                .get(owner)
-                .ok_or(CapaError::InvalidValue)
-                .unwrap();
-
-            let wrapper = if capa.starts_with("td") {
-                let dom = self
-                    .domains
-                    .get(capa)
-                    .ok_or(CapaError::InvalidValue)
-                    .unwrap();
-                CapaWrapper::Domain(dom.clone())
+            let dom_owner = self.domains.get(owner).ok_or(CapaError::ParserDomain)?;
+
+            // Children own what they hold, as for td0 above.
+            if capa.starts_with("td") {
+                let dom = self.domains.get(capa).ok_or(CapaError::ParserDomain)?;
+                let wrapper = CapaWrapper::Domain(dom.clone());
+                let handle = dom_owner.borrow_mut().data.install(wrapper)?;
+                dom.borrow_mut().owned = Ownership::new(Rc::downgrade(dom_owner), handle);
             } else {
-                let reg = self
-                    .regions
-                    .get(capa)
-                    .ok_or(CapaError::InvalidValue)
-                    .unwrap();
-                CapaWrapper::Region(reg.clone())
-            };
-            dom_owner.borrow_mut().data.install(wrapper);
+                let reg = self.regions.get(capa).ok_or(CapaError::ParserRegion)?;
+                let wrapper = CapaWrapper::Region(reg.clone());
+                let handle = dom_owner.borrow_mut().data.install(wrapper)?;
+                reg.borrow_mut().owned = Ownership::new(Rc::downgrade(dom_owner), handle);
+            }
         }
         Ok(())
     }
@@ -397,7 +414,16 @@ impl Unmarshall for MonitorAPI {
     }
 }
 
-impl InterruptPolicy {
+impl<const N: usize> SizedInterruptPolicy<N> {
+    /// Inverse of the display, one `|vec` line per range.
+    pub fn parse(input: &str) -> Result<Self, CapaError> {
+        let mut policy = Self::default_none();
+        for l in input.lines() {
+            policy.parse_one(l.to_string())?;
+        }
+        Ok(policy)
+    }
+
     pub fn parse_one(&mut self, l: String) -> Result<(), CapaError> {
         if !l.starts_with("|vec") {
             return Err(CapaError::InvalidValue);
@@ -408,8 +434,8 @@ impl InterruptPolicy {
             return Err(CapaError::InvalidValue);
         }
 
-        let tmp: Vec<&str> = parts[0].split(':').collect();
-        let (range, visi) = (tmp[0].trim_start_matches("|vec"), tmp[1]);
+        let (range, visi) = parts[0].split_once(':').ok_or(CapaError::InvalidValue)?;
+        let range = range.trim_start_matches("|vec");
         // We have the start and end vector.
         let (vs, ve) = if let Some((start, end)) = range.split_once('-') {
             (
@@ -429,12 +455,14 @@ impl InterruptPolicy {
             _ => return Err(CapaError::InvalidValue),
         };
 
+        if vs > ve || ve >= N {
+            return Err(CapaError::InvalidValue);
+        }
+
         let read = u64::from_str_radix(parts[1].trim_start_matches(" r: 0x"), 16)
-            .map_err(|_| CapaError::InvalidValue)
-            .unwrap();
+            .map_err(|_| CapaError::InvalidValue)?;
         let write = u64::from_str_radix(parts[2].trim_start_matches(" w: 0x"), 16)
-            .map_err(|_| CapaError::InvalidValue)
-            .unwrap();
+            .map_err(|_| CapaError::InvalidValue)?;
 
         // Now set the values
         for j in vs..=ve {
@@ -5,16 +5,27 @@ use super::{
     capakey::WeakKey,
     coalesced::CoalescedView,
     domain::Domain,
+    memory_region::{Access, Rights},
 };
 
 // Encodes the updates of memory operations.
 pub enum Update {
     // Zero-out a region.
-    Clean { start: u64, size: u64 },
+    Clean {
+        start: u64,
+        size: u64,
+    },
     // Revoke the domain
-    Revoke { dom: WeakRef<Domain> },
+    Revoke {
+        dom: WeakRef<Domain>,
+    },
     // Change in memory mappings for a domain.
-    ChangeMemory { dom: WeakRef<Domain> },
+    // The range affected and the rights it had before the change.
+    ChangeMemory {
+        dom: WeakRef<Domain>,
+        range: Access,
+        rights: Rights,
+    },
 }
 
 //TODO: implement this.
@@ -25,6 +36,7 @@ pub struct OperationUpdate {
     pub to_clean: Vec<Update>,
     pub to_revoke: HashSet<WeakKey<Domain>>,
     pub to_change: HashSet<WeakKey<Domain>>,
+    pub changes: Vec<Update>,
     pub snap: HashMap<WeakKey<Domain>, CoalescedView>,
 }
 
@@ -35,6 +47,7 @@ impl OperationUpdate {
             to_clean: Vec::new(),
             to_revoke: HashSet::new(),
             to_change: HashSet::new(),
+            changes: Vec::new(),
             snap: HashMap::new(),
         }
     }
@@ -54,12 +67,14 @@ impl OperationUpdate {
             }
             Update::Revoke { ref dom } => {
                 self.to_change.remove(&WeakKey(dom.clone()));
-                self.to_change.insert(WeakKey(dom.clone()));
+                self.to_revoke.insert(WeakKey(dom.clone()));
             }
-            Update::ChangeMemory { ref dom } => {
+            Update::ChangeMemory { ref dom, .. } => {
                 if !self.to_revoke.contains(&WeakKey(dom.clone())) {
                     self.to_change.insert(WeakKey(dom.clone()));
                 }
+                // Keep the ranges so we know what to re-enable.
+                self.changes.push(update);
             }
         }
     }
@@ -78,7 +93,42 @@ impl OperationUpdate {
         Ok(())
     }
 
+    // Merge adjacent or overlapping cleans into the minimal sorted set.
+    fn coalesce_cleans(&mut self) {
+        let mut ranges: Vec<(u64, u64)> = self
+            .to_clean
+            .iter()
+            .filter_map(|u| match u {
+                Update::Clean { start, size } => Some((*start, start.saturating_add(*size))),
+                _ => None,
+            })
+            .collect();
+        ranges.sort();
+        let mut merged: Vec<(u64, u64)> = Vec::new();
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some((_, last_end)) if start <= *last_end => *last_end = u64::max(*last_end, end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.to_clean = merged
+            .into_iter()
+            .map(|(start, end)| Update::Clean {
+                start,
+                size: end - start,
+            })
+            .collect();
+    }
+
+    /// Drain the updates once computed: cleans first, then memory changes.
+    pub fn take_updates(&mut self) -> Vec<Update> {
+        let mut updates: Vec<Update> = self.to_clean.drain(..).collect();
+        updates.append(&mut self.changes);
+        updates
+    }
+
     pub fn compute(&mut self) -> Result<(), CapaError> {
+        self.coalesce_cleans();
         //TODO: I'll have to think about the most efficient change.
         /*for (d, v) in self.snap.iter() {
             if let Some(dom) = &d.0.upgrade() {
@@ -1,4 +1,5 @@
 use core::{
+    capability::CapaError,
     domain::{Field, FieldType, InterruptPolicy, MonitorAPI},
     memory_region::{Access, Attributes, Remapped},
 };
@@ -11,8 +12,8 @@ fn is_core_subset(reference: u64, other: u64) -> bool {
     (reference & other) == other
 }
 
-// Call identifiers for the engine trait.
-#[derive(Debug)]
+// Call identifiers for the engine trait, ordered by their value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(u8)]
 pub enum CallInterface {
     CREATE = 1,
@@ -26,6 +27,57 @@ pub enum CallInterface {
     CARVE = 9,
     REVOKE = 10,
     SEND = 11,
+    // Bulk update of a range of interrupt vectors.
+    INTERRUPTS = 12,
+    // Move a region between two children of the caller.
+    TRANSFER = 13,
+    // Drop rights from an owned region.
+    RESTRICT = 14,
+    // Pull a child region back from wherever it was sent.
+    RECLAIM = 15,
+    // Sibling alias with the same access and remapping.
+    DUPLICATE = 16,
+    // Turn an owned carve into a root region.
+    PROMOTE = 17,
+    // Change the attributes of a region before it is sent.
+    ATTRIBUTES = 18,
+    // Copy the caller's interrupt policy into a child.
+    INHERIT = 19,
+}
+
+impl CallInterface {
+    pub fn as_u8(&self) -> u8 {
+        *self as u8
+    }
+}
+
+impl TryFrom<u64> for CallInterface {
+    type Error = CapaError;
+
+    fn try_from(v: u64) -> Result<Self, Self::Error> {
+        match v {
+            1 => Ok(Self::CREATE),
+            2 => Ok(Self::SET),
+            3 => Ok(Self::GET),
+            4 => Ok(Self::SEAL),
+            5 => Ok(Self::ATTEST),
+            6 => Ok(Self::ENUMERATE),
+            7 => Ok(Self::SWITCH),
+            8 => Ok(Self::ALIAS),
+            9 => Ok(Self::CARVE),
+            10 => Ok(Self::REVOKE),
+            11 => Ok(Self::SEND),
+            12 => Ok(Self::INTERRUPTS),
+            13 => Ok(Self::TRANSFER),
+            14 => Ok(Self::RESTRICT),
+            15 => Ok(Self::RECLAIM),
+            16 => Ok(Self::DUPLICATE),
+            17 => Ok(Self::PROMOTE),
+            18 => Ok(Self::ATTRIBUTES),
+            19 => Ok(Self::INHERIT),
+            _ => Err(CapaError::InvalidValue),
+        }
+    }
 }
 
 // Common interface for a capability engine.
@@ -32,6 +32,6 @@ fn main() {
     let mut domain = create_root_domain();
     let region = create_root();
     let reference = Rc::new(RefCell::new(region));
-    domain.data.install(CapaWrapper::Region(reference));
+    domain.data.install(CapaWrapper::Region(reference)).unwrap();
     println!("The root domain:\n{}", domain);
 }
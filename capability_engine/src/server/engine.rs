@@ -1,63 +1,1710 @@
-use std::collections::VecDeque;
-use std::{cell::RefCell, rc::Rc};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::ControlFlow;
+use std::{
+    cell::{Cell, RefCell},
+    rc::{Rc, Weak},
+};
+
+use crate::client::engine::ClientResult;
+use crate::core::attestation::{sign_attestation, RegionNode};
+use crate::core::capability::{same_capa, CapaError, CapaRef, Capability, Ownership, WeakRef};
+use crate::core::domain::{CapaKind, CapaWrapper, CapabilityStore};
+use crate::core::domain::{
+    Domain, Field, FieldType, InterruptPolicy, LocalCapa, MonitorAPI, Policies, Status,
+    VectorPolicy, VectorVisibility,
+};
+use crate::core::memory_region::{
+    Access, Attributes, MemoryRegion, RegionKind, Remapped, Rights, ViewRegion,
+};
+use crate::core::update::{CoreUpdate, OperationUpdate, Update};
+use crate::{is_core_subset, CallInterface, EngineInterface};
+
+/// Engine implementation.
+/// This is the entry point for all operations.
+pub struct Engine {
+    // The root lives in the engine.
+    pub root: CapaRef<Domain>,
+    pub scheduled: Vec<WeakRef<Domain>>,
+    pub updates: VecDeque<Vec<Update>>,
+    pub core_update: Vec<Vec<CoreUpdate>>,
+    // Successful calls, only recorded once enabled.
+    pub audit: Option<Vec<AuditEntry>>,
+    // Alignment required for sent regions and remaps, 0 disables the check.
+    pub page_size: u64,
+    // Maximum depth of the domain tree, the root is at depth 0.
+    pub max_depth: Option<usize>,
+    // Id of the last domain returned by `next_runnable` for each core.
+    last_runnable: Vec<Cell<Option<u64>>>,
+    // Operations only queue their updates once enabled, see `enable_updates`.
+    queue_updates: bool,
+    // Maximum number of batches kept in `updates`, unbounded if None.
+    update_cap: Option<(usize, UpdateQueueMode)>,
+}
+
+/// What to do when a batch is pushed on a full update queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateQueueMode {
+    // Ring buffer: the oldest batch is dropped.
+    DropOldest,
+    // The operation fails with `InvalidValue` before modifying anything.
+    Reject,
+}
+
+// Page size a real MMU would require.
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// Checkpoint of an engine, see `Engine::snapshot`.
+/// Domains and regions are listed by index, links between them use those indices.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EngineSnapshot {
+    // Attestation of the root, the rebuilt tree must match it.
+    pub attestation: String,
+    // The root first, then its descendants in pre-order.
+    pub domains: Vec<DomainSnapshot>,
+    // Every region of the forest, parents before their children.
+    pub regions: Vec<RegionSnapshot>,
+    pub page_size: u64,
+    pub max_depth: Option<usize>,
+    // Index of the domain scheduled on each core.
+    pub scheduled: Vec<Option<usize>>,
+    pub last_runnable: Vec<Option<u64>>,
+    pub queue_updates: bool,
+    pub update_cap: Option<(usize, UpdateQueueMode)>,
+}
+
+/// A domain of an `EngineSnapshot`, with its store and handle allocator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainSnapshot {
+    pub id: u64,
+    pub status: Status,
+    pub policies: Policies,
+    pub max_footprint: Option<u64>,
+    pub parent: Option<usize>,
+    // Live handles with the index of the domain or region they designate.
+    pub capabilities: Vec<(LocalCapa, CapaKind, usize)>,
+    pub next_handle: LocalCapa,
+    pub free_handles: VecDeque<LocalCapa>,
+    pub max_handles: usize,
+    pub monotonic: bool,
+}
+
+/// A region of an `EngineSnapshot`, its holder is found through the stores.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionSnapshot {
+    pub data: MemoryRegion,
+    pub parent: Option<usize>,
+}
+
+/// A successful call to the engine, arguments are encoded as in the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub call: CallInterface,
+    pub domain: u64,
+    pub args: [u64; 6],
+    pub handle: Option<LocalCapa>,
+}
+
+impl Engine {
+    /// First check of every call, before any handle is resolved: the caller
+    /// must be sealed to invoke any operation. A `DomainUnsealed` coming from
+    /// here is thus always about the caller, never about the target.
+    fn is_sealed_and_allowed(
+        &self,
+        domain: &CapaRef<Domain>,
+        call: MonitorAPI,
+    ) -> Result<(), CapaError> {
+        let dom = domain.borrow();
+        // A stale reference to a revoked domain must not act as a zombie.
+        if dom.data.status == Status::Revoked {
+            return Err(CapaError::DomainRevoked);
+        }
+        if dom.data.status != Status::Sealed {
+            return Err(CapaError::DomainUnsealed);
+        }
+        if !dom.data.operation_allowed(call) {
+            return Err(CapaError::CallNotAllowed);
+        }
+        Ok(())
+    }
+
+    pub fn add_root_region(
+        &self,
+        domain: &CapaRef<Domain>,
+        region: &CapaRef<MemoryRegion>,
+    ) -> Result<LocalCapa, CapaError> {
+        let local_handle = {
+            let dom = &mut domain.borrow_mut();
+            dom.data.install(CapaWrapper::Region(region.clone()))?
+        };
+        let reg = &mut region.borrow_mut();
+        reg.owned = Ownership::new(Rc::downgrade(domain), local_handle);
+        Ok(local_handle)
+    }
+
+    // Losing a vital region kills the domain that held it.
+    // The revocations are added to the batch of the operation that caused them.
+    fn revoke_vital_holders(updates: &mut OperationUpdate) -> Result<(), CapaError> {
+        let victims: Vec<CapaRef<Domain>> = updates
+            .to_revoke
+            .iter()
+            .filter_map(|v| v.0.upgrade())
+            .collect();
+        for victim in victims {
+            // It might have gone down with a previous victim.
+            if victim.borrow().data.status == Status::Revoked {
+                continue;
+            }
+            let Some(owner) = victim.borrow().parent.upgrade() else {
+                continue;
+            };
+            let handle = owner
+                .borrow()
+                .data
+                .capabilities
+                .capabilities
+                .iter()
+                .find_map(|(h, c)| match c {
+                    CapaWrapper::Domain(d) if same_capa(d, &victim) => Some(*h),
+                    _ => None,
+                });
+            if let Some(handle) = handle {
+                Self::prepare_domains_revoke(&owner, &[handle], updates)?;
+                Self::revoke_domains(&owner, &[handle])?;
+            }
+        }
+        Ok(())
+    }
+
+    // Checks of `seal` on the child, shared with `seal_recursive`.
+    fn check_seal(domain: &CapaRef<Domain>, child: LocalCapa) -> Result<(), CapaError> {
+        let dom = domain.borrow();
+        let target = dom.data.capabilities.get(&child)?.as_domain()?;
+        // Check the child's policies are a subset of the parent.
+        if !dom.data.policies.contains(&target.borrow().data.policies) {
+            return Err(CapaError::InsufficientRights);
+        }
+        dom.check_seal(child)?;
+        Ok(())
+    }
+
+    // Resolve a child domain handle, the caller must be its owner.
+    fn owned_child(
+        domain: &CapaRef<Domain>,
+        child: LocalCapa,
+    ) -> Result<CapaRef<Domain>, CapaError> {
+        let child = domain.borrow().data.capabilities.get(&child)?.as_domain()?;
+        let owner = child.borrow().owned.owner.upgrade();
+        if !owner.is_some_and(|o| same_capa(&o, domain)) {
+            return Err(CapaError::CapaNotOwned);
+        }
+        Ok(child)
+    }
+
+    fn revoke_region_handler(capa: &mut Capability<MemoryRegion>) -> Result<(), CapaError> {
+        let owner = capa.owned.owner.upgrade().ok_or(CapaError::CapaNotOwned)?;
+        owner
+            .borrow_mut()
+            .data
+            .capabilities
+            .remove(&capa.owned.handle)?;
+        Ok(())
+    }
+
+    fn record(
+        &mut self,
+        call: CallInterface,
+        domain: u64,
+        args: [u64; 6],
+        handle: Option<LocalCapa>,
+    ) {
+        if let Some(audit) = &mut self.audit {
+            audit.push(AuditEntry {
+                call,
+                domain,
+                args,
+                handle,
+            });
+        }
+    }
+
+    /// Start recording successful calls.
+    pub fn enable_audit(&mut self) {
+        if self.audit.is_none() {
+            self.audit = Some(Vec::new());
+        }
+    }
+
+    /// Drain the recorded calls.
+    pub fn take_audit(&mut self) -> Vec<AuditEntry> {
+        self.audit.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    // Collect the updates caused by revoking the child domains.
+    fn prepare_domains_revoke(
+        domain: &CapaRef<Domain>,
+        handles: &[LocalCapa],
+        update: &mut OperationUpdate,
+    ) -> Result<(), CapaError> {
+        let dom = domain.borrow();
+        for h in handles {
+            let d = dom.data.capabilities.get(h)?.as_domain()?;
+            dom.on_revoke_child(&d, update)?;
+        }
+        Ok(())
+    }
+
+    // Tear down the child domains and everything they hold, callers do the checks.
+    fn revoke_domains(domain: &CapaRef<Domain>, handles: &[LocalCapa]) -> Result<(), CapaError> {
+        let dom = &mut domain.borrow_mut();
+        for h in handles {
+            let d = dom.data.capabilities.get(h)?.as_domain()?;
+            // Mark the domain as being revoked.
+            d.borrow_mut().data.status = Status::Revoked;
+            dom.revoke_child(&d, &mut Self::revoke_domain_handler)?;
+            // Remove the handle
+            dom.data.capabilities.remove(h)?;
+        }
+        Ok(())
+    }
+
+    fn revoke_domain_handler(capa: &mut Capability<Domain>) -> Result<(), CapaError> {
+        capa.data.status = Status::Revoked;
+        capa.data
+            .capabilities
+            .foreach_region_mut(|c: &CapaRef<MemoryRegion>| {
+                Capability::<MemoryRegion>::revoke_node(c.clone(), &mut |_c| Ok(()))?;
+                Ok(ControlFlow::Continue(()))
+            })?;
+        capa.data.capabilities.reset();
+        Ok(())
+    }
+
+    /// Revoke all the domain's children at once, memory updates are batched.
+    pub fn revoke_all(&mut self, domain: CapaRef<Domain>) -> Result<(), CapaError> {
+        self.is_sealed_and_allowed(&domain, MonitorAPI::REVOKE)?;
+
+        let handles: Vec<LocalCapa> = domain
+            .borrow()
+            .indices()
+            .into_iter()
+            .filter(|(_, kind)| *kind == CapaKind::Domain)
+            .map(|(handle, _)| handle)
+            .collect();
+        self.revoke_child_domains(&domain, &handles)?;
+        // One entry per child, as if revoked one by one.
+        let id = domain.borrow().data.id;
+        for handle in handles {
+            self.record(CallInterface::REVOKE, id, [handle, 0, 0, 0, 0, 0], None);
+        }
+        Ok(())
+    }
+
+    // Revoke several child domains with a single batched update, callers do the checks.
+    fn revoke_child_domains(
+        &mut self,
+        domain: &CapaRef<Domain>,
+        handles: &[LocalCapa],
+    ) -> Result<(), CapaError> {
+        if handles.is_empty() {
+            return Ok(());
+        }
+
+        // Prepare a single update for all the children.
+        let mut update = OperationUpdate::new();
+        Self::prepare_domains_revoke(domain, handles, &mut update)?;
+        update.snapshot()?;
+        self.check_update_room(&update)?;
+        //TODO: notify all then we process the revoke.
+
+        Self::revoke_domains(domain, handles)?;
+        update.compute()?;
+        //TODO: notify
+        self.push_updates(&mut update);
+        Ok(())
+    }
+
+    /// Duplicate an alias: creates a sibling alias with the same access and remapping.
+    /// Carves are exclusive and cannot be duplicated.
+    pub fn duplicate(
+        &mut self,
+        domain: CapaRef<Domain>,
+        capa: LocalCapa,
+    ) -> Result<LocalCapa, CapaError> {
+        self.is_sealed_and_allowed(&domain, MonitorAPI::ALIAS)?;
+
+        let region = domain.borrow().data.capabilities.get(&capa)?.as_region()?;
+        let (access, remapped) = {
+            let reg = region.borrow();
+            if reg.data.kind != RegionKind::Alias {
+                return Err(CapaError::InvalidAccess);
+            }
+            (reg.data.access, reg.data.remapped)
+        };
+        let parent = region
+            .borrow()
+            .parent
+            .upgrade()
+            .ok_or(CapaError::InvalidAccess)?;
+        domain.borrow().data.capabilities.check_room(1)?;
+
+        let duplicate = parent.borrow_mut().alias(&access)?;
+        duplicate.borrow_mut().data.remapped = remapped;
+        let duplicate_capa = domain
+            .borrow_mut()
+            .data
+            .install(CapaWrapper::Region(duplicate.clone()))?;
+
+        // Tree & ownership logic.
+        duplicate.borrow_mut().parent = Rc::downgrade(&parent);
+        duplicate.borrow_mut().owned = Ownership::new(Rc::downgrade(&domain), duplicate_capa);
+        let id = domain.borrow().data.id;
+        self.record(
+            CallInterface::DUPLICATE,
+            id,
+            [capa, 0, 0, 0, 0, 0],
+            Some(duplicate_capa),
+        );
+        Ok(duplicate_capa)
+    }
+
+    /// Pairs of scheduled domain ids (smaller first) whose core masks overlap.
+    pub fn core_conflicts(&self) -> Vec<(u64, u64)> {
+        let mut running: Vec<CapaRef<Domain>> = Vec::new();
+        for domain in self.scheduled.iter().filter_map(|s| s.upgrade()) {
+            if !running.iter().any(|r| same_capa(r, &domain)) {
+                running.push(domain);
+            }
+        }
+        let mut conflicts = Vec::new();
+        for (i, a) in running.iter().enumerate() {
+            for b in running.iter().skip(i + 1) {
+                let (a, b) = (a.borrow(), b.borrow());
+                if a.data.shares_cores(&b.data) {
+                    let (x, y) = (a.data.id, b.data.id);
+                    conflicts.push((x.min(y), x.max(y)));
+                }
+            }
+        }
+        conflicts.sort();
+        conflicts
+    }
+
+    /// The view of the domain scheduled on the core, empty if nothing is scheduled.
+    pub fn active_view(&self, core: u64) -> Result<Vec<ViewRegion>, CapaError> {
+        let scheduled = self
+            .scheduled
+            .get(core as usize)
+            .ok_or(CapaError::InvalidField)?;
+        match scheduled.upgrade() {
+            Some(domain) => domain.borrow().view(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Engine with a sealed root restricted to `policies`.
+    /// The number of cores is derived from the highest core in the mask.
+    pub fn with_root_policies(policies: Policies) -> Self {
+        let nb_cores = u64::BITS - policies.cores.leading_zeros();
+        // Create the root capability for the domain.
+        let mut root = Domain::new(policies);
+        root.status = Status::Sealed;
+        let dom = Capability::<Domain>::new(root);
+        let ref_td = Rc::new(RefCell::new(dom));
+        Engine {
+            root: ref_td,
+            // Nothing is scheduled until the first switch.
+            scheduled: (0..nb_cores).map(|_| WeakRef::new()).collect(),
+            updates: VecDeque::<Vec<Update>>::new(),
+            core_update: Vec::new(),
+            audit: None,
+            page_size: 0,
+            max_depth: None,
+            last_runnable: (0..nb_cores).map(|_| Cell::new(None)).collect(),
+            queue_updates: false,
+            update_cap: None,
+        }
+    }
+
+    /// Checkpoint of every domain and region reachable from the root, see `restore`.
+    pub fn snapshot(&self) -> EngineSnapshot {
+        // Number the domains in pre-order, children keep their order.
+        let mut domains: Vec<CapaRef<Domain>> = Vec::new();
+        let mut stack = vec![self.root.clone()];
+        while let Some(dom) = stack.pop() {
+            stack.extend(dom.borrow().children.iter().rev().cloned());
+            domains.push(dom);
+        }
+        let domain_indices: HashMap<*const RefCell<Capability<Domain>>, usize> = domains
+            .iter()
+            .enumerate()
+            .map(|(i, d)| (Rc::as_ptr(d), i))
+            .collect();
+        let domain_index = |d: &CapaRef<Domain>| domain_indices.get(&Rc::as_ptr(d)).copied();
+
+        // Number the regions of every tree a domain holds a region in.
+        let mut regions: Vec<CapaRef<MemoryRegion>> = Vec::new();
+        let mut seen: HashSet<*const RefCell<Capability<MemoryRegion>>> = HashSet::new();
+        for dom in domains.iter() {
+            for capa in dom.borrow().data.capabilities.capabilities.values() {
+                let CapaWrapper::Region(region) = capa else {
+                    continue;
+                };
+                let mut root = region.clone();
+                while let Some(parent) = root.clone().borrow().parent.upgrade() {
+                    root = parent;
+                }
+                if seen.contains(&Rc::as_ptr(&root)) {
+                    continue;
+                }
+                let mut stack = vec![root];
+                while let Some(region) = stack.pop() {
+                    stack.extend(region.borrow().children.iter().rev().cloned());
+                    seen.insert(Rc::as_ptr(&region));
+                    regions.push(region);
+                }
+            }
+        }
+        let region_indices: HashMap<*const RefCell<Capability<MemoryRegion>>, usize> = regions
+            .iter()
+            .enumerate()
+            .map(|(i, r)| (Rc::as_ptr(r), i))
+            .collect();
+        let region_index = |r: &CapaRef<MemoryRegion>| region_indices.get(&Rc::as_ptr(r)).copied();
+
+        let domain_snapshots = domains
+            .iter()
+            .map(|dom| {
+                let dom = dom.borrow();
+                let store = &dom.data.capabilities;
+                // Held domains are always in the tree, see `validate_tree`.
+                let capabilities = store
+                    .capabilities
+                    .iter()
+                    .filter_map(|(h, c)| match c {
+                        CapaWrapper::Domain(d) => Some((*h, CapaKind::Domain, domain_index(d)?)),
+                        CapaWrapper::Region(r) => Some((*h, CapaKind::Region, region_index(r)?)),
+                    })
+                    .collect();
+                DomainSnapshot {
+                    id: dom.data.id,
+                    status: dom.data.status,
+                    policies: dom.data.policies,
+                    max_footprint: dom.data.max_footprint,
+                    parent: dom.parent.upgrade().and_then(|p| domain_index(&p)),
+                    capabilities,
+                    next_handle: store.next_handle,
+                    free_handles: store.free_handles.clone(),
+                    max_handles: store.max_handles,
+                    monotonic: store.monotonic,
+                }
+            })
+            .collect();
+        let region_snapshots = regions
+            .iter()
+            .map(|r| RegionSnapshot {
+                data: r.borrow().data.clone(),
+                parent: r.borrow().parent.upgrade().and_then(|p| region_index(&p)),
+            })
+            .collect();
+        EngineSnapshot {
+            attestation: format!("{}", self.root.borrow()),
+            domains: domain_snapshots,
+            regions: region_snapshots,
+            page_size: self.page_size,
+            max_depth: self.max_depth,
+            scheduled: self
+                .scheduled
+                .iter()
+                .map(|s| s.upgrade().and_then(|d| domain_index(&d)))
+                .collect(),
+            last_runnable: self.last_runnable.iter().map(|l| l.get()).collect(),
+            queue_updates: self.queue_updates,
+            update_cap: self.update_cap,
+        }
+    }
+
+    /// Rebuild an engine from a snapshot, handles and allocators are kept as they were.
+    pub fn restore(snapshot: &EngineSnapshot) -> Result<Engine, CapaError> {
+        let root = snapshot.domains.first().ok_or(CapaError::ParserDomain)?;
+        if root.status != Status::Sealed {
+            return Err(CapaError::DomainUnsealed);
+        }
+        let mut engine = Self::with_root_policies(root.policies);
+
+        let domains: Vec<CapaRef<Domain>> = snapshot
+            .domains
+            .iter()
+            .map(|d| {
+                let mut store = CapabilityStore::new(d.max_handles);
+                store.next_handle = d.next_handle;
+                store.free_handles = d.free_handles.clone();
+                store.monotonic = d.monotonic;
+                let domain = Domain {
+                    id: d.id,
+                    status: d.status,
+                    capabilities: store,
+                    policies: d.policies,
+                    max_footprint: d.max_footprint,
+                };
+                Rc::new(RefCell::new(Capability::<Domain>::new(domain)))
+            })
+            .collect();
+        let regions: Vec<CapaRef<MemoryRegion>> = snapshot
+            .regions
+            .iter()
+            .map(|r| {
+                Rc::new(RefCell::new(Capability::<MemoryRegion>::new(
+                    r.data.clone(),
+                )))
+            })
+            .collect();
+
+        // Link the trees, children are listed in order.
+        for (child, d) in domains.iter().zip(snapshot.domains.iter()) {
+            if let Some(parent) = d.parent {
+                let parent = domains.get(parent).ok_or(CapaError::ParserDomain)?;
+                parent.borrow_mut().children.push(child.clone());
+                child.borrow_mut().parent = Rc::downgrade(parent);
+            }
+        }
+        for (child, r) in regions.iter().zip(snapshot.regions.iter()) {
+            if let Some(parent) = r.parent {
+                let parent = regions.get(parent).ok_or(CapaError::ParserRegion)?;
+                parent.borrow_mut().children.push(child.clone());
+                child.borrow_mut().parent = Rc::downgrade(parent);
+            }
+        }
+
+        // Install every capability at its handle, owned by its holder.
+        for (holder, d) in domains.iter().zip(snapshot.domains.iter()) {
+            for (handle, kind, index) in d.capabilities.iter() {
+                let owned = Ownership::new(Rc::downgrade(holder), *handle);
+                let wrapper = match kind {
+                    CapaKind::Domain => {
+                        let dom = domains.get(*index).ok_or(CapaError::ParserDomain)?;
+                        dom.borrow_mut().owned = owned;
+                        CapaWrapper::Domain(dom.clone())
+                    }
+                    CapaKind::Region => {
+                        let reg = regions.get(*index).ok_or(CapaError::ParserRegion)?;
+                        reg.borrow_mut().owned = owned;
+                        CapaWrapper::Region(reg.clone())
+                    }
+                };
+                holder
+                    .borrow_mut()
+                    .data
+                    .capabilities
+                    .capabilities
+                    .insert(*handle, wrapper);
+            }
+        }
+
+        engine.scheduled = snapshot
+            .scheduled
+            .iter()
+            .map(|s| match s {
+                Some(index) => domains
+                    .get(*index)
+                    .map(Rc::downgrade)
+                    .ok_or(CapaError::ParserDomain),
+                None => Ok(WeakRef::new()),
+            })
+            .collect::<Result<_, CapaError>>()?;
+        engine.last_runnable = snapshot
+            .last_runnable
+            .iter()
+            .map(|l| Cell::new(*l))
+            .collect();
+        engine.root = domains[0].clone();
+        engine.page_size = snapshot.page_size;
+        engine.max_depth = snapshot.max_depth;
+        engine.queue_updates = snapshot.queue_updates;
+        engine.update_cap = snapshot.update_cap;
+
+        // Refuse snapshots that do not describe a coherent tree.
+        engine.validate_tree()?;
+        if format!("{}", engine.root.borrow()) != snapshot.attestation {
+            return Err(CapaError::ParserCapability);
+        }
+        Ok(engine)
+    }
+
+    /// Engine enforcing `page_size` alignment on sends, see `PAGE_SIZE`.
+    pub fn with_page_size(nb_cores: u64, page_size: u64) -> Self {
+        let mut engine = Self::new(nb_cores);
+        engine.page_size = page_size;
+        engine
+    }
+
+    /// Engine queuing the updates of each operation in `updates`, keeping at
+    /// most `cap` batches, see `UpdateQueueMode`.
+    pub fn with_update_cap(nb_cores: u64, cap: usize, mode: UpdateQueueMode) -> Self {
+        let mut engine = Self::new(nb_cores);
+        engine.enable_updates();
+        engine.set_update_cap(Some((cap, mode)));
+        engine
+    }
+
+    /// Start queuing the updates of each operation in `updates`, see `drain_updates`.
+    pub fn enable_updates(&mut self) {
+        self.queue_updates = true;
+    }
+
+    /// Keep at most `cap` batches in `updates`, `None` means unbounded.
+    pub fn set_update_cap(&mut self, cap: Option<(usize, UpdateQueueMode)>) {
+        self.update_cap = cap;
+    }
+
+    pub fn update_cap(&self) -> Option<(usize, UpdateQueueMode)> {
+        self.update_cap
+    }
+
+    // Fails if the batch would be rejected, call before modifying anything.
+    fn check_update_room(&self, updates: &OperationUpdate) -> Result<(), CapaError> {
+        if updates.changes.is_empty() && updates.to_clean.is_empty() {
+            return Ok(());
+        }
+        match self.update_cap {
+            Some((cap, UpdateQueueMode::Reject)) if self.updates.len() >= cap => {
+                Err(CapaError::InvalidValue)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Hand the pending batches to the caller, oldest first.
+    pub fn drain_updates(&mut self) -> Vec<Vec<Update>> {
+        self.updates.drain(..).collect()
+    }
+
+    // Queue the batch of an operation, empty batches are not kept.
+    fn push_updates(&mut self, updates: &mut OperationUpdate) {
+        if !self.queue_updates {
+            return;
+        }
+        let batch = updates.take_updates();
+        if batch.is_empty() {
+            return;
+        }
+        if let Some((cap, mode)) = self.update_cap {
+            if cap == 0 {
+                return;
+            }
+            debug_assert!(mode == UpdateQueueMode::DropOldest || self.updates.len() < cap);
+            while self.updates.len() >= cap {
+                self.updates.pop_front();
+            }
+        }
+        self.updates.push_back(batch);
+    }
+
+    /// Limit how deep domains can be nested, `None` means unlimited.
+    pub fn set_max_depth(&mut self, max_depth: Option<usize>) {
+        self.max_depth = max_depth;
+    }
+
+    fn check_alignment(&self, access: &Access, remap: Remapped) -> Result<(), CapaError> {
+        if self.page_size == 0 {
+            return Ok(());
+        }
+        let target = match remap {
+            Remapped::Identity => access.start,
+            Remapped::Remapped(gva) => gva,
+        };
+        if [access.start, access.size, target]
+            .iter()
+            .any(|v| v % self.page_size != 0)
+        {
+            return Err(CapaError::InvalidRemap);
+        }
+        Ok(())
+    }
+
+    /// Find a live domain from its id, e.g., obtained from a previous attestation.
+    pub fn find_domain_by_id(&self, id: u64) -> Option<CapaRef<Domain>> {
+        let mut stack = vec![self.root.clone()];
+        while let Some(current) = stack.pop() {
+            let node = current.borrow();
+            if node.data.id == id {
+                return Some(current.clone());
+            }
+            // Push in reverse to visit children in order.
+            stack.extend(node.children.iter().rev().cloned());
+        }
+        None
+    }
+
+    /// Same as `alias`, the child keeps the parent's HASH if `inherit_attributes`.
+    pub fn alias_with(
+        &mut self,
+        domain: CapaRef<Domain>,
+        capa: LocalCapa,
+        access: &Access,
+        inherit_attributes: bool,
+    ) -> Result<LocalCapa, CapaError> {
+        self.is_sealed_and_allowed(&domain, MonitorAPI::ALIAS)?;
+
+        let dom = &mut domain.borrow_mut();
+        let region = dom.data.capabilities.get(&capa)?.as_region()?;
+        dom.data.capabilities.check_room(1)?;
+        let aliased =
+            region
+                .borrow_mut()
+                .alias_carve_logic(access, RegionKind::Alias, inherit_attributes)?;
+        let aliased_capa = dom.data.install(CapaWrapper::Region(aliased.clone()))?;
+
+        // Tree & ownership logic.
+        aliased.borrow_mut().parent = Rc::downgrade(&region);
+        aliased.borrow_mut().owned = Ownership::new(Rc::downgrade(&domain), aliased_capa);
+        self.record(
+            CallInterface::ALIAS,
+            dom.data.id,
+            [
+                capa,
+                access.start,
+                access.size,
+                access.rights.bits() as u64,
+                inherit_attributes as u64,
+                0,
+            ],
+            Some(aliased_capa),
+        );
+        Ok(aliased_capa)
+    }
+
+    /// Same as `carve`, the child keeps the parent's HASH if `inherit_attributes`.
+    pub fn carve_with(
+        &mut self,
+        domain: CapaRef<Domain>,
+        capa: LocalCapa,
+        access: &Access,
+        inherit_attributes: bool,
+    ) -> Result<LocalCapa, CapaError> {
+        self.is_sealed_and_allowed(&domain, MonitorAPI::CARVE)?;
+
+        let mut updates = OperationUpdate::new();
+
+        let region = {
+            let dom = &domain.borrow();
+            dom.data.capabilities.check_room(1)?;
+            dom.data.capabilities.get(&capa)?.as_region()?
+        };
+
+        // The carve must fit in the domain's quota like a received region.
+        let candidate = Capability::<MemoryRegion>::new(MemoryRegion {
+            access: *access,
+            ..region.borrow().data
+        });
+        domain.borrow().check_quota(&candidate)?;
+
+        // Carve can require updates if we reduce access rights.
+        if region.borrow().data.access.rights != access.rights {
+            updates.add(Update::ChangeMemory {
+                dom: Rc::downgrade(&domain.clone()),
+                range: *access,
+                rights: region.borrow().data.access.rights,
+            });
+        }
+        updates.snapshot()?;
+        self.check_update_room(&updates)?;
+        //TODO: notify all cores
+
+        let dom = &mut domain.borrow_mut();
+        let carved =
+            region
+                .borrow_mut()
+                .alias_carve_logic(access, RegionKind::Carve, inherit_attributes)?;
+        let carved_capa = dom.data.install(CapaWrapper::Region(carved.clone()))?;
+
+        // Tree & ownership logic.
+        carved.borrow_mut().parent = Rc::downgrade(&region);
+        carved.borrow_mut().owned = Ownership::new(Rc::downgrade(&domain), carved_capa);
+
+        updates.compute()?;
+        self.push_updates(&mut updates);
+        self.record(
+            CallInterface::CARVE,
+            dom.data.id,
+            [
+                capa,
+                access.start,
+                access.size,
+                access.rights.bits() as u64,
+                inherit_attributes as u64,
+                0,
+            ],
+            Some(carved_capa),
+        );
+        Ok(carved_capa)
+    }
+
+    /// Revoke the first alias of `capa` with exactly this access, for callers
+    /// that track regions by range rather than by child index.
+    pub fn revoke_alias(
+        &mut self,
+        domain: CapaRef<Domain>,
+        capa: LocalCapa,
+        access: &Access,
+    ) -> Result<(), CapaError> {
+        self.is_sealed_and_allowed(&domain, MonitorAPI::REVOKE)?;
+        let region = domain.borrow().data.capabilities.get(&capa)?.as_region()?;
+        let index = region
+            .borrow()
+            .children
+            .iter()
+            .position(|c| {
+                let c = c.borrow();
+                c.data.kind == RegionKind::Alias && c.data.access == *access
+            })
+            .ok_or(CapaError::ChildNotFound)?;
+        self.revoke(domain, capa, index as u64)
+    }
+
+    /// Pull the `child`-th child of `capa` back into `domain`, wherever it was
+    /// sent. Only the memory subtree below it is revoked, the domains that held
+    /// it stay alive. Returns the new handle of the region in `domain`.
+    pub fn reclaim(
+        &mut self,
+        domain: CapaRef<Domain>,
+        capa: LocalCapa,
+        child: u64,
+    ) -> Result<LocalCapa, CapaError> {
+        self.is_sealed_and_allowed(&domain, MonitorAPI::REVOKE)?;
+        let region = domain.borrow().data.capabilities.get(&capa)?.as_region()?;
+        let owner = region.borrow().owned.owner.upgrade();
+        if !owner.is_some_and(|o| same_capa(&o, &domain)) {
+            return Err(CapaError::CapaNotOwned);
+        }
+        let child_index = child;
+        let child = region
+            .borrow()
+            .children
+            .get(child as usize)
+            .cloned()
+            .ok_or(CapaError::InvalidChildCapa)?;
+        let holder = child.borrow().owned.owner.upgrade();
+        // Make sure the region can be installed before modifying anything.
+        if !holder.as_ref().is_some_and(|h| same_capa(h, &domain)) {
+            domain.borrow().data.capabilities.check_room(1)?;
+        }
+
+        // Prepare the update, this finds affected domains.
+        let mut updates = OperationUpdate::new();
+        child.borrow().on_revoke(&mut updates)?;
+        updates.snapshot()?;
+        self.check_update_room(&updates)?;
+
+        // Revoke everything below the region.
+        let descendants = child.borrow().children.clone();
+        for d in descendants.iter() {
+            child
+                .borrow_mut()
+                .revoke_child(d, &mut |a| Self::revoke_region_handler(a))?;
+        }
+
+        // Move the region back to the caller, as if freshly carved.
+        let handle = match holder {
+            Some(h) if same_capa(&h, &domain) => child.borrow().owned.handle,
+            _ => {
+                Self::revoke_region_handler(&mut child.borrow_mut())?;
+                domain
+                    .borrow_mut()
+                    .data
+                    .install(CapaWrapper::Region(child.clone()))?
+            }
+        };
+        {
+            let mut reclaimed = child.borrow_mut();
+            reclaimed.owned = Ownership::new(Rc::downgrade(&domain), handle);
+            reclaimed.data.remapped = region
+                .borrow()
+                .data
+                .remapped
+                .offset(reclaimed.data.access.start - region.borrow().data.access.start);
+            reclaimed.data.attributes = Attributes::NONE;
+        }
+        Self::revoke_vital_holders(&mut updates)?;
+        updates.compute()?;
+        self.push_updates(&mut updates);
+
+        debug_assert_eq!(self.validate_tree(), Ok(()));
+        let id = domain.borrow().data.id;
+        self.record(
+            CallInterface::RECLAIM,
+            id,
+            [capa, child_index, 0, 0, 0, 0],
+            Some(handle),
+        );
+        Ok(handle)
+    }
+
+    /// Turn an owned carve into a root region that survives the revocation
+    /// of its parent, see `Capability::<MemoryRegion>::promote`.
+    /// The domain must own every ancestor, as they all give up the range.
+    pub fn promote(&mut self, domain: CapaRef<Domain>, capa: LocalCapa) -> Result<(), CapaError> {
+        self.is_sealed_and_allowed(&domain, MonitorAPI::CARVE)?;
+        let region = domain.borrow().data.capabilities.get(&capa)?.as_region()?;
+        let owned_by_domain = |r: &CapaRef<MemoryRegion>| {
+            let owner = r.borrow().owned.owner.upgrade();
+            owner.is_some_and(|o| same_capa(&o, &domain))
+        };
+        if !owned_by_domain(&region) {
+            return Err(CapaError::CapaNotOwned);
+        }
+        // Each ancestor loses the range it had with its own rights.
+        let access = region.borrow().data.access;
+        let mut updates = OperationUpdate::new();
+        let mut current = region.borrow().parent.upgrade();
+        while let Some(ancestor) = current {
+            if !owned_by_domain(&ancestor) {
+                return Err(CapaError::CapaNotOwned);
+            }
+            updates.add(Update::ChangeMemory {
+                dom: Rc::downgrade(&domain),
+                range: access,
+                rights: ancestor.borrow().data.access.rights,
+            });
+            current = ancestor.borrow().parent.upgrade();
+        }
+        updates.snapshot()?;
+        self.check_update_room(&updates)?;
+        Capability::<MemoryRegion>::promote(&region)?;
+        updates.compute()?;
+        self.push_updates(&mut updates);
+        debug_assert_eq!(self.validate_tree(), Ok(()));
+        let id = domain.borrow().data.id;
+        self.record(CallInterface::PROMOTE, id, [capa, 0, 0, 0, 0, 0], None);
+        Ok(())
+    }
+
+    /// Round-robin over the sealed domains allowed to run on `core`, by id.
+    /// Revoked and unsealed domains are skipped.
+    pub fn next_runnable(&self, core: u64) -> Option<CapaRef<Domain>> {
+        let last = self.last_runnable.get(core as usize)?;
+        let mut candidates = Vec::new();
+        let mut stack = vec![self.root.clone()];
+        while let Some(current) = stack.pop() {
+            let node = current.borrow();
+            stack.extend(node.children.iter().cloned());
+            if node.data.is_sealed() && (node.data.policies.cores >> core) & 1 == 1 {
+                candidates.push((node.data.id, current.clone()));
+            }
+        }
+        candidates.sort_by_key(|(id, _)| *id);
+        // The first one after the last pick, wrapping around.
+        let idx = match last.get() {
+            Some(prev) => candidates
+                .iter()
+                .position(|(id, _)| *id > prev)
+                .unwrap_or(0),
+            None => 0,
+        };
+        let (id, next) = candidates.into_iter().nth(idx)?;
+        last.set(Some(id));
+        Some(next)
+    }
+
+    /// Check the invariants linking domains and regions:
+    /// every held capability is owned by its holder, no region is held twice,
+    /// and parent/children edges agree in both directions.
+    pub fn validate_tree(&self) -> Result<(), CapaError> {
+        let owned_by = |owner: &WeakRef<Domain>, dom: &CapaRef<Domain>| {
+            owner.upgrade().is_some_and(|o| same_capa(&o, dom))
+        };
+        let mut seen: HashSet<*const RefCell<Capability<MemoryRegion>>> = HashSet::new();
+        let mut stack = vec![self.root.clone()];
+        while let Some(dom) = stack.pop() {
+            let node = dom.borrow();
+            for child in node.children.iter() {
+                if !child
+                    .borrow()
+                    .parent
+                    .upgrade()
+                    .is_some_and(|p| same_capa(&p, &dom))
+                {
+                    return Err(CapaError::ChildNotFound);
+                }
+            }
+            for (_, capa) in node.data.capabilities.capabilities.iter() {
+                match capa {
+                    CapaWrapper::Domain(d) => {
+                        if !owned_by(&d.borrow().owned.owner, &dom) {
+                            return Err(CapaError::CapaNotOwned);
+                        }
+                    }
+                    CapaWrapper::Region(r) => {
+                        if !owned_by(&r.borrow().owned.owner, &dom) || !seen.insert(Rc::as_ptr(r)) {
+                            return Err(CapaError::CapaNotOwned);
+                        }
+                        let region = r.borrow();
+                        if let Some(parent) = region.parent.upgrade() {
+                            if !parent.borrow().children.iter().any(|c| same_capa(c, r)) {
+                                return Err(CapaError::ChildNotFound);
+                            }
+                        }
+                        for child in region.children.iter() {
+                            if !child
+                                .borrow()
+                                .parent
+                                .upgrade()
+                                .is_some_and(|p| same_capa(&p, r))
+                            {
+                                return Err(CapaError::ChildNotFound);
+                            }
+                        }
+                    }
+                }
+            }
+            stack.extend(node.children.iter().cloned());
+        }
+        Ok(())
+    }
+
+    /// Decode a call marshalled by the client and run it on behalf of the domain.
+    pub fn dispatch(
+        &mut self,
+        domain: CapaRef<Domain>,
+        call: CallInterface,
+        args: &[u64; 6],
+    ) -> Result<ClientResult, CapaError> {
+        match call {
+            CallInterface::SET => {
+                let field_type = FieldType::try_from(args[2])?;
+                self.set(domain, args[0], args[1], field_type, args[3], args[4])?;
+                Ok(ClientResult::EmptyValue)
+            }
+            CallInterface::GET => {
+                let field_type = FieldType::try_from(args[2])?;
+                let value = self.get(domain, args[0], args[1], field_type, args[3])?;
+                Ok(ClientResult::SingleValue(value))
+            }
+            CallInterface::SEAL => {
+                self.seal(domain, args[0])?;
+                Ok(ClientResult::EmptyValue)
+            }
+            CallInterface::SEND => {
+                let remap = if args[2] == 0 {
+                    Remapped::Identity
+                } else {
+                    Remapped::Remapped(args[3])
+                };
+                let attributes = Attributes::from_bits_truncate(args[4] as u8);
+                self.send(domain, args[0], args[1], remap, attributes)?;
+                Ok(ClientResult::EmptyValue)
+            }
+            CallInterface::ALIAS | CallInterface::CARVE => {
+                // Reject unknown bits rather than silently dropping them.
+                let rights = u8::try_from(args[3])
+                    .ok()
+                    .and_then(Rights::from_bits)
+                    .ok_or(CapaError::InvalidValue)?;
+                let access = Access::new(args[1], args[2], rights);
+                let inherit = args[4] != 0;
+                let capa = if call == CallInterface::ALIAS {
+                    self.alias_with(domain, args[0], &access, inherit)?
+                } else {
+                    self.carve_with(domain, args[0], &access, inherit)?
+                };
+                Ok(ClientResult::SingleValue(capa))
+            }
+            CallInterface::CREATE => {
+                let quota = if args[2] != 0 { Some(args[3]) } else { None };
+                let (child, _) = self.create_child(
+                    &domain,
+                    args[0],
+                    MonitorAPI::from_bits_truncate(args[1] as u16),
+                    InterruptPolicy::default_none(),
+                    quota,
+                )?;
+                Ok(ClientResult::SingleValue(child))
+            }
+            CallInterface::ATTEST => {
+                let other = if args[0] != 0 { Some(args[0]) } else { None };
+                Ok(ClientResult::StringValue(self.attest(domain, other)?))
+            }
+            CallInterface::SWITCH => {
+                self.switch(domain, args[0])?;
+                Ok(ClientResult::EmptyValue)
+            }
+            CallInterface::REVOKE => {
+                self.revoke(domain, args[0], args[1])?;
+                Ok(ClientResult::EmptyValue)
+            }
+            CallInterface::INTERRUPTS => {
+                let visibility =
+                    VectorVisibility::from_bits(args[3] as u8).ok_or(CapaError::InvalidValue)?;
+                let policy = VectorPolicy {
+                    visibility,
+                    read_set: args[4],
+                    write_set: args[5],
+                };
+                self.set_interrupt_range(
+                    domain,
+                    args[0],
+                    args[1] as usize,
+                    args[2] as usize,
+                    policy,
+                )?;
+                Ok(ClientResult::EmptyValue)
+            }
+            CallInterface::ENUMERATE => {
+                Ok(ClientResult::StringValue(self.enumerate(domain, args[0])?))
+            }
+            CallInterface::RESTRICT => {
+                let rights = u8::try_from(args[1])
+                    .ok()
+                    .and_then(Rights::from_bits)
+                    .ok_or(CapaError::InvalidValue)?;
+                self.restrict(domain, args[0], rights)?;
+                Ok(ClientResult::EmptyValue)
+            }
+            CallInterface::RECLAIM => {
+                let capa = self.reclaim(domain, args[0], args[1])?;
+                Ok(ClientResult::SingleValue(capa))
+            }
+            CallInterface::TRANSFER => {
+                let capa = self.transfer(domain, args[0], args[1], args[2])?;
+                Ok(ClientResult::SingleValue(capa))
+            }
+            CallInterface::DUPLICATE => {
+                let capa = self.duplicate(domain, args[0])?;
+                Ok(ClientResult::SingleValue(capa))
+            }
+            CallInterface::PROMOTE => {
+                self.promote(domain, args[0])?;
+                Ok(ClientResult::EmptyValue)
+            }
+            CallInterface::ATTRIBUTES => {
+                let attributes = u8::try_from(args[1])
+                    .ok()
+                    .and_then(Attributes::from_bits)
+                    .ok_or(CapaError::InvalidValue)?;
+                self.set_attributes(domain, args[0], attributes)?;
+                Ok(ClientResult::EmptyValue)
+            }
+            CallInterface::INHERIT => {
+                self.inherit_interrupts(domain, args[0])?;
+                Ok(ClientResult::EmptyValue)
+            }
+        }
+    }
+
+    /// Change the attributes of a region the domain still owns and did not receive,
+    /// i.e., before it is sent.
+    /// VITAL and CLEAN are set by the sender, the receiver cannot clear them.
+    pub fn set_attributes(
+        &mut self,
+        domain: CapaRef<Domain>,
+        capa: LocalCapa,
+        attributes: Attributes,
+    ) -> Result<(), CapaError> {
+        self.is_sealed_and_allowed(&domain, MonitorAPI::SEND)?;
+        let region = domain.borrow().data.capabilities.get(&capa)?.as_region()?;
+        let owned_by_domain = |r: &CapaRef<MemoryRegion>| {
+            let owner = r.borrow().owned.owner.upgrade();
+            owner.is_some_and(|o| same_capa(&o, &domain))
+        };
+        // A region whose parent is held elsewhere was received.
+        let parent = region.borrow().parent.upgrade();
+        if !owned_by_domain(&region) || !parent.as_ref().is_none_or(owned_by_domain) {
+            return Err(CapaError::InvalidAttributes);
+        }
+        if region
+            .borrow()
+            .data
+            .attributes
+            .intersects(Attributes::VITAL | Attributes::CLEAN)
+        {
+            return Err(CapaError::InvalidAttributes);
+        }
+        region.borrow_mut().data.attributes = attributes;
+        let id = domain.borrow().data.id;
+        self.record(
+            CallInterface::ATTRIBUTES,
+            id,
+            [capa, attributes.bits() as u64, 0, 0, 0, 0],
+            None,
+        );
+        Ok(())
+    }
+
+    /// Create a child domain that cannot access more than `max_footprint` bytes.
+    pub fn create_with_quota(
+        &mut self,
+        domain: &CapaRef<Domain>,
+        cores: u64,
+        api: MonitorAPI,
+        interrupts: InterruptPolicy,
+        max_footprint: u64,
+    ) -> Result<LocalCapa, CapaError> {
+        self.create_child(domain, cores, api, interrupts, Some(max_footprint))
+            .map(|(capa, _)| capa)
+    }
+
+    /// Same as `attest` with a trailing checksum line for tamper evidence.
+    pub fn attest_signed(
+        &mut self,
+        domain: CapaRef<Domain>,
+        other: Option<LocalCapa>,
+    ) -> Result<String, CapaError> {
+        Ok(sign_attestation(self.attest(domain, other)?))
+    }
+
+    /// Same as `create` but also returns the new child.
+    pub fn create_ref(
+        &mut self,
+        domain: &CapaRef<Domain>,
+        cores: u64,
+        api: MonitorAPI,
+        interrupts: InterruptPolicy,
+    ) -> Result<(LocalCapa, CapaRef<Domain>), CapaError> {
+        self.create_child(domain, cores, api, interrupts, None)
+    }
+
+    // Common path of the create calls, the quota is recorded with the call.
+    fn create_child(
+        &mut self,
+        domain: &CapaRef<Domain>,
+        cores: u64,
+        api: MonitorAPI,
+        interrupts: InterruptPolicy,
+        max_footprint: Option<u64>,
+    ) -> Result<(LocalCapa, CapaRef<Domain>), CapaError> {
+        self.is_sealed_and_allowed(domain, MonitorAPI::CREATE)?;
+
+        if let Some(max) = self.max_depth {
+            // The child sits one level below the domain.
+            let mut depth = 1;
+            let mut current = domain.borrow().parent.upgrade();
+            while let Some(p) = current {
+                depth += 1;
+                current = p.borrow().parent.upgrade();
+            }
+            if depth > max {
+                return Err(CapaError::MaxDepthExceeded);
+            }
+        }
+
+        let dom = &mut domain.borrow_mut();
+        if !is_core_subset(dom.data.policies.cores, cores) {
+            return Err(CapaError::InsufficientRights);
+        }
+        // Fail early rather than at seal time.
+        if !dom.data.policies.api.contains(api) {
+            return Err(CapaError::InsufficientRights);
+        }
+        let policies = Policies::new(cores, api, interrupts);
+        let mut child_dom = Domain::new(policies);
+        child_dom.max_footprint = max_footprint;
+
+        let capa = Capability::<Domain>::new(child_dom);
+        let reference = Rc::new(RefCell::new(capa));
+        // Install first so a full store does not leave a dangling child.
+        let local_capa = dom.data.install(CapaWrapper::Domain(reference.clone()))?;
+        dom.add_child(reference.clone(), Rc::downgrade(domain));
+        reference.borrow_mut().parent = Rc::downgrade(domain);
+        self.record(
+            CallInterface::CREATE,
+            dom.data.id,
+            [
+                cores,
+                api.bits() as u64,
+                max_footprint.is_some() as u64,
+                max_footprint.unwrap_or(0),
+                0,
+                0,
+            ],
+            Some(local_capa),
+        );
+        Ok((local_capa, reference))
+    }
+
+    /// Dry run of `send`: performs all its checks and returns the error it
+    /// would produce, without modifying anything.
+    pub fn can_send(
+        &self,
+        domain: CapaRef<Domain>,
+        dest: LocalCapa,
+        capa: LocalCapa,
+        remap: Remapped,
+        attributes: Attributes,
+    ) -> Result<(), CapaError> {
+        self.is_sealed_and_allowed(&domain, MonitorAPI::SEND)?;
+        let dest = { domain.borrow().data.capabilities.get(&dest)?.as_domain()? };
+        // A handle back to the caller would remove and reinstall the region.
+        if same_capa(&dest, &domain) {
+            return Err(CapaError::SelfSend);
+        }
+        // Sending upwards would create a cycle in the tree.
+        if domain.borrow().has_ancestor(&dest) {
+            return Err(CapaError::CycleDetected);
+        }
+
+        Self::check_dest_policy(&dest, attributes)?;
+
+        // Check the attributes for the owner and conflicts in the dest.
+        let region = domain.borrow().data.capabilities.get(&capa)?.as_region()?;
+        // Check attributes.
+        if region
+            .borrow()
+            .data
+            .attributes
+            .intersects(Attributes::VITAL | Attributes::CLEAN)
+        {
+            return Err(CapaError::InvalidAttributes);
+        }
+        self.check_alignment(&region.borrow().data.access, remap)?;
+        Self::check_dest_room(&dest, &region, remap)
+    }
+
+    // Whether the dest accepts a region with these attributes, see `send`.
+    fn check_dest_policy(dest: &CapaRef<Domain>, attributes: Attributes) -> Result<(), CapaError> {
+        if !dest.borrow().data.can_receive() {
+            return Err(CapaError::CallNotAllowed);
+        }
+        // A sealed domain only accepts regions that will be cleaned on revocation.
+        if dest.borrow().data.is_sealed() && !attributes.difference(Attributes::CLEAN).is_empty() {
+            return Err(CapaError::CallNotAllowed);
+        }
+        Ok(())
+    }
+
+    // Whether the region fits in the dest: no conflict, within quota and a free handle.
+    fn check_dest_room(
+        dest: &CapaRef<Domain>,
+        region: &CapaRef<MemoryRegion>,
+        remap: Remapped,
+    ) -> Result<(), CapaError> {
+        dest.borrow()
+            .check_conflict(&ViewRegion::new(region.borrow().data.access, remap))?;
+        dest.borrow().check_quota(&region.borrow())?;
+        // The region would be lost if the dest cannot install it.
+        dest.borrow().data.capabilities.check_room(1)
+    }
+
+    /// Seal the child and attest it under the same `&mut self` borrow, so nothing
+    /// can modify the child in between. Fails before sealing if ATTEST is missing.
+    pub fn seal_and_attest(
+        &mut self,
+        domain: CapaRef<Domain>,
+        child: LocalCapa,
+    ) -> Result<String, CapaError> {
+        self.is_sealed_and_allowed(&domain, MonitorAPI::ATTEST)?;
+        self.seal(domain.clone(), child)?;
+        self.attest(domain, Some(child))
+    }
+
+    /// Set `count` interrupt vectors of an unsealed child starting at `first`.
+    pub fn set_interrupt_range(
+        &mut self,
+        domain: CapaRef<Domain>,
+        child: LocalCapa,
+        first: usize,
+        count: usize,
+        policy: VectorPolicy,
+    ) -> Result<(), CapaError> {
+        self.is_sealed_and_allowed(&domain, MonitorAPI::SET)?;
+        let target = Self::owned_child(&domain, child)?;
+        if target.borrow().data.is_sealed() {
+            return Err(CapaError::DomainSealed);
+        }
+        target
+            .borrow_mut()
+            .data
+            .policies
+            .interrupts
+            .set_range(first, count, policy)?;
+        let id = domain.borrow().data.id;
+        self.record(
+            CallInterface::INTERRUPTS,
+            id,
+            [
+                child,
+                first as u64,
+                count as u64,
+                policy.visibility.bits() as u64,
+                policy.read_set,
+                policy.write_set,
+            ],
+            None,
+        );
+        Ok(())
+    }
 
-use crate::core::capability::{CapaError, CapaRef, Capability, Ownership, WeakRef};
-use crate::core::domain::CapaWrapper;
-use crate::core::domain::{
-    Domain, Field, FieldType, InterruptPolicy, LocalCapa, MonitorAPI, Policies, Status,
-};
-use crate::core::memory_region::{Access, Attributes, MemoryRegion, Remapped, ViewRegion};
-use crate::core::update::{CoreUpdate, OperationUpdate, Update};
-use crate::{is_core_subset, EngineInterface};
+    /// Replace the whole interrupt policy of an unsealed child in one operation.
+    pub fn set_interrupt_policy(
+        &mut self,
+        domain: CapaRef<Domain>,
+        child: LocalCapa,
+        policy: &InterruptPolicy,
+    ) -> Result<(), CapaError> {
+        // Identical vectors are set together, the checks fail on the first run.
+        for (first, count, vector) in policy.runs() {
+            self.set_interrupt_range(domain.clone(), child, first, count, vector)?;
+        }
+        Ok(())
+    }
 
-/// Engine implementation.
-/// This is the entry point for all operations.
-pub struct Engine {
-    // The root lives in the engine.
-    pub root: CapaRef<Domain>,
-    pub scheduled: Vec<WeakRef<Domain>>,
-    pub updates: VecDeque<Vec<Update>>,
-    pub core_update: Vec<Vec<CoreUpdate>>,
-}
+    /// Copy the domain's whole interrupt policy into an unsealed child,
+    /// instead of one SET per vector. The child can narrow it afterwards.
+    pub fn inherit_interrupts(
+        &mut self,
+        domain: CapaRef<Domain>,
+        child: LocalCapa,
+    ) -> Result<(), CapaError> {
+        self.is_sealed_and_allowed(&domain, MonitorAPI::SET)?;
+        let interrupts = domain.borrow().data.policies.interrupts;
+        let target = Self::owned_child(&domain, child)?;
+        if target.borrow().data.is_sealed() {
+            return Err(CapaError::DomainSealed);
+        }
+        target.borrow_mut().data.policies.interrupts = interrupts;
+        let id = domain.borrow().data.id;
+        self.record(CallInterface::INHERIT, id, [child, 0, 0, 0, 0, 0], None);
+        Ok(())
+    }
 
-impl Engine {
-    fn is_sealed_and_allowed(
-        &self,
-        domain: &CapaRef<Domain>,
-        call: MonitorAPI,
+    /// Drop rights from a region the domain owns, e.g., remove EXECUTE.
+    /// Children must already fit within the new rights.
+    pub fn restrict(
+        &mut self,
+        domain: CapaRef<Domain>,
+        capa: LocalCapa,
+        new_rights: Rights,
     ) -> Result<(), CapaError> {
-        let dom = domain.borrow();
-        if dom.data.status != Status::Sealed {
-            return Err(CapaError::DomainUnsealed);
+        // Giving up rights needs no API, only the ownership of the region.
+        self.is_sealed_and_allowed(&domain, MonitorAPI::empty())?;
+        let region = domain.borrow().data.capabilities.get(&capa)?.as_region()?;
+        if !region
+            .borrow()
+            .owned
+            .owner
+            .upgrade()
+            .is_some_and(|o| same_capa(&o, &domain))
+        {
+            return Err(CapaError::CapaNotOwned);
         }
-        if !dom.data.operation_allowed(call) {
-            return Err(CapaError::CallNotAllowed);
+        let access = region.borrow().data.access;
+        if !new_rights.is_subset_of(&access.rights) {
+            return Err(CapaError::InsufficientRights);
+        }
+        if region
+            .borrow()
+            .children
+            .iter()
+            .any(|c| !c.borrow().data.access.rights.is_subset_of(&new_rights))
+        {
+            return Err(CapaError::InvalidAccess);
+        }
+
+        let mut updates = OperationUpdate::new();
+        if new_rights != access.rights {
+            updates.add(Update::ChangeMemory {
+                dom: Rc::downgrade(&domain),
+                range: access,
+                rights: access.rights,
+            });
         }
+        updates.snapshot()?;
+        self.check_update_room(&updates)?;
+        region.borrow_mut().data.access.rights = new_rights;
+        updates.compute()?;
+        self.push_updates(&mut updates);
+        let id = domain.borrow().data.id;
+        self.record(
+            CallInterface::RESTRICT,
+            id,
+            [capa, new_rights.bits() as u64, 0, 0, 0, 0],
+            None,
+        );
         Ok(())
     }
 
-    pub fn add_root_region(
-        &self,
+    /// Create `count` identical children, either all of them or none.
+    pub fn create_children(
+        &mut self,
         domain: &CapaRef<Domain>,
-        region: &CapaRef<MemoryRegion>,
+        count: usize,
+        cores: u64,
+        api: MonitorAPI,
+        interrupts: InterruptPolicy,
+    ) -> Result<Vec<LocalCapa>, CapaError> {
+        self.is_sealed_and_allowed(domain, MonitorAPI::CREATE)?;
+        {
+            let dom = domain.borrow();
+            let policies = &dom.data.policies;
+            if !is_core_subset(policies.cores, cores) || !policies.api.contains(api) {
+                return Err(CapaError::InsufficientRights);
+            }
+            // The children are identical, only the handles can run out midway.
+            dom.data.capabilities.check_room(count)?;
+        }
+        let recorded = self.audit.as_ref().map(|a| a.len());
+        let mut children = Vec::with_capacity(count);
+        for _ in 0..count {
+            match self.create(domain, cores, api, interrupts) {
+                Ok(child) => children.push(child),
+                Err(e) => {
+                    // Roll back the ones we already created, without a trace.
+                    self.revoke_child_domains(domain, &children)?;
+                    if let (Some(audit), Some(len)) = (self.audit.as_mut(), recorded) {
+                        audit.truncate(len);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(children)
+    }
+
+    /// Seal the child and all the unsealed domains it transitively owns.
+    /// Everything is validated first so that a failure seals nothing.
+    pub fn seal_recursive(
+        &mut self,
+        domain: CapaRef<Domain>,
+        capa: LocalCapa,
+    ) -> Result<(), CapaError> {
+        self.is_sealed_and_allowed(&domain, MonitorAPI::SEAL)?;
+        Self::check_seal(&domain, capa)?;
+
+        // Collect (owner, handle) pairs to seal, in pre-order.
+        let mut to_seal: Vec<(CapaRef<Domain>, LocalCapa)> = Vec::new();
+        let mut stack = vec![(domain.clone(), capa)];
+        while let Some((owner, handle)) = stack.pop() {
+            let child = owner.borrow().data.capabilities.get(&handle)?.as_domain()?;
+            let owned: Vec<LocalCapa> = child
+                .borrow()
+                .data
+                .capabilities
+                .capabilities
+                .iter()
+                .filter_map(|(h, c)| match c {
+                    CapaWrapper::Domain(d) if !d.borrow().data.is_sealed() => Some(*h),
+                    _ => None,
+                })
+                .collect();
+            stack.extend(owned.into_iter().rev().map(|h| (child.clone(), h)));
+            to_seal.push((owner, handle));
+        }
+
+        // Validate everything first, so that a failure seals nothing.
+        for (owner, handle) in to_seal.iter() {
+            Self::check_seal(owner, *handle)?;
+        }
+        for (owner, handle) in to_seal {
+            owner.borrow().seal(handle)?;
+            let id = owner.borrow().data.id;
+            self.record(CallInterface::SEAL, id, [handle, 0, 0, 0, 0, 0], None);
+        }
+        Ok(())
+    }
+
+    /// Move a region between two sealed children of the domain.
+    /// The memory tree is untouched, only the ownership changes.
+    pub fn transfer(
+        &mut self,
+        domain: CapaRef<Domain>,
+        from: LocalCapa,
+        from_capa: LocalCapa,
+        to: LocalCapa,
     ) -> Result<LocalCapa, CapaError> {
-        let local_handle = {
-            let dom = &mut domain.borrow_mut();
-            dom.data.install(CapaWrapper::Region(region.clone()))
+        self.is_sealed_and_allowed(&domain, MonitorAPI::SEND)?;
+
+        let (from_handle, to_handle) = (from, to);
+        let (from, to) = {
+            let dom = domain.borrow();
+            (
+                dom.data.capabilities.get(&from)?.as_domain()?,
+                dom.data.capabilities.get(&to)?.as_domain()?,
+            )
         };
-        let reg = &mut region.borrow_mut();
-        reg.owned = Ownership::new(Rc::downgrade(domain), local_handle);
-        Ok(local_handle)
-    }
+        if same_capa(&from, &to) || from.borrow().has_ancestor(&to) {
+            return Err(CapaError::CycleDetected);
+        }
+        if !from.borrow().data.is_sealed() || !to.borrow().data.is_sealed() {
+            return Err(CapaError::DomainUnsealed);
+        }
+        let region = from
+            .borrow()
+            .data
+            .capabilities
+            .get(&from_capa)?
+            .as_region()?;
+        let (access, remapped, attributes) = {
+            let reg = region.borrow();
+            (reg.data.access, reg.data.remapped, reg.data.attributes)
+        };
+        // Same checks as a send of the region with its current attributes.
+        Self::check_dest_policy(&to, attributes)?;
+        Self::check_dest_room(&to, &region, remapped)?;
 
-    fn revoke_region_handler(capa: &mut Capability<MemoryRegion>) -> Result<(), CapaError> {
-        let owner = capa.owned.owner.upgrade().ok_or(CapaError::CapaNotOwned)?;
-        owner
+        // Both children see their memory change.
+        let mut updates = OperationUpdate::new();
+        updates.add(Update::ChangeMemory {
+            dom: Rc::downgrade(&from),
+            range: access,
+            rights: access.rights,
+        });
+        updates.add(Update::ChangeMemory {
+            dom: Rc::downgrade(&to),
+            range: access,
+            rights: Rights::empty(),
+        });
+        updates.snapshot()?;
+        self.check_update_room(&updates)?;
+
+        // Install first so that a failure leaves the source intact.
+        let to_capa = to
             .borrow_mut()
             .data
-            .capabilities
-            .remove(&capa.owned.handle)?;
-        Ok(())
+            .install(CapaWrapper::Region(region.clone()))?;
+        from.borrow_mut().data.capabilities.remove(&from_capa)?;
+        region.borrow_mut().owned = Ownership::new(Rc::downgrade(&to), to_capa);
+
+        updates.compute()?;
+        self.push_updates(&mut updates);
+        let id = domain.borrow().data.id;
+        self.record(
+            CallInterface::TRANSFER,
+            id,
+            [from_handle, from_capa, to_handle, 0, 0, 0],
+            Some(to_capa),
+        );
+        Ok(to_capa)
+    }
+
+    /// Structured version of enumerate for a region and its subtree.
+    pub fn enumerate_tree(
+        &self,
+        domain: CapaRef<Domain>,
+        capa: LocalCapa,
+    ) -> Result<RegionNode, CapaError> {
+        self.is_sealed_and_allowed(&domain, MonitorAPI::ENUMERATE)?;
+        let region = domain.borrow().data.capabilities.get(&capa)?.as_region()?;
+        let node = region.borrow().enumerate_tree();
+        Ok(node)
+    }
+
+    /// Visit every region reachable from the root exactly once.
+    pub fn for_each_region<F: FnMut(&CapaRef<MemoryRegion>)>(&self, mut f: F) {
+        let mut seen: Vec<CapaRef<MemoryRegion>> = Vec::new();
+        let mut stack = vec![self.root.clone()];
+        while let Some(current) = stack.pop() {
+            let node = current.borrow();
+            // The closure never fails.
+            let _ = node
+                .data
+                .capabilities
+                .foreach_region(&mut |r: &CapaRef<MemoryRegion>| {
+                    if !seen.iter().any(|s| same_capa(s, r)) {
+                        seen.push(r.clone());
+                        f(r);
+                    }
+                    Ok(())
+                });
+            stack.extend(node.children.iter().rev().cloned());
+        }
     }
 }
 
@@ -67,21 +1714,11 @@ impl EngineInterface for Engine {
     type CapabilityError = CapaError;
 
     fn new(nb_cores: u64) -> Self {
-        // Create the root capability for the domain.
-        let mut root = Domain::new(Policies::new(
+        Self::with_root_policies(Policies::new(
             (1 << nb_cores) - 1,
             MonitorAPI::all(),
             InterruptPolicy::default_all(),
-        ));
-        root.status = Status::Sealed;
-        let dom = Capability::<Domain>::new(root);
-        let ref_td = Rc::new(RefCell::new(dom));
-        Engine {
-            root: ref_td,
-            scheduled: Vec::new(), /*vec![&ref_td; nb_cores]*/
-            updates: VecDeque::<Vec<Update>>::new(),
-            core_update: Vec::new(),
-        }
+        ))
     }
 
     fn create(
@@ -91,20 +1728,8 @@ impl EngineInterface for Engine {
         api: MonitorAPI,
         interrupts: InterruptPolicy,
     ) -> Result<LocalCapa, CapaError> {
-        self.is_sealed_and_allowed(&domain, MonitorAPI::CREATE)?;
-
-        let dom = &mut domain.borrow_mut();
-        if !is_core_subset(dom.data.policies.cores, cores) {
-            return Err(CapaError::InsufficientRights);
-        }
-        let policies = Policies::new(cores, api, interrupts);
-        let child_dom = Domain::new(policies);
-
-        let capa = Capability::<Domain>::new(child_dom);
-        let reference = Rc::new(RefCell::new(capa));
-        dom.add_child(reference.clone(), Rc::downgrade(&domain));
-        let local_capa = dom.data.install(CapaWrapper::Domain(reference));
-        Ok(local_capa)
+        self.create_ref(domain, cores, api, interrupts)
+            .map(|(capa, _)| capa)
     }
 
     fn set(
@@ -117,29 +1742,21 @@ impl EngineInterface for Engine {
         value: u64,
     ) -> Result<(), CapaError> {
         self.is_sealed_and_allowed(&domain, MonitorAPI::SET)?;
+        let child_dom = Self::owned_child(&domain, child)?;
         // Check if the domain is sealed in which case policies cannot be set.
-        if tpe != FieldType::Register
-            && domain
-                .borrow()
-                .data
-                .capabilities
-                .get(&child)?
-                .as_domain()?
-                .borrow()
-                .data
-                .is_sealed()
-        {
+        if tpe != FieldType::Register && child_dom.borrow().data.is_sealed() {
             return Err(CapaError::DomainSealed);
         }
         // The fact that it is a subset will be checked at seal time for policies.
-        domain
-            .borrow()
-            .data
-            .capabilities
-            .get(&child)?
-            .as_domain()?
-            .borrow_mut()
-            .set(core, tpe, field, value)
+        child_dom.borrow_mut().set(core, tpe, field, value)?;
+        let id = domain.borrow().data.id;
+        self.record(
+            CallInterface::SET,
+            id,
+            [child, core, tpe as u64, field, value, 0],
+            None,
+        );
+        Ok(())
     }
 
     fn get(
@@ -151,35 +1768,26 @@ impl EngineInterface for Engine {
         field: Field,
     ) -> Result<u64, CapaError> {
         self.is_sealed_and_allowed(&domain, MonitorAPI::GET)?;
-        domain
-            .borrow()
-            .data
-            .capabilities
-            .get(&child)?
-            .as_domain()?
+        let value = Self::owned_child(&domain, child)?
             .borrow()
-            .get(core, tpe, field)
+            .get(core, tpe, field)?;
+        let id = domain.borrow().data.id;
+        self.record(
+            CallInterface::GET,
+            id,
+            [child, core, tpe as u64, field, 0, 0],
+            None,
+        );
+        Ok(value)
     }
 
     fn seal(&mut self, domain: CapaRef<Domain>, child: LocalCapa) -> Result<(), CapaError> {
         self.is_sealed_and_allowed(&domain, MonitorAPI::SEAL)?;
-
-        let current_policies = &domain.borrow().data.policies;
-        // Check the child's policies are a subset of the parent.
-        if !current_policies.contains(
-            &domain
-                .borrow()
-                .data
-                .capabilities
-                .get(&child)?
-                .as_domain()?
-                .borrow()
-                .data
-                .policies,
-        ) {
-            return Err(CapaError::InsufficientRights);
-        }
-        domain.borrow().seal(child)
+        Self::check_seal(&domain, child)?;
+        domain.borrow().seal(child)?;
+        let id = domain.borrow().data.id;
+        self.record(CallInterface::SEAL, id, [child, 0, 0, 0, 0, 0], None);
+        Ok(())
     }
 
     fn attest(
@@ -189,26 +1797,51 @@ impl EngineInterface for Engine {
     ) -> Result<String, CapaError> {
         self.is_sealed_and_allowed(&domain, MonitorAPI::ATTEST)?;
 
-        if let Some(child) = other {
-            return domain.borrow().attest(child);
-        }
-        let display = format!("{}", domain.borrow());
-        return Ok(display);
+        let attestation = if let Some(child) = other {
+            domain.borrow().attest(child)?
+        } else {
+            format!("{}", domain.borrow())
+        };
+        let id = domain.borrow().data.id;
+        self.record(CallInterface::ATTEST, id, [other.unwrap_or(0); 6], None);
+        Ok(attestation)
     }
 
     fn enumerate(&mut self, domain: CapaRef<Domain>, capa: LocalCapa) -> Result<String, CapaError> {
         self.is_sealed_and_allowed(&domain, MonitorAPI::ENUMERATE)?;
-        let binding = domain.borrow();
-        let capa = binding.data.capabilities.get(&capa)?;
-        match capa {
-            CapaWrapper::Region(r) => Ok(format!("{}", r.borrow())),
-            CapaWrapper::Domain(d) => Ok(format!("{}", d.borrow())),
-        }
+        let (id, enumeration) = {
+            let binding = domain.borrow();
+            let enumeration = match binding.data.capabilities.get(&capa)? {
+                CapaWrapper::Region(r) => format!("{}", r.borrow()),
+                CapaWrapper::Domain(d) => format!("{}", d.borrow()),
+            };
+            (binding.data.id, enumeration)
+        };
+        self.record(CallInterface::ENUMERATE, id, [capa, 0, 0, 0, 0, 0], None);
+        Ok(enumeration)
     }
 
-    fn switch(&mut self, domain: CapaRef<Domain>, _capa: LocalCapa) -> Result<(), CapaError> {
+    fn switch(&mut self, domain: CapaRef<Domain>, capa: LocalCapa) -> Result<(), CapaError> {
         self.is_sealed_and_allowed(&domain, MonitorAPI::SWITCH)?;
-        todo!();
+        let child = domain.borrow().data.capabilities.get(&capa)?.as_domain()?;
+        // We can only switch to a sealed domain.
+        if !child.borrow().data.is_sealed() {
+            return Err(CapaError::DomainUnsealed);
+        }
+        // The child takes over the cores it can run on that are free or held by the caller.
+        let cores = child.borrow().data.policies.cores;
+        for (core, scheduled) in self.scheduled.iter_mut().enumerate() {
+            if !is_core_subset(cores, 1 << core) {
+                continue;
+            }
+            let free = scheduled.upgrade().is_none();
+            if free || Weak::ptr_eq(scheduled, &Rc::downgrade(&domain)) {
+                *scheduled = Rc::downgrade(&child);
+            }
+        }
+        let id = domain.borrow().data.id;
+        self.record(CallInterface::SWITCH, id, [capa, 0, 0, 0, 0, 0], None);
+        Ok(())
     }
 
     fn alias(
@@ -217,17 +1850,7 @@ impl EngineInterface for Engine {
         capa: LocalCapa,
         access: &Access,
     ) -> Result<LocalCapa, CapaError> {
-        self.is_sealed_and_allowed(&domain, MonitorAPI::ALIAS)?;
-
-        let dom = &mut domain.borrow_mut();
-        let region = dom.data.capabilities.get(&capa)?.as_region()?;
-        let aliased = region.borrow_mut().alias(access)?;
-        let aliased_capa = dom.data.install(CapaWrapper::Region(aliased.clone()));
-
-        // Tree & ownership logic.
-        aliased.borrow_mut().parent = Rc::downgrade(&region);
-        aliased.borrow_mut().owned = Ownership::new(Rc::downgrade(&domain), aliased_capa);
-        Ok(aliased_capa)
+        self.alias_with(domain, capa, access, false)
     }
 
     fn carve(
@@ -236,34 +1859,7 @@ impl EngineInterface for Engine {
         capa: LocalCapa,
         access: &Access,
     ) -> Result<LocalCapa, CapaError> {
-        self.is_sealed_and_allowed(&domain, MonitorAPI::CARVE)?;
-
-        let mut updates = OperationUpdate::new();
-
-        let region = {
-            let dom = &domain.borrow();
-            dom.data.capabilities.get(&capa)?.as_region()?
-        };
-
-        // Carve can require updates if we reduce access rights.
-        if region.borrow().data.access.rights != access.rights {
-            updates.add(Update::ChangeMemory {
-                dom: Rc::downgrade(&domain.clone()),
-            });
-        }
-        updates.snapshot()?;
-        //TODO: notify all cores
-
-        let dom = &mut domain.borrow_mut();
-        let carved = region.borrow_mut().carve(access)?;
-        let carved_capa = dom.data.install(CapaWrapper::Region(carved.clone()));
-
-        // Tree & ownership logic.
-        carved.borrow_mut().parent = Rc::downgrade(&region);
-        carved.borrow_mut().owned = Ownership::new(Rc::downgrade(&domain), carved_capa);
-
-        updates.compute()?;
-        Ok(carved_capa)
+        self.carve_with(domain, capa, access, false)
     }
 
     fn revoke(
@@ -274,41 +1870,22 @@ impl EngineInterface for Engine {
     ) -> Result<(), CapaError> {
         self.is_sealed_and_allowed(&domain, MonitorAPI::REVOKE)?;
 
+        // The handle must resolve to something the caller actually owns.
+        let owner = match domain.borrow().data.capabilities.get(&capa)? {
+            CapaWrapper::Region(r) => r.borrow().owned.owner.upgrade(),
+            CapaWrapper::Domain(d) => d.borrow().owned.owner.upgrade(),
+        };
+        if !owner.is_some_and(|o| same_capa(&o, &domain)) {
+            return Err(CapaError::CapaNotOwned);
+        }
+
         let is_domain = {
             let dom = &mut domain.borrow_mut();
             dom.data.is_domain(capa)?
         };
         // Match directly on the wrapper while we hold the borrow
         if is_domain {
-            // Prepare the update.
-            let mut update = OperationUpdate::new();
-            {
-                let dom = domain.borrow();
-                let d = dom.data.capabilities.get(&capa)?.as_domain()?;
-                dom.on_revoke_child(&d, &mut update)?;
-            }
-            update.snapshot()?;
-            //TODO: notify all then we process the revoke.
-
-            let dom = &mut domain.borrow_mut();
-            let d = dom.data.capabilities.get(&capa)?.as_domain()?;
-
-            // Mark the domain as being revoked.
-            d.borrow_mut().data.status = Status::Revoked;
-            dom.revoke_child(&d, &mut |c: &mut Capability<Domain>| {
-                c.data.status = Status::Revoked;
-                c.data
-                    .capabilities
-                    .foreach_region_mut(|c: &CapaRef<MemoryRegion>| {
-                        Capability::<MemoryRegion>::revoke_node(c.clone(), &mut |_c| Ok(()))
-                    })?;
-                c.data.capabilities.reset();
-                update.compute()?;
-                //TODO: notify
-                Ok(())
-            })?;
-            // Remove the handle
-            dom.data.capabilities.remove(&capa)?;
+            self.revoke_child_domains(&domain, &[capa])?;
         } else {
             let r = domain
                 .borrow_mut()
@@ -330,6 +1907,7 @@ impl EngineInterface for Engine {
             let mut updates = OperationUpdate::new();
             child.borrow().on_revoke(&mut updates)?;
             updates.snapshot()?;
+            self.check_update_room(&updates)?;
 
             // Now we should know all the affected domains.
             // TODO: preempt and process them.
@@ -338,10 +1916,15 @@ impl EngineInterface for Engine {
             // The region might belong to the dom, so we need to drop the domain.
             r.borrow_mut()
                 .revoke_child(&child, &mut |a| Self::revoke_region_handler(a))?;
-            updates.compute()?
+            Self::revoke_vital_holders(&mut updates)?;
+            updates.compute()?;
             // TODO Will need to notify
+            self.push_updates(&mut updates);
         }
 
+        debug_assert_eq!(self.validate_tree(), Ok(()));
+        let id = domain.borrow().data.id;
+        self.record(CallInterface::REVOKE, id, [capa, child, 0, 0, 0, 0], None);
         Ok(())
     }
 
@@ -353,46 +1936,35 @@ impl EngineInterface for Engine {
         remap: Remapped,
         attributes: Attributes,
     ) -> Result<(), CapaError> {
-        self.is_sealed_and_allowed(&domain, MonitorAPI::SEND)?;
-
-        // Perform all the checks to ensure the operation is allowed.
+        self.can_send(domain.clone(), dest, capa, remap, attributes)?;
+        let (remapped, gva) = match remap {
+            Remapped::Identity => (0, 0),
+            Remapped::Remapped(x) => (1, x),
+        };
+        let args = [dest, capa, remapped, gva, attributes.bits() as u64, 0];
         let dest = { domain.borrow().data.capabilities.get(&dest)?.as_domain()? };
 
-        if dest.borrow().data.is_sealed()
-            && (!dest.borrow().data.operation_allowed(MonitorAPI::RECEIVE)
-                || !attributes.is_empty())
-        {
-            return Err(CapaError::CallNotAllowed);
-        }
-
-        // Check the attributes for the owner and conflicts in the dest.
-        {
-            let region = domain.borrow().data.capabilities.get(&capa)?.as_region()?;
-            // Check attributes.
-            if region
-                .borrow()
-                .data
-                .attributes
-                .intersects(Attributes::VITAL | Attributes::CLEAN)
-            {
-                return Err(CapaError::InvalidAttributes);
-            }
-            // Check conflicts.
-            dest.borrow()
-                .check_conflict(&ViewRegion::new(region.borrow().data.access, remap))?;
-        }
-
         // Compute the updates, only trigger one if the dest is sealed.
         let mut updates = OperationUpdate::new();
+        let sent = {
+            let region = domain.borrow().data.capabilities.get(&capa)?.as_region()?;
+            let access = region.borrow().data.access;
+            access
+        };
         updates.add(Update::ChangeMemory {
             dom: Rc::downgrade(&domain.clone()),
+            range: sent,
+            rights: sent.rights,
         });
         if dest.borrow().data.is_sealed() {
             updates.add(Update::ChangeMemory {
                 dom: Rc::downgrade(&dest.clone()),
+                range: sent,
+                rights: Rights::empty(),
             });
         }
         updates.snapshot()?;
+        self.check_update_room(&updates)?;
 
         // Now effect the send.
         let dom = &mut domain.borrow_mut();
@@ -408,11 +1980,13 @@ impl EngineInterface for Engine {
         let dest_capa = dest
             .borrow_mut()
             .data
-            .install(CapaWrapper::Region(region.clone()));
+            .install(CapaWrapper::Region(region.clone()))?;
         region.borrow_mut().owned = Ownership::new(Rc::downgrade(&dest), dest_capa);
 
         // Apply the updates.
         updates.compute()?;
+        self.push_updates(&mut updates);
+        self.record(CallInterface::SEND, dom.data.id, args, Some(dest_capa));
         Ok(())
     }
 }
@@ -1,4 +1,4 @@
-use capa_engine::core::coalesced::CoalescedView;
+use capa_engine::core::coalesced::{diff_views, CoalescedView, ViewDelta};
 use capa_engine::core::memory_region::{Access, Remapped, Rights, ViewRegion};
 
 // ———————————————————————————————— Helpers ————————————————————————————————— //
@@ -58,3 +58,77 @@ fn test_coalesce() {
     ];
     assert_view_display_eq(&view, &expected)
 }
+
+#[test]
+fn test_diff_views_rights_downgrade() {
+    let old = vec![
+        ViewRegion::new(Access::new(0x0, 0x1000, Rights::all()), Remapped::Identity),
+        ViewRegion::new(
+            Access::new(0x1000, 0x2000, Rights::READ | Rights::WRITE),
+            Remapped::Identity,
+        ),
+    ];
+    let new = vec![
+        ViewRegion::new(Access::new(0x0, 0x1000, Rights::all()), Remapped::Identity),
+        ViewRegion::new(
+            Access::new(0x1000, 0x2000, Rights::READ),
+            Remapped::Identity,
+        ),
+    ];
+    assert_eq!(diff_views(&old, &old), vec![]);
+    assert_eq!(
+        diff_views(&old, &new),
+        vec![ViewDelta::RightsChanged {
+            range: new[1],
+            from: Rights::READ | Rights::WRITE,
+            to: Rights::READ,
+        }]
+    );
+}
+
+#[test]
+fn test_diff_views_remap_change() {
+    let old = vec![ViewRegion::new(
+        Access::new(0x1000, 0x3000, Rights::READ),
+        Remapped::Remapped(0x10000),
+    )];
+    // Only the tail of the region moves.
+    let new = vec![
+        ViewRegion::new(
+            Access::new(0x1000, 0x1000, Rights::READ),
+            Remapped::Remapped(0x10000),
+        ),
+        ViewRegion::new(
+            Access::new(0x2000, 0x2000, Rights::READ),
+            Remapped::Remapped(0x20000),
+        ),
+    ];
+    assert_eq!(
+        diff_views(&old, &new),
+        vec![
+            ViewDelta::Removed(ViewRegion::new(
+                Access::new(0x2000, 0x2000, Rights::READ),
+                Remapped::Remapped(0x11000),
+            )),
+            ViewDelta::Added(new[1]),
+        ]
+    );
+}
+
+#[test]
+fn test_coalesce_keeps_differing_rights() {
+    let rw = ViewRegion::new(
+        Access::new(0x0, 0x1000, Rights::READ | Rights::WRITE),
+        Remapped::Identity,
+    );
+    let rx = ViewRegion::new(
+        Access::new(0x1000, 0x1000, Rights::READ | Rights::EXECUTE),
+        Remapped::Identity,
+    );
+    assert!(rw.contiguous(&rx));
+    assert!(!rw.can_merge(&rx));
+
+    let coal = CoalescedView::from_regions(vec![rx, rw]).unwrap();
+    assert_eq!(coal.regions, vec![rw, rx]);
+    assert_eq!(coal.regions[0].rights(), Rights::READ | Rights::WRITE);
+}
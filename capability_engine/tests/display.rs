@@ -1,4 +1,5 @@
 use capa_engine::core::capability::*;
+use capa_engine::core::domain::VectorVisibility;
 use capa_engine::core::memory_region::*;
 
 fn create_root() -> Capability<MemoryRegion> {
@@ -91,14 +92,14 @@ fn test_display_with_nested_children() {
     );
     let a1 = root.alias(&alias_access).expect("Error");
 
-    let carve_access = Access::new(0x3000, 0x1000, Rights::READ | Rights::WRITE);
-    let c1 = a1.borrow_mut().carve(&carve_access).expect("Error");
+    let nested_access = Access::new(0x3000, 0x1000, Rights::READ | Rights::WRITE);
+    let a2 = a1.borrow_mut().alias(&nested_access).expect("Error");
 
-    // Get the display output of the root capability with its alias and nested carve
+    // Get the display output of the root capability with its alias and nested alias
     let display_output = format!("{}", root);
 
     let expected_output = r#"Exclusive 0x0 0x10000 with RWX mapped Identity
-| Alias at 0x2000 0x4000 with RWX for .0"#; // Nested carve should also be .0
+| Alias at 0x2000 0x4000 with RWX for .0"#; // Nested alias should also be .0
 
     // Check if the display output contains the root and nested child
     assert_eq!(display_output, expected_output);
@@ -106,11 +107,11 @@ fn test_display_with_nested_children() {
     // Check the alias.
     let display_output = format!("{}", a1.borrow());
     let expected_output = r#"Aliased 0x2000 0x4000 with RWX mapped Identity
-| Carve at 0x3000 0x4000 with RW_ for .0"#;
+| Alias at 0x3000 0x4000 with RW_ for .0"#;
     assert_eq!(display_output, expected_output);
 
-    // Check the carve.
-    let display_output = format!("{}", c1.borrow());
+    // Check the nested alias.
+    let display_output = format!("{}", a2.borrow());
     let expected_output = "Aliased 0x3000 0x4000 with RW_ mapped Identity";
     assert_eq!(display_output, expected_output);
 }
@@ -250,3 +251,29 @@ fn test_view_with_alias() {
     let views = capa.view();
     assert_view_display_eq(&views, &vec!["0x1000 0x2000 with RW_ mapped Identity"]);
 }
+
+#[test]
+fn test_debug_access_and_rights() {
+    let access = Access::new(0, 0x1000, Rights::READ);
+    assert_eq!(format!("{:?}", access), "0x0..0x1000 R__");
+    assert_eq!(format!("{:?}", Rights::all()), format!("{}", Rights::all()));
+    assert_eq!(format!("{:?}", Rights::empty()), "___");
+}
+
+#[test]
+fn test_vector_visibility_describe() {
+    let expected = ["NOT REPORTED", "ALLOWED", "VISIBLE", "ALLOWED|VISIBLE"];
+    for (bits, name) in expected.iter().enumerate() {
+        let visibility = VectorVisibility::from_bits(bits as u8).unwrap();
+        assert_eq!(visibility.describe(), *name);
+        assert_eq!(format!("{}", visibility), *name);
+    }
+    assert!(VectorVisibility::from_bits(0b100).is_none());
+}
+
+#[test]
+fn test_display_access_alternate() {
+    let access = Access::new(0x1000, 0x2000, Rights::READ | Rights::WRITE);
+    assert_eq!(format!("{}", access), "0x1000 0x3000 with RW_");
+    assert_eq!(format!("{:#}", access), "0x1000 0x2000 RW_");
+}
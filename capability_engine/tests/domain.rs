@@ -6,6 +6,7 @@ use capa_engine::core::memory_region::{
 use capa_engine::server::engine::Engine;
 use capa_engine::EngineInterface;
 use std::cell::RefCell;
+use std::ops::ControlFlow;
 use std::rc::Rc;
 
 fn create_root_domain() -> Capability<Domain> {
@@ -64,7 +65,7 @@ fn test_root_domain_with_root_memory() {
     let mut domain = create_root_domain();
     let region = create_root();
     let reference = Rc::new(RefCell::new(region));
-    domain.data.install(CapaWrapper::Region(reference));
+    domain.data.install(CapaWrapper::Region(reference)).unwrap();
 
     let display_output = format!("{}", domain);
     let expected_output = format!("td0 = Sealed domain(r0)\n|cores: 0x3f\n|mon.api: 0x1fff\n|vec0-255: ALLOWED|VISIBLE, r: 0x0, w: 0x0\nr0 = Exclusive 0x0 0x10000 with RWX mapped Identity\n|indices: 1->r0\n");
@@ -381,3 +382,238 @@ fn test_set_get() {
     engine.seal(td0.clone(), child_td).unwrap();
     engine.revoke(td0.clone(), child_td, 0).unwrap();
 }
+
+#[test]
+fn test_interrupt_policy_builder() {
+    let policy = InterruptPolicyBuilder::new()
+        .allowed((32, 48))
+        .visible((32, 48))
+        .read((32, 48), 0xff)
+        .build()
+        .unwrap();
+
+    let mut expected = InterruptPolicy::default_none();
+    for i in 32..48 {
+        expected.vectors[i] = VectorPolicy {
+            visibility: VectorVisibility::ALLOWED | VectorVisibility::VISIBLE,
+            read_set: 0xff,
+            write_set: !0,
+        };
+    }
+    assert!(policy.vectors == expected.vectors);
+
+    // Out of range vectors are rejected.
+    let err = InterruptPolicyBuilder::new()
+        .visible((0, 16))
+        .write((250, NB_INTERRUPTS + 1), 0)
+        .build();
+    assert_eq!(err.err(), Some(CapaError::InvalidField));
+}
+
+#[test]
+fn test_capability_store_max_handles() {
+    let mut store = CapabilityStore::new(4);
+    let install = |store: &mut CapabilityStore| {
+        store.install_capability(CapaWrapper::Region(Rc::new(RefCell::new(create_root()))))
+    };
+    for i in 1..=4 {
+        assert_eq!(install(&mut store), Ok(i));
+    }
+    assert_eq!(install(&mut store), Err(CapaError::TooManyCapabilities));
+
+    // Removing one frees a slot and its handle is recycled.
+    store.remove(&2).unwrap();
+    assert_eq!(install(&mut store), Ok(2));
+    assert_eq!(install(&mut store), Err(CapaError::TooManyCapabilities));
+}
+
+#[test]
+fn test_foreach_region_mut_short_circuit() {
+    let mut store = CapabilityStore::new(MAX_HANDLES);
+    for i in 0..5 {
+        let mut region = create_root();
+        region.data.access = Access::new(0x1000 * i, 0x1000, Rights::READ);
+        store
+            .install_capability(CapaWrapper::Region(Rc::new(RefCell::new(region))))
+            .unwrap();
+    }
+
+    // Count regions until we find the one at 0x2000.
+    let mut visited = 0;
+    store
+        .foreach_region_mut(|r| {
+            visited += 1;
+            if r.borrow().data.access.start == 0x2000 {
+                return Ok(ControlFlow::Break(()));
+            }
+            Ok(ControlFlow::Continue(()))
+        })
+        .unwrap();
+    assert_eq!(visited, 3);
+}
+
+#[test]
+fn test_policies_intersection_union() {
+    let all = Policies::new(0xff, MonitorAPI::all(), InterruptPolicy::default_all());
+    let restricted = Policies::new(
+        0b101,
+        MonitorAPI::SEND | MonitorAPI::CARVE,
+        InterruptPolicyBuilder::new()
+            .visible((0, 32))
+            .read((0, 16), 0xf0)
+            .write((8, 24), 0x1)
+            .build()
+            .unwrap(),
+    );
+
+    assert_eq!(all.intersection(&restricted), restricted);
+    assert_eq!(restricted.intersection(&all), restricted);
+    assert_eq!(all.union(&restricted), all);
+    assert!(all.contains(&all.intersection(&restricted)));
+
+    let none = Policies::new(0, MonitorAPI::empty(), InterruptPolicy::default_none());
+    assert_eq!(restricted.intersection(&none), none);
+    assert_eq!(restricted.union(&none), restricted);
+}
+
+#[test]
+fn test_indices_sorted_by_handle() {
+    let mut domain = create_root_domain();
+    // Install out of order, the store keeps them sorted by handle.
+    for (handle, start) in [(7, 0x0), (2, 0x10000), (5, 0x20000), (3, 0x30000)] {
+        let mut region = create_root();
+        region.data.access.start = start;
+        domain
+            .data
+            .capabilities
+            .install_capabilitiy_at(CapaWrapper::Region(Rc::new(RefCell::new(region))), handle);
+    }
+
+    let indices = |d: &Capability<Domain>| -> Vec<u64> {
+        let display = format!("{}", d);
+        let line = display
+            .lines()
+            .find(|l| l.starts_with("|indices:"))
+            .unwrap()
+            .to_string();
+        line.trim_start_matches("|indices:")
+            .split_whitespace()
+            .map(|e| e.split("->").next().unwrap().parse().unwrap())
+            .collect()
+    };
+    let first = indices(&domain);
+    assert_eq!(first, vec![2, 3, 5, 7]);
+    for _ in 0..10 {
+        assert_eq!(indices(&domain), first);
+    }
+}
+
+#[test]
+fn test_capability_store_monotonic() {
+    let mut store = CapabilityStore::new_monotonic(4);
+    let install = |store: &mut CapabilityStore| {
+        store.install_capability(CapaWrapper::Region(Rc::new(RefCell::new(create_root()))))
+    };
+    assert_eq!(install(&mut store), Ok(1));
+    assert_eq!(install(&mut store), Ok(2));
+
+    // The freed handle is not handed out again.
+    store.remove(&2).unwrap();
+    assert_eq!(install(&mut store), Ok(3));
+    assert_eq!(store.get(&2).err(), Some(CapaError::InvalidLocalCapa));
+}
+
+#[test]
+fn test_capability_store_recycled_handle() {
+    let mut store = CapabilityStore::new(MAX_HANDLES);
+    let old = Rc::new(RefCell::new(create_root()));
+    let new = Rc::new(RefCell::new(create_root()));
+    let handle = store
+        .install_capability(CapaWrapper::Region(old.clone()))
+        .unwrap();
+    assert!(store.contains(&handle));
+
+    store.remove(&handle).unwrap();
+    assert!(!store.contains(&handle));
+    // Removing twice is rejected.
+    assert_eq!(
+        store.remove(&handle).err(),
+        Some(CapaError::InvalidLocalCapa)
+    );
+
+    // The handle is recycled, the stale copy now designates the new object.
+    let recycled = store
+        .install_capability(CapaWrapper::Region(new.clone()))
+        .unwrap();
+    assert_eq!(recycled, handle);
+    let current = store.get(&handle).unwrap().as_region().unwrap();
+    assert!(same_capa(&current, &new));
+    assert!(!same_capa(&current, &old));
+}
+
+#[test]
+fn test_interrupt_policy_diff() {
+    let base = InterruptPolicy::default_none();
+    let mut policy = InterruptPolicy::default_none();
+    policy.vectors[3].visibility = VectorVisibility::VISIBLE;
+    policy.vectors[200] = InterruptPolicy::default_all().vectors[200];
+
+    let diff = policy.diff(&base);
+    assert_eq!(diff.len(), 2);
+    assert_eq!(diff[0], (3, policy.vectors[3]));
+    assert_eq!(diff[1], (200, policy.vectors[200]));
+    assert!(base.diff(&base).is_empty());
+
+    let mut rebuilt = base;
+    rebuilt.apply_diff(&diff).unwrap();
+    assert_eq!(rebuilt, policy);
+    assert_eq!(
+        rebuilt.apply_diff(&[(NB_INTERRUPTS, policy.vectors[3])]),
+        Err(CapaError::InvalidField)
+    );
+}
+
+#[test]
+fn test_interrupt_policy_custom_size() {
+    let mut policy = SizedInterruptPolicy::<64>::default_none();
+    policy
+        .set_range(8, 8, SizedInterruptPolicy::<64>::default_all().vectors[0])
+        .unwrap();
+    assert_eq!(
+        policy.set_range(60, 8, policy.vectors[0]),
+        Err(CapaError::InvalidField)
+    );
+
+    let display = format!("{}", policy);
+    assert_eq!(
+        display,
+        "|vec0-7: NOT REPORTED, r: 0xffffffffffffffff, w: 0xffffffffffffffff
+|vec8-15: ALLOWED|VISIBLE, r: 0x0, w: 0x0
+|vec16-63: NOT REPORTED, r: 0xffffffffffffffff, w: 0xffffffffffffffff
+"
+    );
+    assert_eq!(SizedInterruptPolicy::<64>::parse(&display), Ok(policy));
+    // Vectors beyond the size are rejected.
+    assert!(SizedInterruptPolicy::<64>::parse("|vec64: ALLOWED, r: 0x0, w: 0x0").is_err());
+    // The default is unchanged.
+    let default = InterruptPolicy::default_none();
+    assert_eq!(default.vectors.len(), NB_INTERRUPTS);
+}
+
+#[test]
+fn test_capa_wrapper_kind() {
+    let region = Rc::new(RefCell::new(create_root()));
+    let domain = Rc::new(RefCell::new(create_root_domain()));
+    let wrappers = [
+        CapaWrapper::Region(region.clone()),
+        CapaWrapper::Domain(domain.clone()),
+    ];
+    assert_eq!(Rc::strong_count(&region), 2);
+    assert_eq!(Rc::strong_count(&domain), 2);
+
+    assert_eq!(wrappers[0].kind(), CapaKind::Region);
+    assert_eq!(wrappers[1].kind(), CapaKind::Domain);
+    // The inner references were not cloned.
+    assert_eq!(Rc::strong_count(&region), 2);
+    assert_eq!(Rc::strong_count(&domain), 2);
+}
@@ -1,11 +1,14 @@
+use capa_engine::client::engine::ClientResult;
 use capa_engine::core::capability::*;
 use capa_engine::core::domain::*;
 use capa_engine::core::memory_region::{
-    Access, Attributes, MemoryRegion, RegionKind, Remapped, Rights, Status as MStatus,
+    Access, Attributes, MemoryRegion, RegionKind, Remapped, Rights, Status as MStatus, ViewRegion,
 };
-use capa_engine::server::engine::Engine;
-use capa_engine::EngineInterface;
+use capa_engine::core::update::{OperationUpdate, Update};
+use capa_engine::server::engine::{AuditEntry, Engine, UpdateQueueMode, PAGE_SIZE};
+use capa_engine::{CallInterface, EngineInterface};
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::rc::Rc;
 
 fn create_root_region() -> Capability<MemoryRegion> {
@@ -35,6 +38,34 @@ fn setup_engine_with_root() -> (
     (engine, ref_td, ref_mem, ref_region)
 }
 
+// Returns a handle in td0 that resolves to td2, a domain owned by td1.
+fn setup_foreign_child() -> (Engine, CapaRef<Domain>, LocalCapa, CapaRef<Domain>) {
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+    let (td0_td1, td1) = engine
+        .create_ref(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    let (_, td2) = engine
+        .create_ref(
+            &td1.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    let foreign = td0
+        .borrow_mut()
+        .data
+        .install(CapaWrapper::Domain(td2.clone()))
+        .unwrap();
+    (engine, td0, foreign, td2)
+}
+
 #[test]
 fn test_engine_create_root_and_simple_child() {
     // Initial setup
@@ -154,7 +185,8 @@ r1 = Exclusive 0x2000 0x4000 with RWX mapped Identity
         let ref_phantom = child
             .borrow_mut()
             .data
-            .install(CapaWrapper::Region(phantom));
+            .install(CapaWrapper::Region(phantom))
+            .unwrap();
 
         // Now do the attestation again, we should see a region that is not reported.
         let display = format!("{}", ref_td.borrow());
@@ -1063,18 +1095,15 @@ fn test_engine_policies_core_fail() {
 
         assert!(engine.seal(td1.clone(), td1_td2).is_err());
 
-        // Now let's try the wrong api policies.
+        // Now let's try the wrong api policies, rejected at creation.
         engine.revoke(td1.clone(), td1_td2, 0).unwrap();
-        let td1_td2 = engine
-            .create(
-                &td1.clone(),
-                0b1,
-                MonitorAPI::all(),
-                InterruptPolicy::default_none(),
-            )
-            .unwrap();
-
-        assert!(engine.seal(td1.clone(), td1_td2).is_err());
+        let td1_td2 = engine.create(
+            &td1.clone(),
+            0b1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        );
+        assert_eq!(td1_td2, Err(CapaError::InsufficientRights));
     }
 }
 
@@ -1139,3 +1168,3323 @@ r2 = Exclusive 0x0 0x1000 with RWX mapped Identity
 "#;
     assert_eq!(display, expected);
 }
+
+#[test]
+fn test_engine_policies_read_set_fail() {
+    // Initial setup
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0b1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    let td1 = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_td1)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+
+    // The child requests access to more registers than td1 on vector 0.
+    let mut interrupts = InterruptPolicy::default_none();
+    interrupts.vectors[0].read_set = 0xff;
+    let td1_td2 = engine
+        .create(&td1.clone(), 0b1, MonitorAPI::all(), interrupts)
+        .unwrap();
+    assert_eq!(
+        engine.seal(td1.clone(), td1_td2),
+        Err(CapaError::InsufficientRights)
+    );
+
+    // Same for the write set.
+    engine.revoke(td1.clone(), td1_td2, 0).unwrap();
+    let mut interrupts = InterruptPolicy::default_none();
+    interrupts.vectors[0].write_set = 0xff;
+    let td1_td2 = engine
+        .create(&td1.clone(), 0b1, MonitorAPI::all(), interrupts)
+        .unwrap();
+    assert_eq!(
+        engine.seal(td1.clone(), td1_td2),
+        Err(CapaError::InsufficientRights)
+    );
+
+    // Restricting the same registers as the parent is fine.
+    engine.revoke(td1.clone(), td1_td2, 0).unwrap();
+    let td1_td2 = engine
+        .create(
+            &td1.clone(),
+            0b1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    engine.seal(td1.clone(), td1_td2).unwrap();
+}
+
+#[test]
+fn test_engine_duplicate_alias() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+
+    let access = Access::new(0x1000, 0x1000, Rights::READ | Rights::WRITE);
+    let alias = engine.alias(td0.clone(), td0_r0, &access).unwrap();
+    let duplicate = engine.duplicate(td0.clone(), alias).unwrap();
+    assert_ne!(alias, duplicate);
+
+    let display = format!("{}", td0.borrow());
+    let expected = r#"td0 = Sealed domain(r0,r1,r2)
+|cores: 0xffff
+|mon.api: 0x1fff
+|vec0-255: ALLOWED|VISIBLE, r: 0x0, w: 0x0
+r0 = Exclusive 0x0 0x10000 with RWX mapped Identity
+| Alias at 0x1000 0x2000 with RW_ for r1
+| Alias at 0x1000 0x2000 with RW_ for r2
+r1 = Aliased 0x1000 0x2000 with RW_ mapped Identity
+r2 = Aliased 0x1000 0x2000 with RW_ mapped Identity
+|indices: 1->r0 2->r1 3->r2
+"#;
+    assert_eq!(display, expected);
+
+    // Carves are exclusive and cannot be duplicated.
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x4000, 0x1000, Rights::all()),
+        )
+        .unwrap();
+    assert_eq!(
+        engine.duplicate(td0.clone(), carve),
+        Err(CapaError::InvalidAccess)
+    );
+    assert_eq!(
+        engine.duplicate(td0.clone(), td0_r0),
+        Err(CapaError::InvalidAccess)
+    );
+}
+
+#[test]
+fn test_engine_find_domain_by_id() {
+    // Initial setup
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0b1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    let td1 = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_td1)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    let td1_td2 = engine
+        .create(
+            &td1.clone(),
+            0b1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    let td2 = td1
+        .borrow()
+        .data
+        .capabilities
+        .get(&td1_td2)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    let id = td2.borrow().data.id;
+
+    let found = engine.find_domain_by_id(id).unwrap();
+    assert!(Rc::ptr_eq(&found, &td2));
+    let root_id = td0.borrow().data.id;
+    assert!(Rc::ptr_eq(
+        &engine.find_domain_by_id(root_id).unwrap(),
+        &td0
+    ));
+    assert!(engine.find_domain_by_id(u64::MAX).is_none());
+}
+
+#[test]
+fn test_engine_seal_no_cores() {
+    // Initial setup
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    assert_eq!(
+        engine.seal(td0.clone(), td0_td1),
+        Err(CapaError::NoCoresAssigned)
+    );
+
+    // Fixing the cores allows the seal.
+    engine
+        .set(td0.clone(), td0_td1, 0, FieldType::Cores, 0, 0b1)
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+}
+
+#[test]
+fn test_engine_indices() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+
+    let access = Access::new(0x0, 0x1000, Rights::all());
+    let alias1 = engine.alias(td0.clone(), td0_r0, &access).unwrap();
+    let alias2 = engine.alias(td0.clone(), td0_r0, &access).unwrap();
+    let child = engine
+        .create(
+            &td0.clone(),
+            0b1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+
+    let mut expected = BTreeMap::new();
+    expected.insert(td0_r0, CapaKind::Region);
+    expected.insert(alias1, CapaKind::Region);
+    expected.insert(alias2, CapaKind::Region);
+    expected.insert(child, CapaKind::Domain);
+    assert_eq!(td0.borrow().indices(), expected);
+    assert_eq!(
+        td0.borrow().indices().into_keys().collect::<Vec<_>>(),
+        vec![1, 2, 3, 4]
+    );
+}
+
+#[test]
+fn test_engine_active_view() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+
+    // Nothing is scheduled yet.
+    assert_eq!(engine.active_view(1), Ok(Vec::new()));
+    assert_eq!(engine.active_view(16), Err(CapaError::InvalidField));
+
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0b10,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x1000, 0x2000, Rights::READ | Rights::WRITE),
+        )
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            td0_td1,
+            carve,
+            Remapped::Identity,
+            Attributes::empty(),
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    engine.switch(td0.clone(), td0_td1).unwrap();
+
+    let td1 = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_td1)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    let expected = td1.borrow().view().unwrap();
+    assert_eq!(
+        expected,
+        vec![ViewRegion::new(
+            Access::new(0x1000, 0x2000, Rights::READ | Rights::WRITE),
+            Remapped::Identity,
+        )]
+    );
+    assert_eq!(engine.active_view(1), Ok(expected));
+    // The child cannot run on core 0.
+    assert_eq!(engine.active_view(0), Ok(Vec::new()));
+}
+
+#[test]
+fn test_engine_revoke_all() {
+    // Initial setup
+    let (mut engine, td0, r0, td0_r0) = setup_engine_with_root();
+    let baseline = format!("{}", td0.borrow());
+
+    // Create three children, each with its own region.
+    for i in 0..3 {
+        let child = engine
+            .create(
+                &td0.clone(),
+                0x1,
+                MonitorAPI::all(),
+                InterruptPolicy::default_all(),
+            )
+            .unwrap();
+        let carve = engine
+            .carve(
+                td0.clone(),
+                td0_r0,
+                &Access::new(0x1000 * i, 0x1000, Rights::all()),
+            )
+            .unwrap();
+        engine
+            .send(
+                td0.clone(),
+                child,
+                carve,
+                Remapped::Identity,
+                Attributes::empty(),
+            )
+            .unwrap();
+        engine.seal(td0.clone(), child).unwrap();
+    }
+    assert_ne!(format!("{}", td0.borrow()), baseline);
+
+    // Revoke everything in one shot.
+    engine.revoke_all(td0.clone()).unwrap();
+    assert_eq!(format!("{}", td0.borrow()), baseline);
+
+    // Nothing left to revoke.
+    engine.revoke_all(td0.clone()).unwrap();
+    assert_eq!(format!("{}", td0.borrow()), baseline);
+
+    assert_eq!(Rc::strong_count(&td0), 2);
+    assert_eq!(Rc::weak_count(&td0), 1);
+    assert_eq!(Rc::strong_count(&r0), 2);
+}
+
+#[test]
+fn test_engine_send_clean_to_sealed() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x2000, 0x1000, Rights::READ | Rights::WRITE),
+        )
+        .unwrap();
+    let vital = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x4000, 0x1000, Rights::READ | Rights::WRITE),
+        )
+        .unwrap();
+
+    // Vital is still rejected for a sealed domain.
+    assert_eq!(
+        engine.send(
+            td0.clone(),
+            td0_td1,
+            vital,
+            Remapped::Identity,
+            Attributes::VITAL,
+        ),
+        Err(CapaError::CallNotAllowed)
+    );
+    engine
+        .send(
+            td0.clone(),
+            td0_td1,
+            carve,
+            Remapped::Identity,
+            Attributes::CLEAN,
+        )
+        .unwrap();
+
+    // Revoking the region must clean it.
+    let region = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_r0)
+        .unwrap()
+        .as_region()
+        .unwrap();
+    let sent = region.borrow().children[0].clone();
+    assert_eq!(sent.borrow().data.attributes, Attributes::CLEAN);
+    let mut updates = OperationUpdate::new();
+    sent.borrow().on_revoke(&mut updates).unwrap();
+    assert_eq!(updates.to_clean.len(), 1);
+    match updates.to_clean[0] {
+        Update::Clean { start, size } => {
+            assert_eq!(start, 0x2000);
+            assert_eq!(size, 0x1000);
+        }
+        _ => panic!("Expected a clean update"),
+    }
+    engine.revoke(td0.clone(), td0_r0, 0).unwrap();
+}
+
+#[test]
+fn test_engine_for_each_region() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x0, 0x2000, Rights::all()),
+        )
+        .unwrap();
+    let _alias = engine
+        .alias(td0.clone(), carve, &Access::new(0x0, 0x1000, Rights::READ))
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            td0_td1,
+            carve,
+            Remapped::Identity,
+            Attributes::empty(),
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+
+    // The root region, the carve in td1, and the alias kept by td0.
+    let mut sizes = Vec::new();
+    engine.for_each_region(|r| sizes.push(r.borrow().data.access.size));
+    sizes.sort();
+    assert_eq!(sizes, vec![0x1000, 0x2000, 0x10000]);
+}
+
+#[test]
+fn test_engine_create_api_not_subset() {
+    // Initial setup
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+    let api_without_send = MonitorAPI::all() & !MonitorAPI::SEND;
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0b1,
+            api_without_send,
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    let td1 = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_td1)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+
+    // Asking for send fails right away.
+    assert_eq!(
+        engine.create(
+            &td1.clone(),
+            0b1,
+            MonitorAPI::CREATE | MonitorAPI::SEND,
+            InterruptPolicy::default_none(),
+        ),
+        Err(CapaError::InsufficientRights)
+    );
+    assert_eq!(td1.borrow().children.len(), 0);
+
+    // A subset is still fine.
+    assert!(engine
+        .create(
+            &td1.clone(),
+            0b1,
+            MonitorAPI::CREATE,
+            InterruptPolicy::default_none(),
+        )
+        .is_ok());
+}
+
+#[test]
+fn test_engine_attest_structured() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let mut children = Vec::new();
+    for i in 0..2 {
+        let child = engine
+            .create(
+                &td0.clone(),
+                0b11,
+                MonitorAPI::all(),
+                InterruptPolicy::default_none(),
+            )
+            .unwrap();
+        let carve = engine
+            .carve(
+                td0.clone(),
+                td0_r0,
+                &Access::new(0x1000 * (i + 1), 0x1000, Rights::READ),
+            )
+            .unwrap();
+        engine
+            .send(
+                td0.clone(),
+                child,
+                carve,
+                Remapped::Remapped(0x10000),
+                Attributes::empty(),
+            )
+            .unwrap();
+        engine.seal(td0.clone(), child).unwrap();
+        children.push(child);
+    }
+
+    let report = td0.borrow().attest_structured();
+    assert_eq!(report.domains.len(), 3);
+    assert_eq!(report.domains[0].policies.cores, 0xffff);
+    assert_eq!(report.domains[0].nb_regions, 1);
+    for d in &report.domains[1..] {
+        assert_eq!(d.status, Status::Sealed);
+        assert_eq!(d.policies.cores, 0b11);
+        assert_eq!(d.policies.api, MonitorAPI::all());
+        assert_eq!(d.policies.interrupts, InterruptPolicy::default_none());
+        assert_eq!(d.nb_regions, 1);
+    }
+
+    assert_eq!(report.regions.len(), 3);
+    assert_eq!(report.regions[0].owner, 0);
+    assert_eq!(report.regions[0].handle, td0_r0);
+    assert_eq!(
+        report.regions[0].access,
+        Access::new(0, 0x10000, Rights::all())
+    );
+    for (i, r) in report.regions[1..].iter().enumerate() {
+        assert_eq!(r.owner, i + 1);
+        assert_eq!(r.kind, RegionKind::Carve);
+        assert_eq!(r.status, MStatus::Exclusive);
+        assert_eq!(
+            r.access,
+            Access::new(0x1000 * (i as u64 + 1), 0x1000, Rights::READ)
+        );
+        assert_eq!(r.remapped, Remapped::Remapped(0x10000));
+        assert_eq!(r.attributes, Attributes::empty());
+    }
+
+    let mut expected = BTreeMap::new();
+    expected.insert(td0_r0, CapaKind::Region);
+    for c in children {
+        expected.insert(c, CapaKind::Domain);
+    }
+    assert_eq!(report.indices, expected);
+}
+
+#[test]
+fn test_engine_alias_over_carved_range() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+
+    engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x0, 0x1000, Rights::all()),
+        )
+        .unwrap();
+
+    // An alias cannot see memory that was carved away.
+    let err = engine.alias(
+        td0.clone(),
+        td0_r0,
+        &Access::new(0x0, 0x2000, Rights::all()),
+    );
+    assert_eq!(err, Err(CapaError::InvalidAccess));
+
+    // Next to the carve is fine.
+    assert!(engine
+        .alias(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x1000, 0x1000, Rights::all()),
+        )
+        .is_ok());
+}
+
+#[test]
+fn test_engine_enumerate_tree() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let first = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x1000, 0x1000, Rights::READ),
+        )
+        .unwrap();
+    engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x4000, 0x2000, Rights::READ | Rights::WRITE),
+        )
+        .unwrap();
+    engine
+        .alias(
+            td0.clone(),
+            first,
+            &Access::new(0x1000, 0x1000, Rights::READ),
+        )
+        .unwrap();
+
+    let tree = engine.enumerate_tree(td0.clone(), td0_r0).unwrap();
+    assert_eq!(tree.kind, RegionKind::Carve);
+    assert_eq!(tree.access, Access::new(0x0, 0x10000, Rights::all()));
+    assert_eq!(tree.remapped, Remapped::Identity);
+    assert_eq!(tree.children.len(), 2);
+    assert_eq!(
+        tree.children[0].access,
+        Access::new(0x1000, 0x1000, Rights::READ)
+    );
+    assert_eq!(
+        tree.children[1].access,
+        Access::new(0x4000, 0x2000, Rights::READ | Rights::WRITE)
+    );
+    assert!(tree.children[1].children.is_empty());
+
+    // The nested alias of the first carve.
+    let nested = &tree.children[0].children;
+    assert_eq!(nested.len(), 1);
+    assert_eq!(nested[0].kind, RegionKind::Alias);
+    assert!(nested[0].children.is_empty());
+
+    // Only regions can be enumerated as a tree.
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    assert_eq!(
+        engine.enumerate_tree(td0.clone(), td0_td1),
+        Err(CapaError::WrongCapaType)
+    );
+}
+
+#[test]
+fn test_engine_transfer_between_siblings() {
+    // Initial setup
+    let (mut engine, td0, r0, td0_r0) = setup_engine_with_root();
+    let mut children = Vec::new();
+    for _ in 0..2 {
+        children.push(
+            engine
+                .create(
+                    &td0.clone(),
+                    0x1,
+                    MonitorAPI::all(),
+                    InterruptPolicy::default_all(),
+                )
+                .unwrap(),
+        );
+    }
+    let (td0_td1, td0_td2) = (children[0], children[1]);
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x1000, 0x1000, Rights::READ | Rights::WRITE),
+        )
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            td0_td1,
+            carve,
+            Remapped::Identity,
+            Attributes::empty(),
+        )
+        .unwrap();
+
+    // Both must be sealed.
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    assert_eq!(
+        engine.transfer(td0.clone(), td0_td1, 1, td0_td2),
+        Err(CapaError::DomainUnsealed)
+    );
+    engine.seal(td0.clone(), td0_td2).unwrap();
+
+    let td2_capa = engine.transfer(td0.clone(), td0_td1, 1, td0_td2).unwrap();
+    assert_eq!(td2_capa, 1);
+
+    let attestation = engine.attest(td0.clone(), Some(td0_td1)).unwrap();
+    let expected = r#"td0 = Sealed domain()
+|cores: 0x1
+|mon.api: 0x1fff
+|vec0-255: ALLOWED|VISIBLE, r: 0x0, w: 0x0
+"#;
+    assert_eq!(attestation, expected);
+    let attestation = engine.attest(td0.clone(), Some(td0_td2)).unwrap();
+    let expected = r#"td0 = Sealed domain(r0)
+|cores: 0x1
+|mon.api: 0x1fff
+|vec0-255: ALLOWED|VISIBLE, r: 0x0, w: 0x0
+r0 = Exclusive 0x1000 0x2000 with RW_ mapped Identity
+|indices: 1->r0
+"#;
+    assert_eq!(attestation, expected);
+
+    // The tree is unchanged and the region is still a child of r0.
+    assert_eq!(r0.borrow().children.len(), 1);
+    let td2 = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_td2)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    assert!(Rc::ptr_eq(
+        &r0.borrow().children[0]
+            .borrow()
+            .owned
+            .owner
+            .upgrade()
+            .unwrap(),
+        &td2
+    ));
+}
+
+#[test]
+fn test_engine_audit() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+
+    // Nothing is recorded until enabled.
+    engine.attest(td0.clone(), None).unwrap();
+    assert!(engine.take_audit().is_empty());
+    engine.enable_audit();
+
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x1000, 0x1000, Rights::READ),
+        )
+        .unwrap();
+    // Failed calls are not recorded.
+    assert!(engine.seal(td0.clone(), 42).is_err());
+    engine
+        .send(
+            td0.clone(),
+            td0_td1,
+            carve,
+            Remapped::Remapped(0x8000),
+            Attributes::empty(),
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+
+    let audit = engine.take_audit();
+    let calls: Vec<CallInterface> = audit.iter().map(|e| e.call).collect();
+    assert_eq!(
+        calls,
+        vec![
+            CallInterface::CREATE,
+            CallInterface::CARVE,
+            CallInterface::SEND,
+            CallInterface::SEAL,
+        ]
+    );
+    let id = td0.borrow().data.id;
+    assert!(audit.iter().all(|e| e.domain == id));
+    assert_eq!(audit[0].handle, Some(td0_td1));
+    assert_eq!(audit[1].args, [td0_r0, 0x1000, 0x1000, 1, 0, 0]);
+    assert_eq!(audit[1].handle, Some(carve));
+    assert_eq!(audit[2].args, [td0_td1, carve, 1, 0x8000, 0, 0]);
+    assert_eq!(audit[3].handle, None);
+
+    // The log was drained.
+    assert!(engine.take_audit().is_empty());
+}
+
+#[test]
+fn test_engine_quota() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    assert_eq!(td0.borrow().data.max_footprint, None);
+
+    let td0_td1 = engine
+        .create_with_quota(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+            0x2000,
+        )
+        .unwrap();
+    let small = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x0, 0x1000, Rights::all()),
+        )
+        .unwrap();
+    let big = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x4000, 0x2000, Rights::all()),
+        )
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            td0_td1,
+            small,
+            Remapped::Identity,
+            Attributes::empty(),
+        )
+        .unwrap();
+    assert_eq!(
+        engine.send(
+            td0.clone(),
+            td0_td1,
+            big,
+            Remapped::Identity,
+            Attributes::empty(),
+        ),
+        Err(CapaError::QuotaExceeded)
+    );
+
+    // The rejected region is still owned by td0.
+    assert!(td0.borrow().data.capabilities.get(&big).is_ok());
+}
+
+#[test]
+fn test_engine_send_to_ancestor() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x0, 0x2000, Rights::all()),
+        )
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            td0_td1,
+            carve,
+            Remapped::Identity,
+            Attributes::empty(),
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    let td1 = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_td1)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+
+    // Hand td1 a capability to its own parent, as delegation would.
+    let td1_td0 = td1
+        .borrow_mut()
+        .data
+        .install(CapaWrapper::Domain(td0.clone()))
+        .unwrap();
+    assert_eq!(
+        engine.send(
+            td1.clone(),
+            td1_td0,
+            1,
+            Remapped::Identity,
+            Attributes::empty(),
+        ),
+        Err(CapaError::CycleDetected)
+    );
+    td1.borrow_mut().data.remove(td1_td0).unwrap();
+}
+
+#[test]
+fn test_engine_self_send() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x2000, 0x1000, Rights::READ),
+        )
+        .unwrap();
+
+    // A handle pointing back to td0 itself.
+    let td0_td0 = td0
+        .borrow_mut()
+        .data
+        .install(CapaWrapper::Domain(td0.clone()))
+        .unwrap();
+    assert_eq!(
+        engine.send(
+            td0.clone(),
+            td0_td0,
+            carve,
+            Remapped::Remapped(0x10000),
+            Attributes::empty(),
+        ),
+        Err(CapaError::SelfSend)
+    );
+    // The region was left untouched.
+    let region = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&carve)
+        .unwrap()
+        .as_region()
+        .unwrap();
+    assert_eq!(region.borrow().data.remapped, Remapped::Identity);
+    td0.borrow_mut().data.remove(td0_td0).unwrap();
+}
+
+#[test]
+fn test_engine_set_attributes() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x0, 0x1000, Rights::all()),
+        )
+        .unwrap();
+    engine
+        .set_attributes(td0.clone(), carve, Attributes::HASH)
+        .unwrap();
+    let display = format!("{}", td0.borrow());
+    let expected = r#"td0 = Sealed domain(r0,r1)
+|cores: 0xffff
+|mon.api: 0x1fff
+|vec0-255: ALLOWED|VISIBLE, r: 0x0, w: 0x0
+r0 = Exclusive 0x0 0x10000 with RWX mapped Identity
+| Carve at 0x0 0x1000 with RWX for r1
+r1 = Exclusive 0x0 0x1000 with RWX mapped Identity H
+|indices: 1->r0 2->r1
+"#;
+    assert_eq!(display, expected);
+
+    // Once sent, the region cannot be changed anymore.
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            td0_td1,
+            carve,
+            Remapped::Identity,
+            Attributes::HASH,
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    let td1 = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_td1)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    assert_eq!(
+        engine.set_attributes(td1.clone(), 1, Attributes::empty()),
+        Err(CapaError::InvalidAttributes)
+    );
+
+    // Building regions with attributes.
+    let region = create_root_region().data.with_attributes(Attributes::VITAL);
+    assert_eq!(region.attributes, Attributes::VITAL);
+}
+
+#[test]
+fn test_engine_dispatch_carve() {
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let access = Access::new(0x1000, 0x1000, Rights::READ | Rights::WRITE);
+
+    let direct = engine.carve(td0.clone(), td0_r0, &access).unwrap();
+    let args = [td0_r0, 0x3000, 0x1000, access.rights.bits() as u64, 0, 0];
+    let dispatched = match engine
+        .dispatch(td0.clone(), CallInterface::CARVE, &args)
+        .unwrap()
+    {
+        ClientResult::SingleValue(v) => v,
+        _ => panic!("Expected a handle"),
+    };
+    assert_eq!(dispatched, direct + 1);
+
+    let direct = engine.enumerate(td0.clone(), direct).unwrap();
+    let dispatched = engine.enumerate(td0.clone(), dispatched).unwrap();
+    assert_eq!(direct, "Exclusive 0x1000 0x2000 with RW_ mapped Identity");
+    assert_eq!(
+        dispatched,
+        "Exclusive 0x3000 0x4000 with RW_ mapped Identity"
+    );
+
+    // Bad field types are rejected.
+    let args = [0, 0, 42, 0, 0, 0];
+    assert!(matches!(
+        engine.dispatch(td0.clone(), CallInterface::SET, &args),
+        Err(CapaError::InvalidValue)
+    ));
+}
+
+#[test]
+fn test_engine_revoked_domain_rejected() {
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let child = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x0, 0x1000, Rights::all()),
+        )
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            child,
+            carve,
+            Remapped::Identity,
+            Attributes::empty(),
+        )
+        .unwrap();
+    engine.seal(td0.clone(), child).unwrap();
+
+    // Keep a reference to the child past its revocation.
+    let stale = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&child)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    engine.revoke(td0.clone(), child, 0).unwrap();
+
+    assert_eq!(
+        engine.attest(stale.clone(), None),
+        Err(CapaError::DomainRevoked)
+    );
+    assert_eq!(
+        engine.alias(stale.clone(), 1, &Access::new(0x0, 0x1000, Rights::all())),
+        Err(CapaError::DomainRevoked)
+    );
+    assert_eq!(
+        engine.create(
+            &stale,
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none()
+        ),
+        Err(CapaError::DomainRevoked)
+    );
+}
+
+#[test]
+fn test_engine_seal_recursive() {
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+    let parent = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    let parent_ref = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&parent)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+
+    // An unsealed domain cannot create, so install its children by hand.
+    let mut children = Vec::new();
+    for cores in [0x1, 0x0] {
+        let policies = Policies::new(cores, MonitorAPI::all(), InterruptPolicy::default_none());
+        let child = Rc::new(RefCell::new(Capability::<Domain>::new(Domain::new(
+            policies,
+        ))));
+        parent_ref
+            .borrow_mut()
+            .data
+            .install(CapaWrapper::Domain(child.clone()))
+            .unwrap();
+        children.push(child);
+    }
+
+    // A child without cores aborts the whole operation.
+    assert_eq!(
+        engine.seal_recursive(td0.clone(), parent),
+        Err(CapaError::NoCoresAssigned)
+    );
+    assert!(!parent_ref.borrow().data.is_sealed());
+    assert!(!children[0].borrow().data.is_sealed());
+
+    children[1].borrow_mut().data.policies.cores = 0x1;
+    engine.seal_recursive(td0.clone(), parent).unwrap();
+    assert!(parent_ref.borrow().data.is_sealed());
+    for child in children.iter() {
+        assert_eq!(child.borrow().data.status, Status::Sealed);
+    }
+    assert_eq!(
+        engine.seal_recursive(td0.clone(), parent),
+        Err(CapaError::DomainSealed)
+    );
+}
+
+#[test]
+fn test_engine_create_children() {
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+    let children = engine
+        .create_children(
+            &td0,
+            5,
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    assert_eq!(children.len(), 5);
+    assert_eq!(td0.borrow().children.len(), 5);
+    for child in children.iter() {
+        assert!(td0.borrow().data.is_domain(*child).unwrap());
+    }
+
+    // Not enough handles left for the batch, nothing is created.
+    let baseline = format!("{}", td0.borrow());
+    let used = td0.borrow().data.capabilities.capabilities.len();
+    td0.borrow_mut().data.capabilities.max_handles = used + 2;
+    assert_eq!(
+        engine.create_children(
+            &td0,
+            3,
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none()
+        ),
+        Err(CapaError::TooManyCapabilities)
+    );
+    assert_eq!(td0.borrow().children.len(), 5);
+    assert_eq!(format!("{}", td0.borrow()), baseline);
+}
+
+#[test]
+fn test_engine_revoke_vital_kills_holder() {
+    let (mut engine, td0, r0, td0_r0) = setup_engine_with_root();
+    let child = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x0, 0x1000, Rights::all()),
+        )
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            child,
+            carve,
+            Remapped::Identity,
+            Attributes::VITAL,
+        )
+        .unwrap();
+    engine.seal(td0.clone(), child).unwrap();
+    let child_ref = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&child)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+
+    // Revoking the vital region takes the child down with it.
+    engine.revoke(td0.clone(), td0_r0, 0).unwrap();
+    assert_eq!(child_ref.borrow().data.status, Status::Revoked);
+    assert!(td0.borrow().children.is_empty());
+    assert!(td0.borrow().data.capabilities.get(&child).is_err());
+    assert!(r0.borrow().children.is_empty());
+}
+
+#[test]
+fn test_engine_revoke_not_owned() {
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let child = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x0, 0x1000, Rights::all()),
+        )
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            child,
+            carve,
+            Remapped::Identity,
+            Attributes::empty(),
+        )
+        .unwrap();
+    let child_ref = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&child)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    let child_handle = *child_ref
+        .borrow()
+        .data
+        .capabilities
+        .capabilities
+        .keys()
+        .next()
+        .unwrap();
+    let region = child_ref
+        .borrow()
+        .data
+        .capabilities
+        .get(&child_handle)
+        .unwrap()
+        .as_region()
+        .unwrap();
+
+    // A stale entry in td0 pointing at the child's region.
+    let stale = 42;
+    td0.borrow_mut()
+        .data
+        .capabilities
+        .install_capabilitiy_at(CapaWrapper::Region(region.clone()), stale);
+    assert_eq!(
+        engine.revoke(td0.clone(), stale, 0),
+        Err(CapaError::CapaNotOwned)
+    );
+    assert!(Rc::ptr_eq(
+        &region.borrow().owned.owner.upgrade().unwrap(),
+        &child_ref
+    ));
+}
+
+#[test]
+fn test_engine_attest_chunks() {
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    for i in 0..20 {
+        let child = engine
+            .create(
+                &td0.clone(),
+                0x1,
+                MonitorAPI::all(),
+                InterruptPolicy::default_none(),
+            )
+            .unwrap();
+        let alias = engine
+            .alias(
+                td0.clone(),
+                td0_r0,
+                &Access::new(0x800 * i, 0x800, Rights::READ),
+            )
+            .unwrap();
+        engine
+            .send(
+                td0.clone(),
+                child,
+                alias,
+                Remapped::Identity,
+                Attributes::empty(),
+            )
+            .unwrap();
+    }
+
+    let full = format!("{}", td0.borrow());
+    let chunks: Vec<String> = td0.borrow().attest_chunks(64).collect();
+    assert!(chunks.len() > 1);
+    assert!(chunks.iter().all(|c| !c.is_empty() && c.len() <= 64));
+    assert_eq!(chunks.concat(), full);
+
+    // Everything fits in a single chunk.
+    let single: Vec<String> = td0.borrow().attest_chunks(full.len()).collect();
+    assert_eq!(single, vec![full]);
+}
+
+#[test]
+fn test_engine_find_region_by_access() {
+    let (mut engine, td0, r0, td0_r0) = setup_engine_with_root();
+    let access = Access::new(0x3000, 0x1000, Rights::READ | Rights::WRITE);
+    let carve = engine.carve(td0.clone(), td0_r0, &access).unwrap();
+
+    let (handle, region) = td0.borrow().find_region_by_access(&access).unwrap();
+    assert_eq!(handle, carve);
+    assert!(Rc::ptr_eq(&region, &r0.borrow().children[0]));
+
+    // Only exact matches.
+    let partial = Access::new(0x3000, 0x800, Rights::READ | Rights::WRITE);
+    assert!(td0.borrow().find_region_by_access(&partial).is_none());
+}
+
+#[test]
+fn test_engine_validate_tree_double_alias() {
+    let (mut engine, td0, r0, td0_r0) = setup_engine_with_root();
+    engine.validate_tree().unwrap();
+
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x0, 0x2000, Rights::all()),
+        )
+        .unwrap();
+    let mut children = Vec::new();
+    for size in [0x2000, 0x1000] {
+        let child = engine
+            .create(
+                &td0.clone(),
+                0b111,
+                MonitorAPI::all(),
+                InterruptPolicy::default_all(),
+            )
+            .unwrap();
+        let alias = engine
+            .alias(td0.clone(), carve, &Access::new(0x0, size, Rights::all()))
+            .unwrap();
+        engine
+            .send(
+                td0.clone(),
+                child,
+                alias,
+                Remapped::Identity,
+                Attributes::empty(),
+            )
+            .unwrap();
+        engine.seal(td0.clone(), child).unwrap();
+        children.push(child);
+    }
+    engine.validate_tree().unwrap();
+
+    // Revoking the carve removes both aliases.
+    engine.revoke(td0.clone(), td0_r0, 0).unwrap();
+    engine.validate_tree().unwrap();
+    assert!(r0.borrow().children.is_empty());
+
+    // Corrupt the tree: td0 now holds the root region twice.
+    td0.borrow_mut()
+        .data
+        .capabilities
+        .install_capabilitiy_at(CapaWrapper::Region(r0.clone()), 42);
+    assert_eq!(engine.validate_tree(), Err(CapaError::CapaNotOwned));
+    td0.borrow_mut().data.remove(42).unwrap();
+
+    for child in children {
+        engine.revoke(td0.clone(), child, 0).unwrap();
+    }
+    engine.validate_tree().unwrap();
+}
+
+#[test]
+fn test_engine_restrict() {
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    engine.enable_updates();
+    let access = Access::new(0x0, 0x1000, Rights::all());
+    let carve = engine.carve(td0.clone(), td0_r0, &access).unwrap();
+
+    engine.restrict(td0.clone(), carve, Rights::READ).unwrap();
+    let updates = engine.drain_updates();
+    assert_eq!(updates.len(), 1);
+    assert_eq!(updates[0].len(), 1);
+    match &updates[0][0] {
+        Update::ChangeMemory { dom, range, rights } => {
+            assert!(Rc::ptr_eq(&dom.upgrade().unwrap(), &td0));
+            assert_eq!(*range, access);
+            assert_eq!(*rights, Rights::all());
+        }
+        _ => panic!("expected a memory change"),
+    }
+    let display = format!("{}", td0.borrow());
+    assert!(display.contains("| Carve at 0x0 0x1000 with R__ for r1\n"));
+    assert!(display.contains("r1 = Exclusive 0x0 0x1000 with R__ mapped Identity\n"));
+
+    // Rights cannot come back.
+    assert_eq!(
+        engine
+            .restrict(td0.clone(), carve, Rights::READ | Rights::WRITE)
+            .err(),
+        Some(CapaError::InsufficientRights)
+    );
+}
+
+#[test]
+fn test_engine_send_unaligned_remap() {
+    let mut engine = Engine::with_page_size(16, PAGE_SIZE);
+    let td0 = engine.root.clone();
+    let r0 = Rc::new(RefCell::new(create_root_region()));
+    let td0_r0 = engine.add_root_region(&td0, &r0).unwrap();
+    let child = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x0, 0x1000, Rights::all()),
+        )
+        .unwrap();
+    assert_eq!(
+        engine.send(
+            td0.clone(),
+            child,
+            carve,
+            Remapped::Remapped(0x30800),
+            Attributes::empty(),
+        ),
+        Err(CapaError::InvalidRemap)
+    );
+    engine
+        .send(
+            td0.clone(),
+            child,
+            carve,
+            Remapped::Remapped(0x30000),
+            Attributes::empty(),
+        )
+        .unwrap();
+
+    // The region itself must be aligned too.
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x1000, 0x800, Rights::all()),
+        )
+        .unwrap();
+    assert_eq!(
+        engine.send(
+            td0.clone(),
+            child,
+            carve,
+            Remapped::Identity,
+            Attributes::empty(),
+        ),
+        Err(CapaError::InvalidRemap)
+    );
+}
+
+#[test]
+fn test_engine_core_conflicts() {
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+    let mut children = Vec::new();
+    for cores in [0b011, 0b110] {
+        let child = engine
+            .create(
+                &td0.clone(),
+                cores,
+                MonitorAPI::all(),
+                InterruptPolicy::default_none(),
+            )
+            .unwrap();
+        engine.seal(td0.clone(), child).unwrap();
+        children.push(child);
+    }
+    assert!(engine.core_conflicts().is_empty());
+
+    // The first takes cores 0 and 1, the second only gets core 2.
+    engine.switch(td0.clone(), children[0]).unwrap();
+    engine.switch(td0.clone(), children[1]).unwrap();
+    let ids: Vec<u64> = children
+        .iter()
+        .map(|c| {
+            td0.borrow()
+                .data
+                .capabilities
+                .get(c)
+                .unwrap()
+                .as_domain()
+                .unwrap()
+                .borrow()
+                .data
+                .id
+        })
+        .collect();
+    assert_eq!(engine.core_conflicts(), vec![(ids[0], ids[1])]);
+}
+
+#[test]
+fn test_engine_inherit_interrupts() {
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0b1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    let td1 = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_td1)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    assert_ne!(
+        td1.borrow().data.policies.interrupts,
+        td0.borrow().data.policies.interrupts
+    );
+
+    engine.inherit_interrupts(td0.clone(), td0_td1).unwrap();
+    assert_eq!(
+        td1.borrow().data.policies.interrupts.vectors,
+        InterruptPolicy::default_all().vectors
+    );
+
+    // Once sealed the policy is frozen.
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    assert_eq!(
+        engine.inherit_interrupts(td0.clone(), td0_td1),
+        Err(CapaError::DomainSealed)
+    );
+}
+
+#[test]
+fn test_engine_max_depth() {
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+    engine.set_max_depth(Some(3));
+
+    let mut current = td0.clone();
+    for depth in 1..=4 {
+        let res = engine.create(
+            &current,
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        );
+        if depth == 4 {
+            assert_eq!(res, Err(CapaError::MaxDepthExceeded));
+            break;
+        }
+        let child = res.unwrap();
+        engine.seal(current.clone(), child).unwrap();
+        let next = current
+            .borrow()
+            .data
+            .capabilities
+            .get(&child)
+            .unwrap()
+            .as_domain()
+            .unwrap();
+        current = next;
+    }
+}
+
+#[test]
+fn test_engine_send_requires_receive_once_sealed() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all().difference(MonitorAPI::RECEIVE),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    let first = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x2000, 0x1000, Rights::READ | Rights::WRITE),
+        )
+        .unwrap();
+    let second = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x4000, 0x1000, Rights::READ | Rights::WRITE),
+        )
+        .unwrap();
+
+    // Unsealed, the child receives freely.
+    let td1 = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_td1)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    assert!(td1.borrow().data.can_receive());
+    engine
+        .send(
+            td0.clone(),
+            td0_td1,
+            first,
+            Remapped::Identity,
+            Attributes::NONE,
+        )
+        .unwrap();
+
+    // Sealed without RECEIVE, further sends are rejected.
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    assert!(!td1.borrow().data.can_receive());
+    assert_eq!(
+        engine.send(
+            td0.clone(),
+            td0_td1,
+            second,
+            Remapped::Identity,
+            Attributes::CLEAN,
+        ),
+        Err(CapaError::CallNotAllowed)
+    );
+}
+
+#[test]
+fn test_engine_with_root_policies() {
+    let mut engine = Engine::with_root_policies(Policies::new(
+        0b11,
+        MonitorAPI::all().difference(MonitorAPI::ALIAS),
+        InterruptPolicy::default_all(),
+    ));
+    assert!(engine.root.borrow().data.is_sealed());
+    assert_eq!(engine.scheduled.len(), 2);
+
+    let r0 = Rc::new(RefCell::new(create_root_region()));
+    let td0_r0 = engine.add_root_region(&engine.root.clone(), &r0).unwrap();
+    let td0 = engine.root.clone();
+
+    // The root cannot alias its own region.
+    assert_eq!(
+        engine.alias(td0.clone(), td0_r0, &Access::new(0x0, 0x1000, Rights::READ)),
+        Err(CapaError::CallNotAllowed)
+    );
+    // Other operations remain allowed.
+    engine
+        .carve(td0.clone(), td0_r0, &Access::new(0x0, 0x1000, Rights::READ))
+        .unwrap();
+}
+
+#[test]
+fn test_engine_next_runnable_round_robin() {
+    // Initial setup
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+    let mut expected = vec![td0.clone()];
+    for _ in 0..2 {
+        let capa = engine
+            .create(
+                &td0.clone(),
+                0x1,
+                MonitorAPI::all(),
+                InterruptPolicy::default_all(),
+            )
+            .unwrap();
+        engine.seal(td0.clone(), capa).unwrap();
+        expected.push(
+            td0.borrow()
+                .data
+                .capabilities
+                .get(&capa)
+                .unwrap()
+                .as_domain()
+                .unwrap(),
+        );
+    }
+    // An unsealed domain on the same core is never picked.
+    engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+
+    // Cycles through the three sealed domains in order.
+    for round in 0..6 {
+        let next = engine.next_runnable(0).unwrap();
+        assert!(same_capa(&next, &expected[round % 3]));
+    }
+    // Only the root runs on core 1.
+    assert!(same_capa(&engine.next_runnable(1).unwrap(), &td0));
+    assert!(same_capa(&engine.next_runnable(1).unwrap(), &td0));
+    assert!(engine.next_runnable(16).is_none());
+}
+
+#[test]
+fn test_engine_carve_inherit_attributes() {
+    let mut engine = Engine::new(16);
+    let mut root_region = create_root_region();
+    root_region.data = root_region
+        .data
+        .with_attributes(Attributes::HASH | Attributes::VITAL);
+    let r0 = Rc::new(RefCell::new(root_region));
+    let td0 = engine.root.clone();
+    let td0_r0 = engine.add_root_region(&td0, &r0).unwrap();
+    let attributes = |capa: LocalCapa| {
+        let region = td0
+            .borrow()
+            .data
+            .capabilities
+            .get(&capa)
+            .unwrap()
+            .as_region()
+            .unwrap();
+        let attributes = region.borrow().data.attributes;
+        attributes
+    };
+
+    // By default nothing is inherited.
+    let plain = engine
+        .carve(td0.clone(), td0_r0, &Access::new(0x0, 0x1000, Rights::READ))
+        .unwrap();
+    assert_eq!(attributes(plain), Attributes::NONE);
+
+    // Only HASH is inherited, never VITAL.
+    let carved = engine
+        .carve_with(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x1000, 0x1000, Rights::READ),
+            true,
+        )
+        .unwrap();
+    assert_eq!(attributes(carved), Attributes::HASH);
+    let aliased = engine
+        .alias_with(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x4000, 0x1000, Rights::READ),
+            true,
+        )
+        .unwrap();
+    assert_eq!(attributes(aliased), Attributes::HASH);
+}
+
+#[test]
+fn test_engine_promote_carve() {
+    // Initial setup
+    let (mut engine, td0, r0, td0_r0) = setup_engine_with_root();
+    let rw = Rights::READ | Rights::WRITE;
+    let td0_a = engine
+        .carve(td0.clone(), td0_r0, &Access::new(0x8000, 0x8000, rw))
+        .unwrap();
+    let td0_b = engine
+        .carve(td0.clone(), td0_a, &Access::new(0xc000, 0x4000, rw))
+        .unwrap();
+    // Not at an edge of its parent, cannot be promoted.
+    let td0_c = engine
+        .carve(td0.clone(), td0_a, &Access::new(0x9000, 0x1000, rw))
+        .unwrap();
+    assert_eq!(
+        engine.promote(td0.clone(), td0_c),
+        Err(CapaError::InvalidAccess)
+    );
+    // A root region has nothing to be promoted from.
+    assert_eq!(
+        engine.promote(td0.clone(), td0_r0),
+        Err(CapaError::InvalidValue)
+    );
+
+    engine.promote(td0.clone(), td0_b).unwrap();
+    let b = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_b)
+        .unwrap()
+        .as_region()
+        .unwrap();
+    assert!(b.borrow().parent.upgrade().is_none());
+    // The ancestors gave up the range.
+    assert_eq!(
+        r0.borrow().data.access,
+        Access::new(0x0, 0xc000, Rights::all())
+    );
+
+    // Revoking the former parent leaves the promoted region untouched.
+    engine.revoke(td0.clone(), td0_r0, 0).unwrap();
+    assert!(td0.borrow().data.capabilities.get(&td0_a).is_err());
+    assert!(td0.borrow().data.capabilities.get(&td0_c).is_err());
+    let b = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_b)
+        .unwrap()
+        .as_region()
+        .unwrap();
+    assert_eq!(b.borrow().data.access, Access::new(0xc000, 0x4000, rw));
+    assert_eq!(
+        td0.borrow().view().unwrap(),
+        vec![
+            ViewRegion::new(Access::new(0x0, 0xc000, Rights::all()), Remapped::Identity),
+            ViewRegion::new(Access::new(0xc000, 0x4000, rw), Remapped::Identity),
+        ]
+    );
+}
+
+#[test]
+fn test_engine_set_get_not_owned_child() {
+    // Initial setup
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    let td1 = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_td1)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    let td1_td2 = engine
+        .create(
+            &td1.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    let td2 = td1
+        .borrow()
+        .data
+        .capabilities
+        .get(&td1_td2)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+
+    // A handle in td0 that resolves to a domain owned by td1.
+    let stale = td0
+        .borrow_mut()
+        .data
+        .install(CapaWrapper::Domain(td2.clone()))
+        .unwrap();
+    assert_eq!(
+        engine.set(td0.clone(), stale, 0, FieldType::Cores, 0, 0x1),
+        Err(CapaError::CapaNotOwned)
+    );
+    assert_eq!(
+        engine.get(td0.clone(), stale, 0, FieldType::Cores, 0),
+        Err(CapaError::CapaNotOwned)
+    );
+
+    // The owner can still use its own handles.
+    engine
+        .set(td1.clone(), td1_td2, 0, FieldType::Cores, 0, 0x1)
+        .unwrap();
+    assert_eq!(
+        engine.get(td0.clone(), td0_td1, 0, FieldType::Cores, 0),
+        Ok(0x1)
+    );
+    td0.borrow_mut().data.remove(stale).unwrap();
+}
+
+#[test]
+fn test_engine_seal_and_attest() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x2000, 0x1000, Rights::READ),
+        )
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            td0_td1,
+            carve,
+            Remapped::Identity,
+            Attributes::NONE,
+        )
+        .unwrap();
+
+    let attestation = engine.seal_and_attest(td0.clone(), td0_td1).unwrap();
+    let td1 = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_td1)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    assert!(td1.borrow().data.is_sealed());
+    assert_eq!(
+        attestation,
+        engine.attest(td0.clone(), Some(td0_td1)).unwrap()
+    );
+    // Already sealed.
+    assert_eq!(
+        engine.seal_and_attest(td0.clone(), td0_td1),
+        Err(CapaError::DomainSealed)
+    );
+}
+
+#[test]
+fn test_engine_can_send_dry_run() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    let first = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x2000, 0x1000, Rights::READ),
+        )
+        .unwrap();
+    let second = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x4000, 0x1000, Rights::READ),
+        )
+        .unwrap();
+    let remap = Remapped::Remapped(0x10000);
+    assert_eq!(
+        engine.can_send(td0.clone(), td0_td1, first, remap, Attributes::NONE),
+        Ok(())
+    );
+    engine
+        .send(td0.clone(), td0_td1, first, remap, Attributes::NONE)
+        .unwrap();
+
+    // Same guest address, different physical range.
+    let td1 = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_td1)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    let before = format!("{}", td1.borrow());
+    assert_eq!(
+        engine.can_send(td0.clone(), td0_td1, second, remap, Attributes::NONE),
+        Err(CapaError::IncompatibleRemap)
+    );
+    // Nothing moved.
+    assert!(td0.borrow().data.capabilities.get(&second).is_ok());
+    assert_eq!(format!("{}", td1.borrow()), before);
+    // The actual send fails the same way.
+    assert_eq!(
+        engine.send(td0.clone(), td0_td1, second, remap, Attributes::NONE),
+        Err(CapaError::IncompatibleRemap)
+    );
+}
+
+#[test]
+fn test_engine_reclaim_keeps_domains() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x0, 0x1000, Rights::all()),
+        )
+        .unwrap();
+
+    // Send the carve down a 3-deep chain.
+    let mut chain = Vec::new();
+    let mut current: CapaRef<Domain> = td0.clone();
+    let mut to_send: LocalCapa = carve;
+    for _ in 0..3 {
+        let child = engine
+            .create(
+                &current.clone(),
+                0b1,
+                MonitorAPI::all(),
+                InterruptPolicy::default_all(),
+            )
+            .unwrap();
+        engine.seal(current.clone(), child).unwrap();
+        engine
+            .send(
+                current.clone(),
+                child,
+                to_send,
+                Remapped::Remapped(0x10000),
+                Attributes::empty(),
+            )
+            .unwrap();
+        let child_ref = current
+            .borrow()
+            .data
+            .capabilities
+            .get(&child)
+            .unwrap()
+            .as_domain()
+            .unwrap();
+        current = child_ref;
+        chain.push(current.clone());
+        to_send = 1;
+    }
+    // The last one aliases it further, that is revoked.
+    engine
+        .alias(current.clone(), 1, &Access::new(0x0, 0x1000, Rights::READ))
+        .unwrap();
+    assert_eq!(current.borrow().data.capabilities.capabilities.len(), 2);
+
+    let reclaimed = engine.reclaim(td0.clone(), td0_r0, 0).unwrap();
+    let region = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&reclaimed)
+        .unwrap()
+        .as_region()
+        .unwrap();
+    assert!(region.borrow().children.is_empty());
+    assert_eq!(region.borrow().data.remapped, Remapped::Identity);
+    assert!(same_capa(
+        &region.borrow().owned.owner.upgrade().unwrap(),
+        &td0
+    ));
+
+    // The intermediate domains survive without the region.
+    for dom in chain.iter() {
+        assert!(dom.borrow().data.is_sealed());
+        assert!(dom.borrow().view().unwrap().is_empty());
+    }
+    assert!(current.borrow().data.capabilities.capabilities.is_empty());
+    assert_eq!(
+        td0.borrow().view().unwrap(),
+        vec![ViewRegion::new(
+            Access::new(0x0, 0x10000, Rights::all()),
+            Remapped::Identity
+        )]
+    );
+    assert_eq!(
+        engine.reclaim(td0.clone(), td0_r0, 1),
+        Err(CapaError::InvalidChildCapa)
+    );
+}
+
+#[test]
+fn test_engine_revoke_alias_by_access() {
+    // Initial setup
+    let (mut engine, td0, r0, td0_r0) = setup_engine_with_root();
+    let first = Access::new(0x0, 0x1000, Rights::all());
+    let second = Access::new(0x0, 0x1000, Rights::READ);
+    let td0_first = engine.alias(td0.clone(), td0_r0, &first).unwrap();
+    let td0_second = engine.alias(td0.clone(), td0_r0, &second).unwrap();
+
+    engine.revoke_alias(td0.clone(), td0_r0, &second).unwrap();
+    assert!(td0.borrow().data.capabilities.get(&td0_first).is_ok());
+    assert!(td0.borrow().data.capabilities.get(&td0_second).is_err());
+    assert_eq!(r0.borrow().children.len(), 1);
+    assert_eq!(r0.borrow().children[0].borrow().data.access, first);
+
+    // No alias left with that access.
+    assert_eq!(
+        engine.revoke_alias(td0.clone(), td0_r0, &second),
+        Err(CapaError::ChildNotFound)
+    );
+    // Carves are not matched.
+    let carved = Access::new(0x2000, 0x1000, Rights::all());
+    engine.carve(td0.clone(), td0_r0, &carved).unwrap();
+    assert_eq!(
+        engine.revoke_alias(td0.clone(), td0_r0, &carved),
+        Err(CapaError::ChildNotFound)
+    );
+}
+
+#[test]
+fn test_engine_unsealed_caller() {
+    // Initial setup
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    let td1 = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_td1)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+
+    // The caller is checked before the target, even an invalid one.
+    assert_eq!(
+        engine.attest(td1.clone(), None),
+        Err(CapaError::DomainUnsealed)
+    );
+    assert_eq!(
+        engine.attest(td1.clone(), Some(42)),
+        Err(CapaError::DomainUnsealed)
+    );
+    assert_eq!(
+        engine.enumerate(td1.clone(), 42),
+        Err(CapaError::DomainUnsealed)
+    );
+    assert_eq!(
+        engine.create(
+            &td1.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        ),
+        Err(CapaError::DomainUnsealed)
+    );
+
+    // A sealed caller can attest and enumerate its unsealed child.
+    engine.attest(td0.clone(), Some(td0_td1)).unwrap();
+    engine.enumerate(td0.clone(), td0_td1).unwrap();
+}
+
+#[test]
+fn test_engine_regions_intersecting() {
+    // Initial setup
+    let engine = Engine::new(16);
+    let td0 = engine.root.clone();
+    let mut handles = Vec::new();
+    for start in [0x0, 0x10000, 0x20000] {
+        let region = Capability::<MemoryRegion>::new(MemoryRegion {
+            kind: RegionKind::Carve,
+            status: MStatus::Exclusive,
+            access: Access::new(start, 0x10000, Rights::READ),
+            attributes: Attributes::NONE,
+            remapped: Remapped::Identity,
+        });
+        let region = Rc::new(RefCell::new(region));
+        handles.push(engine.add_root_region(&td0, &region).unwrap());
+    }
+
+    let query = Access::new(0x8000, 0x10000, Rights::all());
+    assert_eq!(
+        td0.borrow().regions_intersecting(&query),
+        vec![
+            (handles[0], Access::new(0x8000, 0x8000, Rights::READ)),
+            (handles[1], Access::new(0x10000, 0x8000, Rights::READ)),
+        ]
+    );
+    assert!(td0
+        .borrow()
+        .regions_intersecting(&Access::new(0x30000, 0x1000, Rights::READ))
+        .is_empty());
+}
+
+#[test]
+fn test_engine_seal_rejects_wider_read_set() {
+    // Initial setup
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+    let mut restricted = InterruptPolicy::default_all();
+    restricted.vectors[0].read_set = 0xff;
+    let td0_td1 = engine
+        .create(&td0.clone(), 0x1, MonitorAPI::all(), restricted)
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    let td1 = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_td1)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+
+    // Unrestricting a register td1 cannot read is rejected.
+    let mut wider = restricted;
+    wider.vectors[0].read_set = 0xf0;
+    let td1_td2 = engine
+        .create(&td1.clone(), 0x1, MonitorAPI::all(), wider)
+        .unwrap();
+    assert_eq!(
+        engine.seal(td1.clone(), td1_td2),
+        Err(CapaError::InsufficientRights)
+    );
+
+    // Restricting more is fine.
+    let mut narrower = restricted;
+    narrower.vectors[0].read_set = 0xfff;
+    narrower.vectors[0].write_set = 0x1;
+    let td1_td3 = engine
+        .create(&td1.clone(), 0x1, MonitorAPI::all(), narrower)
+        .unwrap();
+    engine.seal(td1.clone(), td1_td3).unwrap();
+}
+
+#[test]
+fn test_engine_snapshot_restore() {
+    // Initial setup
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x2000, 0x1000, Rights::READ | Rights::WRITE),
+        )
+        .unwrap();
+    engine
+        .alias(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x8000, 0x1000, Rights::READ),
+        )
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            td0_td1,
+            carve,
+            Remapped::Remapped(0x40000),
+            Attributes::NONE,
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    // Leave a freed handle behind.
+    let tmp = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0xa000, 0x1000, Rights::READ),
+        )
+        .unwrap();
+    engine.revoke(td0.clone(), td0_r0, 2).unwrap();
+    assert!(td0.borrow().data.capabilities.get(&tmp).is_err());
+
+    let snapshot = engine.snapshot();
+    let mut restored = Engine::restore(&snapshot).unwrap();
+    let original = engine.attest(td0.clone(), None).unwrap();
+    assert_eq!(
+        restored.attest(restored.root.clone(), None).unwrap(),
+        original
+    );
+    assert_eq!(
+        engine.attest(td0.clone(), Some(td0_td1)).unwrap(),
+        restored
+            .attest(restored.root.clone(), Some(td0_td1))
+            .unwrap()
+    );
+
+    // Allocations continue the same way on both.
+    let access = Access::new(0xc000, 0x1000, Rights::READ);
+    let next = engine.carve(td0.clone(), td0_r0, &access).unwrap();
+    let root = restored.root.clone();
+    assert_eq!(restored.carve(root, td0_r0, &access), Ok(next));
+    assert_eq!(next, tmp);
+}
+
+#[test]
+fn test_engine_snapshot_restore_children() {
+    let mut engine = Engine::with_update_cap(4, 8, UpdateQueueMode::Reject);
+    let td0 = engine.root.clone();
+    let ref_mem = Rc::new(RefCell::new(create_root_region()));
+    let td0_r0 = engine.add_root_region(&td0, &ref_mem).unwrap();
+    let td0_td1 = engine
+        .create(&td0, 0x1, MonitorAPI::all(), InterruptPolicy::default_all())
+        .unwrap();
+    for start in [0x1000, 0x4000] {
+        let carve = engine
+            .carve(
+                td0.clone(),
+                td0_r0,
+                &Access::new(start, 0x2000, Rights::READ | Rights::WRITE),
+            )
+            .unwrap();
+        engine
+            .send(
+                td0.clone(),
+                td0_td1,
+                carve,
+                Remapped::Identity,
+                Attributes::NONE,
+            )
+            .unwrap();
+    }
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    let td1 = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_td1)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    // The child frees a handle, so its allocator differs from a fresh one.
+    let tmp = engine
+        .carve(td1.clone(), 2, &Access::new(0x4000, 0x1000, Rights::READ))
+        .unwrap();
+    engine.revoke(td1.clone(), 2, 0).unwrap();
+    assert!(td1.borrow().data.capabilities.get(&tmp).is_err());
+    engine.switch(td0.clone(), td0_td1).unwrap();
+    engine.drain_updates();
+
+    let snapshot = engine.snapshot();
+    let mut restored = Engine::restore(&snapshot).unwrap();
+    assert_eq!(restored.validate_tree(), Ok(()));
+    assert_eq!(restored.update_cap(), Some((8, UpdateQueueMode::Reject)));
+    let root = restored.root.clone();
+    let child = root
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_td1)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    assert_eq!(restored.scheduled.len(), 4);
+    assert!(same_capa(&restored.scheduled[0].upgrade().unwrap(), &child));
+    assert!(restored.scheduled[1].upgrade().is_none());
+    let handles = |d: &CapaRef<Domain>| -> Vec<(LocalCapa, Access)> {
+        let mut handles = Vec::new();
+        for (h, c) in d.borrow().data.capabilities.capabilities.iter() {
+            handles.push((*h, c.as_region().unwrap().borrow().data.access));
+        }
+        handles
+    };
+    assert_eq!(handles(&child), handles(&td1));
+
+    // Allocations continue the same way, and the child revokes what it holds.
+    let access = Access::new(0x1000, 0x1000, Rights::READ);
+    let next = engine.carve(td1.clone(), 1, &access).unwrap();
+    assert_eq!(next, tmp);
+    assert_eq!(restored.carve(child.clone(), 1, &access), Ok(next));
+    assert_eq!(restored.revoke(child.clone(), 1, 0), Ok(()));
+    assert!(child.borrow().data.capabilities.get(&next).is_err());
+    assert_eq!(restored.validate_tree(), Ok(()));
+}
+
+#[test]
+fn test_engine_snapshot_restore_forest() {
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let rw = Rights::READ | Rights::WRITE;
+    let (td0_td1, td1) = engine
+        .create_ref(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    let carve = engine
+        .carve(td0.clone(), td0_r0, &Access::new(0x0, 0x8000, rw))
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            td0_td1,
+            carve,
+            Remapped::Identity,
+            Attributes::NONE,
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+
+    // The child carves and aliases its region, and hands a carve to a grandchild.
+    let (td1_td2, td2) = engine
+        .create_ref(
+            &td1.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    let inner = engine
+        .carve(td1.clone(), 1, &Access::new(0x1000, 0x2000, rw))
+        .unwrap();
+    engine
+        .alias(td1.clone(), 1, &Access::new(0x6000, 0x1000, Rights::READ))
+        .unwrap();
+    engine
+        .carve(td1.clone(), inner, &Access::new(0x1000, 0x1000, rw))
+        .unwrap();
+    engine
+        .send(
+            td1.clone(),
+            td1_td2,
+            inner,
+            Remapped::Remapped(0x40000),
+            Attributes::NONE,
+        )
+        .unwrap();
+    engine.seal(td1.clone(), td1_td2).unwrap();
+
+    let snapshot = engine.snapshot();
+    let mut restored = Engine::restore(&snapshot).unwrap();
+    assert_eq!(restored.validate_tree(), Ok(()));
+    let root = restored.root.clone();
+    let get_domain = |d: &CapaRef<Domain>, h: LocalCapa| {
+        d.borrow()
+            .data
+            .capabilities
+            .get(&h)
+            .unwrap()
+            .as_domain()
+            .unwrap()
+    };
+    let child = get_domain(&root, td0_td1);
+    let grandchild = get_domain(&child, td1_td2);
+    for (original, copy) in [(&td0, &root), (&td1, &child), (&td2, &grandchild)] {
+        assert_eq!(
+            format!("{}", copy.borrow()),
+            format!("{}", original.borrow())
+        );
+        assert_eq!(copy.borrow().view(), original.borrow().view());
+    }
+
+    // The child revokes what it sent to the grandchild, on both engines.
+    engine.revoke(td1.clone(), 1, 0).unwrap();
+    assert_eq!(restored.revoke(child.clone(), 1, 0), Ok(()));
+    assert_eq!(restored.validate_tree(), Ok(()));
+    assert!(grandchild
+        .borrow()
+        .data
+        .capabilities
+        .capabilities
+        .is_empty());
+    assert_eq!(format!("{}", child.borrow()), format!("{}", td1.borrow()));
+    let access = Access::new(0x4000, 0x1000, rw);
+    let next = engine.carve(td1.clone(), 1, &access).unwrap();
+    assert_eq!(restored.carve(child.clone(), 1, &access), Ok(next));
+}
+
+#[test]
+fn test_engine_child_domains() {
+    // Initial setup
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+    for _ in 0..2 {
+        engine
+            .create(
+                &td0.clone(),
+                0x1,
+                MonitorAPI::all(),
+                InterruptPolicy::default_all(),
+            )
+            .unwrap();
+    }
+    let children = td0.borrow().child_domains();
+    assert_eq!(children.len(), 2);
+    for child in children.iter() {
+        let weak = Rc::downgrade(child);
+        let live = weak.upgrade().unwrap();
+        assert_eq!(live.borrow().data.status, Status::Unsealed);
+        assert!(same_capa(&live.borrow().parent.upgrade().unwrap(), &td0));
+    }
+    assert!(children[0].borrow().child_domains().is_empty());
+}
+
+#[test]
+fn test_engine_create_ref() {
+    // Initial setup
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+    let (td0_td1, td1) = engine
+        .create_ref(
+            &td0.clone(),
+            0x3,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    td1.borrow_mut().set(0, FieldType::Cores, 0, 0x1).unwrap();
+    assert_eq!(td1.borrow().data.policies.cores, 0x1);
+
+    // The ref is the same child as the one behind the handle.
+    let lookup = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&td0_td1)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    assert!(same_capa(&lookup, &td1));
+    assert_eq!(
+        engine.get(td0.clone(), td0_td1, 0, FieldType::Cores, 0),
+        Ok(0x1)
+    );
+}
+
+#[test]
+fn test_engine_update_cap() {
+    // Sends three regions to a child and returns the outcome of each send.
+    let send_three = |engine: &mut Engine| {
+        let td0 = engine.root.clone();
+        let r0 = Rc::new(RefCell::new(create_root_region()));
+        let td0_r0 = engine.add_root_region(&td0, &r0).unwrap();
+        let child = engine
+            .create(
+                &td0.clone(),
+                0x1,
+                MonitorAPI::all(),
+                InterruptPolicy::default_none(),
+            )
+            .unwrap();
+        (0..3)
+            .map(|i| {
+                let access = Access::new(
+                    i * 0x1000,
+                    0x1000,
+                    Rights::READ | Rights::WRITE | Rights::EXECUTE,
+                );
+                let carve = engine.carve(td0.clone(), td0_r0, &access).unwrap();
+                engine.send(
+                    td0.clone(),
+                    child,
+                    carve,
+                    Remapped::Identity,
+                    Attributes::empty(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let starts = |engine: &Engine| {
+        engine
+            .updates
+            .iter()
+            .map(|batch| match &batch[0] {
+                Update::ChangeMemory { range, .. } => range.start,
+                _ => panic!("unexpected update"),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Nothing is queued by default.
+    let mut engine = Engine::new(16);
+    assert!(send_three(&mut engine).iter().all(|r| r.is_ok()));
+    assert!(engine.updates.is_empty());
+
+    // Once enabled, the queue is unbounded unless a cap is set.
+    let mut engine = Engine::new(16);
+    engine.enable_updates();
+    assert_eq!(engine.update_cap(), None);
+    assert!(send_three(&mut engine).iter().all(|r| r.is_ok()));
+    assert_eq!(starts(&engine), vec![0x0, 0x1000, 0x2000]);
+
+    // Ring mode only keeps the two newest batches.
+    let mut engine = Engine::with_update_cap(16, 2, UpdateQueueMode::DropOldest);
+    assert!(send_three(&mut engine).iter().all(|r| r.is_ok()));
+    assert_eq!(starts(&engine), vec![0x1000, 0x2000]);
+
+    // Reject mode fails the third send and leaves the region in place.
+    let mut engine = Engine::with_update_cap(16, 2, UpdateQueueMode::Reject);
+    assert_eq!(
+        send_three(&mut engine),
+        vec![Ok(()), Ok(()), Err(CapaError::InvalidValue)]
+    );
+    assert_eq!(starts(&engine), vec![0x0, 0x1000]);
+    let mut held = false;
+    engine
+        .root
+        .borrow()
+        .data
+        .capabilities
+        .foreach_region(&mut |r| {
+            held |= r.borrow().data.access.start == 0x2000;
+            Ok(())
+        })
+        .unwrap();
+    assert!(held);
+}
+
+#[test]
+fn test_engine_full_store_leaves_tree_intact() {
+    let (mut engine, td0, r0, td0_r0) = setup_engine_with_root();
+    let alias = engine
+        .alias(td0.clone(), td0_r0, &Access::new(0x0, 0x1000, Rights::READ))
+        .unwrap();
+    let baseline = format!("{}", td0.borrow());
+
+    // No handle left, carve, alias and duplicate must not touch the tree.
+    td0.borrow_mut().data.capabilities.max_handles = 2;
+    let access = Access::new(0x1000, 0x1000, Rights::READ);
+    assert_eq!(
+        engine.carve(td0.clone(), td0_r0, &access),
+        Err(CapaError::TooManyCapabilities)
+    );
+    assert_eq!(
+        engine.alias(td0.clone(), td0_r0, &access),
+        Err(CapaError::TooManyCapabilities)
+    );
+    assert_eq!(
+        engine.duplicate(td0.clone(), alias),
+        Err(CapaError::TooManyCapabilities)
+    );
+    assert_eq!(r0.borrow().children.len(), 1);
+    assert_eq!(format!("{}", td0.borrow()), baseline);
+    assert_eq!(engine.validate_tree(), Ok(()));
+}
+
+#[test]
+fn test_engine_transfer_checks_like_send() {
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    let td0_td2 = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    // td1 gets a vital region and a plain one before being sealed.
+    for (start, attributes) in [(0x1000, Attributes::VITAL), (0x2000, Attributes::empty())] {
+        let carve = engine
+            .carve(
+                td0.clone(),
+                td0_r0,
+                &Access::new(start, 0x1000, Rights::READ | Rights::WRITE),
+            )
+            .unwrap();
+        engine
+            .send(td0.clone(), td0_td1, carve, Remapped::Identity, attributes)
+            .unwrap();
+    }
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    engine.seal(td0.clone(), td0_td2).unwrap();
+    engine.enable_audit();
+
+    // A sealed dest only accepts CLEAN, the vital region stays in td1.
+    assert_eq!(
+        engine.transfer(td0.clone(), td0_td1, 1, td0_td2),
+        Err(CapaError::CallNotAllowed)
+    );
+    assert!(engine.take_audit().is_empty());
+
+    let td2_capa = engine.transfer(td0.clone(), td0_td1, 2, td0_td2).unwrap();
+    let id = td0.borrow().data.id;
+    assert_eq!(
+        engine.take_audit(),
+        vec![AuditEntry {
+            call: CallInterface::TRANSFER,
+            domain: id,
+            args: [td0_td1, 2, td0_td2, 0, 0, 0],
+            handle: Some(td2_capa),
+        }]
+    );
+}
+
+#[test]
+fn test_engine_quota_on_carve_and_audit() {
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    engine.enable_audit();
+    let (td0_td1, td1) = {
+        let capa = engine
+            .create_with_quota(
+                &td0.clone(),
+                0x1,
+                MonitorAPI::all(),
+                InterruptPolicy::default_all(),
+                0x2000,
+            )
+            .unwrap();
+        let td1 = td0
+            .borrow()
+            .data
+            .capabilities
+            .get(&capa)
+            .unwrap()
+            .as_domain()
+            .unwrap();
+        (capa, td1)
+    };
+
+    // The quota is part of the recorded call, and replaying it restores it.
+    let audit = engine.take_audit();
+    assert_eq!(audit[0].call, CallInterface::CREATE);
+    assert_eq!(audit[0].args[2..4], [1, 0x2000]);
+    let replayed = match engine
+        .dispatch(td0.clone(), CallInterface::CREATE, &audit[0].args)
+        .unwrap()
+    {
+        ClientResult::SingleValue(capa) => capa,
+        _ => panic!("expected a handle"),
+    };
+    let replayed = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&replayed)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    assert_eq!(replayed.borrow().data.max_footprint, Some(0x2000));
+
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x0, 0x2000, Rights::all()),
+        )
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            td0_td1,
+            carve,
+            Remapped::Identity,
+            Attributes::empty(),
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+
+    // Once over quota, carving is refused as well.
+    td1.borrow_mut().data.max_footprint = Some(0x1000);
+    assert_eq!(
+        engine.carve(td1.clone(), 1, &Access::new(0x0, 0x1000, Rights::READ)),
+        Err(CapaError::QuotaExceeded)
+    );
+    td1.borrow_mut().data.max_footprint = Some(0x2000);
+    assert!(engine
+        .carve(td1.clone(), 1, &Access::new(0x0, 0x1000, Rights::READ))
+        .is_ok());
+}
+
+#[test]
+fn test_engine_set_attributes_keeps_vital() {
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let (child, child_ref) = engine
+        .create_ref(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x0, 0x1000, Rights::all()),
+        )
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            child,
+            carve,
+            Remapped::Identity,
+            Attributes::VITAL,
+        )
+        .unwrap();
+    engine.seal(td0.clone(), child).unwrap();
+
+    // The child cannot drop VITAL from the region it received.
+    assert_eq!(
+        engine.set_attributes(child_ref.clone(), 1, Attributes::empty()),
+        Err(CapaError::InvalidAttributes)
+    );
+    // Once set, VITAL stays even on a region that was never sent.
+    let own = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x1000, 0x1000, Rights::all()),
+        )
+        .unwrap();
+    engine
+        .set_attributes(td0.clone(), own, Attributes::VITAL)
+        .unwrap();
+    assert_eq!(
+        engine.set_attributes(td0.clone(), own, Attributes::empty()),
+        Err(CapaError::InvalidAttributes)
+    );
+    engine.revoke(td0.clone(), td0_r0, 0).unwrap();
+    assert_eq!(child_ref.borrow().data.status, Status::Revoked);
+}
+
+#[test]
+fn test_engine_seal_recursive_audit() {
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+    let (parent, parent_ref) = engine
+        .create_ref(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    let mut handles = Vec::new();
+    for _ in 0..2 {
+        let policies = Policies::new(0x1, MonitorAPI::all(), InterruptPolicy::default_none());
+        let child = Rc::new(RefCell::new(Capability::<Domain>::new(Domain::new(
+            policies,
+        ))));
+        handles.push(
+            parent_ref
+                .borrow_mut()
+                .data
+                .install(CapaWrapper::Domain(child))
+                .unwrap(),
+        );
+    }
+    engine.enable_audit();
+    engine.seal_recursive(td0.clone(), parent).unwrap();
+
+    // One SEAL per domain, recorded by the domain that owns it.
+    let sealed: Vec<(u64, u64)> = engine
+        .take_audit()
+        .iter()
+        .map(|e| {
+            assert_eq!(e.call, CallInterface::SEAL);
+            (e.domain, e.args[0])
+        })
+        .collect();
+    let (td0_id, parent_id) = (td0.borrow().data.id, parent_ref.borrow().data.id);
+    assert_eq!(
+        sealed,
+        vec![
+            (td0_id, parent),
+            (parent_id, handles[0]),
+            (parent_id, handles[1])
+        ]
+    );
+}
+
+#[test]
+fn test_engine_create_children_without_revoke() {
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+    let (td0_td1, td1) = engine
+        .create_ref(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all() - MonitorAPI::REVOKE,
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    td1.borrow_mut().data.capabilities.max_handles = 2;
+    engine.enable_audit();
+
+    // The batch does not fit, the error is not masked by a failed rollback.
+    assert_eq!(
+        engine.create_children(
+            &td1,
+            3,
+            0x1,
+            MonitorAPI::CREATE,
+            InterruptPolicy::default_none()
+        ),
+        Err(CapaError::TooManyCapabilities)
+    );
+    assert!(td1.borrow().children.is_empty());
+    assert!(td1.borrow().data.capabilities.capabilities.is_empty());
+    assert!(engine.take_audit().is_empty());
+
+    let children = engine
+        .create_children(
+            &td1,
+            2,
+            0x1,
+            MonitorAPI::CREATE,
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    assert_eq!(children.len(), 2);
+    assert_eq!(td1.borrow().children.len(), 2);
+}
+
+#[test]
+fn test_engine_update_cap_domain_revoke() {
+    let mut engine = Engine::with_update_cap(16, 2, UpdateQueueMode::Reject);
+    let td0 = engine.root.clone();
+    let r0 = Rc::new(RefCell::new(create_root_region()));
+    let td0_r0 = engine.add_root_region(&td0, &r0).unwrap();
+    let mut children = Vec::new();
+    for (start, attributes) in [(0x0, Attributes::empty()), (0x1000, Attributes::VITAL)] {
+        let (capa, child) = engine
+            .create_ref(
+                &td0.clone(),
+                0x1,
+                MonitorAPI::all(),
+                InterruptPolicy::default_none(),
+            )
+            .unwrap();
+        let carve = engine
+            .carve(
+                td0.clone(),
+                td0_r0,
+                &Access::new(start, 0x1000, Rights::all()),
+            )
+            .unwrap();
+        engine
+            .send(td0.clone(), capa, carve, Remapped::Identity, attributes)
+            .unwrap();
+        engine.seal(td0.clone(), capa).unwrap();
+        children.push((capa, child));
+    }
+    assert_eq!(engine.updates.len(), 2);
+
+    // A full queue rejects the domain revocation before anything changes.
+    assert_eq!(
+        engine.revoke(td0.clone(), children[0].0, 0),
+        Err(CapaError::InvalidValue)
+    );
+    assert_eq!(children[0].1.borrow().data.status, Status::Sealed);
+
+    engine.drain_updates();
+    engine.revoke(td0.clone(), children[0].0, 0).unwrap();
+    assert_eq!(children[0].1.borrow().data.status, Status::Revoked);
+    assert_eq!(engine.updates.len(), 1);
+
+    // The vital holder goes down within the batch of the region revocation.
+    engine.revoke(td0.clone(), td0_r0, 0).unwrap();
+    assert_eq!(children[1].1.borrow().data.status, Status::Revoked);
+    assert_eq!(engine.updates.len(), 2);
+}
+
+#[test]
+fn test_engine_restrict_without_carve() {
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let (td0_td1, td1) = engine
+        .create_ref(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all() - MonitorAPI::CARVE,
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    let carve = engine
+        .carve(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x0, 0x2000, Rights::all()),
+        )
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            td0_td1,
+            carve,
+            Remapped::Identity,
+            Attributes::empty(),
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    engine.enable_audit();
+
+    // Dropping rights does not need CARVE, the call is recorded.
+    engine
+        .restrict(td1.clone(), 1, Rights::READ | Rights::WRITE)
+        .unwrap();
+    let id = td1.borrow().data.id;
+    let audit = engine.take_audit();
+    assert_eq!(
+        audit,
+        vec![AuditEntry {
+            call: CallInterface::RESTRICT,
+            domain: id,
+            args: [1, (Rights::READ | Rights::WRITE).bits() as u64, 0, 0, 0, 0],
+            handle: None,
+        }]
+    );
+
+    // And it replays through dispatch.
+    let args = [1, Rights::READ.bits() as u64, 0, 0, 0, 0];
+    engine
+        .dispatch(td1.clone(), CallInterface::RESTRICT, &args)
+        .unwrap();
+    let display = format!("{}", td1.borrow());
+    assert!(display.contains("r0 = Exclusive 0x0 0x2000 with R__ mapped Identity\n"));
+}
+
+#[test]
+fn test_engine_inherit_interrupts_not_owned() {
+    let (mut engine, td0, foreign, td2) = setup_foreign_child();
+    assert_eq!(
+        engine.inherit_interrupts(td0.clone(), foreign),
+        Err(CapaError::CapaNotOwned)
+    );
+    assert_eq!(
+        td2.borrow().data.policies.interrupts,
+        InterruptPolicy::default_none()
+    );
+}
+
+#[test]
+fn test_engine_set_interrupt_range_not_owned() {
+    let (mut engine, td0, foreign, td2) = setup_foreign_child();
+    let policy = InterruptPolicy::default_all();
+    assert_eq!(
+        engine.set_interrupt_range(td0.clone(), foreign, 0, 4, policy.vectors[0]),
+        Err(CapaError::CapaNotOwned)
+    );
+    assert_eq!(
+        engine.set_interrupt_policy(td0.clone(), foreign, &policy),
+        Err(CapaError::CapaNotOwned)
+    );
+    assert_eq!(
+        td2.borrow().data.policies.interrupts,
+        InterruptPolicy::default_none()
+    );
+}
+
+#[test]
+fn test_engine_inherit_attributes_audit_replay() {
+    let mut engine = Engine::new(16);
+    let mut root_region = create_root_region();
+    root_region.data = root_region.data.with_attributes(Attributes::HASH);
+    let r0 = Rc::new(RefCell::new(root_region));
+    let td0 = engine.root.clone();
+    let td0_r0 = engine.add_root_region(&td0, &r0).unwrap();
+    engine.enable_audit();
+
+    let access = Access::new(0x1000, 0x1000, Rights::READ);
+    engine
+        .carve_with(td0.clone(), td0_r0, &access, true)
+        .unwrap();
+    engine
+        .alias_with(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x4000, 0x1000, Rights::READ),
+            false,
+        )
+        .unwrap();
+    let audit = engine.take_audit();
+    assert_eq!(audit[0].args[4], 1);
+    assert_eq!(audit[1].args[4], 0);
+
+    // Replaying the carve on a fresh engine inherits HASH again.
+    let mut replay = Engine::new(16);
+    let mut root_region = create_root_region();
+    root_region.data = root_region.data.with_attributes(Attributes::HASH);
+    let r0 = Rc::new(RefCell::new(root_region));
+    let root = replay.root.clone();
+    replay.add_root_region(&root, &r0).unwrap();
+    replay
+        .dispatch(root.clone(), audit[0].call, &audit[0].args)
+        .unwrap();
+    assert_eq!(
+        r0.borrow().children[0].borrow().data.attributes,
+        Attributes::HASH
+    );
+}
+
+#[test]
+fn test_engine_promote_needs_every_ancestor() {
+    let (mut engine, td0, r0, td0_r0) = setup_engine_with_root();
+    engine.enable_updates();
+    let rw = Rights::READ | Rights::WRITE;
+    let (td0_td1, td1) = engine
+        .create_ref(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    let sent = engine
+        .carve(td0.clone(), td0_r0, &Access::new(0x8000, 0x8000, rw))
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            td0_td1,
+            sent,
+            Remapped::Identity,
+            Attributes::empty(),
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+
+    // The child owns its carve but not the regions above it.
+    let td1_c = engine
+        .carve(td1.clone(), 1, &Access::new(0xc000, 0x4000, rw))
+        .unwrap();
+    assert_eq!(
+        engine.promote(td1.clone(), td1_c),
+        Err(CapaError::CapaNotOwned)
+    );
+    assert_eq!(
+        r0.borrow().data.access,
+        Access::new(0x0, 0x10000, Rights::all())
+    );
+    engine.revoke(td0.clone(), td0_r0, 0).unwrap();
+    assert!(td1.borrow().data.capabilities.capabilities.is_empty());
+
+    // The owner of the whole chain can, and every ancestor reports the loss.
+    engine.drain_updates();
+    let td0_a = engine
+        .carve(td0.clone(), td0_r0, &Access::new(0x8000, 0x8000, rw))
+        .unwrap();
+    let td0_b = engine
+        .carve(td0.clone(), td0_a, &Access::new(0xc000, 0x4000, rw))
+        .unwrap();
+    engine.drain_updates();
+    engine.promote(td0.clone(), td0_b).unwrap();
+    let updates = engine.drain_updates();
+    assert_eq!(updates.len(), 1);
+    let lost: Vec<Rights> = updates[0]
+        .iter()
+        .map(|u| match u {
+            Update::ChangeMemory { range, rights, .. } => {
+                assert_eq!(*range, Access::new(0xc000, 0x4000, rw));
+                *rights
+            }
+            _ => panic!("expected a memory change"),
+        })
+        .collect();
+    assert_eq!(lost, vec![rw, Rights::all()]);
+}
+
+#[test]
+fn test_engine_reclaim_audit() {
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let td0_td1 = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_all(),
+        )
+        .unwrap();
+    engine.seal(td0.clone(), td0_td1).unwrap();
+    let send_carve = |engine: &mut Engine| {
+        let access = Access::new(0x0, 0x1000, Rights::all());
+        let carve = engine.carve(td0.clone(), td0_r0, &access).unwrap();
+        engine
+            .send(
+                td0.clone(),
+                td0_td1,
+                carve,
+                Remapped::Identity,
+                Attributes::empty(),
+            )
+            .unwrap();
+    };
+    send_carve(&mut engine);
+    engine.enable_audit();
+
+    // Reclaims show up in the audit log with the child index.
+    let reclaimed = engine.reclaim(td0.clone(), td0_r0, 0).unwrap();
+    let id = td0.borrow().data.id;
+    let audit = engine.take_audit();
+    assert_eq!(
+        audit,
+        vec![AuditEntry {
+            call: CallInterface::RECLAIM,
+            domain: id,
+            args: [td0_r0, 0, 0, 0, 0, 0],
+            handle: Some(reclaimed),
+        }]
+    );
+
+    // And replay through dispatch.
+    engine.revoke(td0.clone(), td0_r0, 0).unwrap();
+    send_carve(&mut engine);
+    let replayed = engine
+        .dispatch(td0.clone(), CallInterface::RECLAIM, &audit[0].args)
+        .unwrap();
+    assert!(matches!(replayed, ClientResult::SingleValue(_)));
+    assert_eq!(td0.borrow().children[0].borrow().view().unwrap(), vec![]);
+}
+
+#[test]
+fn test_engine_audit_replays_every_mutation() {
+    // Every mutation below is logged, including those outside EngineInterface.
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    engine.enable_audit();
+    let child = engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    engine.inherit_interrupts(td0.clone(), child).unwrap();
+    let alias = engine
+        .alias(
+            td0.clone(),
+            td0_r0,
+            &Access::new(0x8000, 0x1000, Rights::READ),
+        )
+        .unwrap();
+    engine.duplicate(td0.clone(), alias).unwrap();
+    let rw = Rights::READ | Rights::WRITE;
+    let carve = engine
+        .carve(td0.clone(), td0_r0, &Access::new(0x0, 0x2000, rw))
+        .unwrap();
+    engine
+        .set_attributes(td0.clone(), carve, Attributes::HASH)
+        .unwrap();
+    let inner = engine
+        .carve(td0.clone(), carve, &Access::new(0x0, 0x1000, rw))
+        .unwrap();
+    engine.promote(td0.clone(), inner).unwrap();
+    engine
+        .create(
+            &td0.clone(),
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    engine.revoke_all(td0.clone()).unwrap();
+
+    let audit = engine.take_audit();
+    let calls: Vec<CallInterface> = audit.iter().map(|e| e.call).collect();
+    assert_eq!(
+        calls,
+        vec![
+            CallInterface::CREATE,
+            CallInterface::INHERIT,
+            CallInterface::ALIAS,
+            CallInterface::DUPLICATE,
+            CallInterface::CARVE,
+            CallInterface::ATTRIBUTES,
+            CallInterface::CARVE,
+            CallInterface::PROMOTE,
+            CallInterface::CREATE,
+            CallInterface::REVOKE,
+            CallInterface::REVOKE,
+        ]
+    );
+
+    // Replaying the log rebuilds the same tree.
+    let (mut replay, replay_td0, _, _) = setup_engine_with_root();
+    for entry in audit.iter() {
+        replay
+            .dispatch(replay_td0.clone(), entry.call, &entry.args)
+            .unwrap();
+    }
+    assert_eq!(
+        format!("{}", replay_td0.borrow()),
+        format!("{}", td0.borrow())
+    );
+}
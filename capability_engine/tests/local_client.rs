@@ -1,11 +1,12 @@
-use capa_engine::client::engine::Engine;
+use capa_engine::client::engine::{ClientError, Engine};
 use capa_engine::client::local_client::LocalClient;
 use capa_engine::core::capability::*;
 use capa_engine::core::domain::*;
 use capa_engine::core::memory_region::{
     Access, Attributes, MemoryRegion, RegionKind, Remapped, Rights, Status as MStatus,
 };
-use capa_engine::EngineInterface;
+use capa_engine::server::engine::Engine as SEngine;
+use capa_engine::{CallInterface, EngineInterface};
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -94,12 +95,7 @@ fn test_client_alias() {
 
     let r0 = client.find_region(|_x| true).unwrap();
     let r1 = client
-        .r_alias(
-            &r0.clone(),
-            0x0,
-            0x1000,
-            (Rights::READ | Rights::WRITE).bits(),
-        )
+        .r_alias(&r0.clone(), 0x0, 0x1000, Rights::READ | Rights::WRITE)
         .unwrap();
 
     // Check we have the right attestations.
@@ -132,12 +128,7 @@ fn test_client_carve() {
     // We have only one region.
     let r0 = client.find_region(|_x| true).unwrap();
     let r1 = client
-        .r_carve(
-            &r0.clone(),
-            0x0,
-            0x1000,
-            (Rights::READ | Rights::WRITE).bits(),
-        )
+        .r_carve(&r0.clone(), 0x0, 0x1000, Rights::READ | Rights::WRITE)
         .unwrap();
 
     // Check we have the right attestations.
@@ -170,16 +161,11 @@ fn test_client_child_alias_carve() {
     // We have only one region.
     let r0 = client.find_region(|_x| true).unwrap();
     let r1 = client
-        .r_carve(&r0.clone(), 0x0, 0x1000, Rights::all().bits())
+        .r_carve(&r0.clone(), 0x0, 0x1000, Rights::all())
         .unwrap();
 
     let r2 = client
-        .r_alias(
-            &r0.clone(),
-            0x1000,
-            0x1000,
-            (Rights::READ | Rights::WRITE).bits(),
-        )
+        .r_alias(&r0.clone(), 0x1000, 0x1000, Rights::READ | Rights::WRITE)
         .unwrap();
 
     let td1 = client
@@ -241,26 +227,16 @@ fn test_client_multiple_children() {
 
     let r0 = client.find_region(|_| true).unwrap();
     let r1 = client
-        .r_carve(&r0.clone(), 0x0, 0x1000, Rights::all().bits())
+        .r_carve(&r0.clone(), 0x0, 0x1000, Rights::all())
         .unwrap();
     let r2 = client
-        .r_alias(
-            &r0.clone(),
-            0x1000,
-            0x1000,
-            (Rights::READ | Rights::WRITE).bits(),
-        )
+        .r_alias(&r0.clone(), 0x1000, 0x1000, Rights::READ | Rights::WRITE)
         .unwrap();
     let r3 = client
-        .r_alias(
-            &r0.clone(),
-            0x1000,
-            0x1000,
-            (Rights::READ | Rights::WRITE).bits(),
-        )
+        .r_alias(&r0.clone(), 0x1000, 0x1000, Rights::READ | Rights::WRITE)
         .unwrap();
     let r4 = client
-        .r_carve(&r0.clone(), 0x2000, 0x1000, Rights::all().bits())
+        .r_carve(&r0.clone(), 0x2000, 0x1000, Rights::all())
         .unwrap();
 
     let td1 = client
@@ -317,10 +293,10 @@ r4 = Exclusive 0x2000 0x3000 with RWX mapped Identity
 
     // Now do something weird.
     let r5 = client
-        .r_alias(&r0.clone(), 0x5000, 0x1000, Rights::all().bits())
+        .r_alias(&r0.clone(), 0x5000, 0x1000, Rights::all())
         .unwrap();
     let r6 = client
-        .r_alias(&r5.clone(), 0x5000, 0x1000, Rights::all().bits())
+        .r_alias(&r5.clone(), 0x5000, 0x1000, Rights::all())
         .unwrap();
 
     client
@@ -518,7 +494,7 @@ fn test_client_100_children() {
             .r_create(0x1, MonitorAPI::empty(), InterruptPolicy::default_none())
             .unwrap();
         let r = client
-            .r_alias(&r0.clone(), 0x2000, 0x1000, Rights::all().bits())
+            .r_alias(&r0.clone(), 0x2000, 0x1000, Rights::all())
             .unwrap();
         client
             .r_send(&child.clone(), &r, Remapped::Identity, Attributes::empty())
@@ -576,3 +552,314 @@ r0 = Exclusive 0x0 0x10000 with RWX mapped Identity
     let attestation = client.r_attest(None).unwrap();
     assert_eq!(attestation, expected);
 }
+
+#[test]
+fn test_client_switch() {
+    let mut client = setup();
+
+    let child_td = client
+        .r_create(0x1, MonitorAPI::all(), InterruptPolicy::default_none())
+        .unwrap();
+    let local = child_td.borrow().owned.handle;
+
+    // Cannot switch to an unsealed domain.
+    assert!(client.switch(client.current.clone(), local).is_err());
+
+    client.r_seal(&child_td).unwrap();
+    client.switch(client.current.clone(), local).unwrap();
+    assert!(Rc::ptr_eq(&client.current, &child_td));
+
+    // Both sides now act on behalf of the child.
+    let attestation = client.r_attest(None).unwrap();
+    let expected = r#"td0 = Sealed domain()
+|cores: 0x1
+|mon.api: 0x1fff
+|vec0-255: NOT REPORTED, r: 0xffffffffffffffff, w: 0xffffffffffffffff
+"#;
+    assert_eq!(attestation, expected);
+    assert_eq!(format!("{}", client.current.borrow()), expected);
+}
+
+#[test]
+fn test_client_enumerate() {
+    let mut client = setup();
+
+    let r0 = client.find_region(|_x| true).unwrap();
+    let enumeration = client.r_enumerate(&r0).unwrap();
+    assert_eq!(
+        enumeration,
+        "Exclusive 0x0 0x10000 with RWX mapped Identity"
+    );
+
+    let r1 = client
+        .r_carve(&r0, 0x0, 0x1000, Rights::READ | Rights::WRITE)
+        .unwrap();
+    let enumeration = client.r_enumerate(&r1).unwrap();
+    assert_eq!(enumeration, "Exclusive 0x0 0x1000 with RW_ mapped Identity");
+
+    let child_td = client
+        .r_create(0x1, MonitorAPI::all(), InterruptPolicy::default_none())
+        .unwrap();
+    let enumeration = client.r_enumerate_domain(&child_td).unwrap();
+    assert!(enumeration.starts_with("td0 = Unsealed domain()\n|cores: 0x1\n"));
+
+    // A stale handle is reported by the server.
+    client.r_revoke_region(&r1).unwrap();
+    let err = client.r_enumerate(&r1);
+    assert!(matches!(
+        err,
+        Err(ClientError::CapaError(CapaError::InvalidLocalCapa))
+    ));
+}
+
+#[test]
+fn test_client_resync() {
+    let mut client = setup();
+    let r0 = client.find_region(|_x| true).unwrap();
+    let r1 = client
+        .r_carve(&r0, 0x0, 0x1000, Rights::READ | Rights::WRITE)
+        .unwrap();
+    let child = client
+        .r_create(0x1, MonitorAPI::all(), InterruptPolicy::default_none())
+        .unwrap();
+    client
+        .r_send(&child, &r1, Remapped::Identity, Attributes::empty())
+        .unwrap();
+
+    // Corrupt the local tree by dropping the root region.
+    let handle = r0.borrow().owned.handle;
+    client.current.borrow_mut().data.remove(handle).unwrap();
+    assert_ne!(
+        format!("{}", client.current.borrow()),
+        client.r_attest(None).unwrap()
+    );
+
+    client.resync().unwrap();
+    assert_eq!(
+        format!("{}", client.current.borrow()),
+        client.r_attest(None).unwrap()
+    );
+    assert!(client.find_child(|_c| true).is_some());
+}
+
+#[test]
+fn test_client_error_display() {
+    use std::error::Error;
+
+    assert_eq!(
+        format!("{}", CapaError::InsufficientRights),
+        "insufficient rights"
+    );
+    let err = ClientError::CapaError(CapaError::InsufficientRights);
+    assert_eq!(format!("{}", err), "capability error: insufficient rights");
+    let source = err.source().unwrap();
+    assert_eq!(
+        source.downcast_ref::<CapaError>(),
+        Some(&CapaError::InsufficientRights)
+    );
+    assert!(ClientError::FailedSeal.source().is_none());
+
+    // Usable with boxed errors.
+    let boxed: Box<dyn Error> = Box::new(CapaError::CarveOfAlias);
+    assert_eq!(boxed.to_string(), "cannot carve an aliased region");
+}
+
+// Every call, in discriminant order.
+const CALLS: [CallInterface; 19] = [
+    CallInterface::CREATE,
+    CallInterface::SET,
+    CallInterface::GET,
+    CallInterface::SEAL,
+    CallInterface::ATTEST,
+    CallInterface::ENUMERATE,
+    CallInterface::SWITCH,
+    CallInterface::ALIAS,
+    CallInterface::CARVE,
+    CallInterface::REVOKE,
+    CallInterface::SEND,
+    CallInterface::INTERRUPTS,
+    CallInterface::TRANSFER,
+    CallInterface::RESTRICT,
+    CallInterface::RECLAIM,
+    CallInterface::DUPLICATE,
+    CallInterface::PROMOTE,
+    CallInterface::ATTRIBUTES,
+    CallInterface::INHERIT,
+];
+
+#[test]
+fn test_decode_call_and_field() {
+    for c in CALLS {
+        assert_eq!(CallInterface::try_from(c as u64), Ok(c));
+    }
+    let last = CALLS[CALLS.len() - 1].as_u8() as u64;
+    assert_eq!(CallInterface::try_from(0), Err(CapaError::InvalidValue));
+    assert_eq!(
+        CallInterface::try_from(last + 1),
+        Err(CapaError::InvalidValue)
+    );
+
+    let fields = [
+        FieldType::Register,
+        FieldType::Cores,
+        FieldType::Api,
+        FieldType::InterruptVisibility,
+        FieldType::InterruptRead,
+        FieldType::InterruptWrite,
+    ];
+    for f in fields {
+        assert_eq!(FieldType::try_from(f as u64), Ok(f));
+    }
+    assert_eq!(FieldType::try_from(7), Err(CapaError::InvalidValue));
+    assert_eq!(FieldType::try_from(u64::MAX), Err(CapaError::InvalidValue));
+}
+
+#[test]
+fn test_call_interface_display() {
+    assert_eq!(format!("{}", CallInterface::CARVE), "carve");
+    assert_eq!(CallInterface::INTERRUPTS.to_string(), "interrupts");
+}
+
+#[test]
+fn test_call_interface_order() {
+    let mut sorted = vec![
+        CallInterface::GET,
+        CallInterface::CREATE,
+        CallInterface::SET,
+    ];
+    sorted.sort();
+    assert_eq!(
+        sorted,
+        vec![
+            CallInterface::CREATE,
+            CallInterface::SET,
+            CallInterface::GET
+        ]
+    );
+    assert!(CALLS.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(CallInterface::INTERRUPTS.as_u8(), 12);
+}
+
+#[test]
+fn test_client_invalid_rights() {
+    let mut client = setup();
+    let r0 = client.find_region(|_x| true).unwrap();
+
+    // A bit outside READ|WRITE|EXECUTE must not turn into an empty region.
+    let bogus = Rights::from_bits_retain(0b1000);
+    let err = client.r_alias(&r0, 0x0, 0x1000, bogus);
+    assert!(matches!(
+        err,
+        Err(ClientError::CapaError(CapaError::InvalidValue))
+    ));
+    let err = client.r_carve(&r0, 0x0, 0x1000, bogus | Rights::READ);
+    assert!(matches!(
+        err,
+        Err(ClientError::CapaError(CapaError::InvalidValue))
+    ));
+    assert_eq!(r0.borrow().children.len(), 0);
+}
+
+#[test]
+fn test_client_from_engine() {
+    let mut server = SEngine::new(16);
+    let root = server.root.clone();
+    let root_region = Rc::new(RefCell::new(create_root_region()));
+    let r0 = server.add_root_region(&root, &root_region).unwrap();
+    server
+        .carve(root.clone(), r0, &Access::new(0x0, 0x1000, Rights::READ))
+        .unwrap();
+    server
+        .create(
+            &root,
+            0x1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    let expected = server.attest(root.clone(), None).unwrap();
+
+    // No need to add the root region on both sides.
+    let platform = LocalClient::from_engine(server, root);
+    let mut client = Engine::attach(platform).unwrap();
+    assert_eq!(client.r_attest(None).unwrap(), expected);
+    assert_eq!(format!("{}", client.current.borrow()), expected);
+}
+
+#[test]
+fn test_client_error_from_capa_error() {
+    let mut client = setup();
+    let r0 = client.find_region(|_x| true).unwrap();
+    let r1 = client
+        .r_carve(&r0, 0x0, 0x1000, Rights::READ | Rights::WRITE)
+        .unwrap();
+
+    // The server refuses the revocation, the client forwards the reason.
+    client
+        .platform
+        .current
+        .borrow_mut()
+        .data
+        .policies
+        .api
+        .remove(MonitorAPI::REVOKE);
+    let err = client.r_revoke_region(&r1).unwrap_err();
+    assert!(matches!(
+        err,
+        ClientError::CapaError(CapaError::CallNotAllowed)
+    ));
+    let source = std::error::Error::source(&err).unwrap();
+    assert_eq!(
+        source.downcast_ref::<CapaError>(),
+        Some(&CapaError::CallNotAllowed)
+    );
+    assert!(matches!(
+        ClientError::from(CapaError::InvalidValue),
+        ClientError::CapaError(CapaError::InvalidValue)
+    ));
+}
+
+#[test]
+fn test_client_set_interrupts_bulk() {
+    let mut client = setup();
+    let child = client
+        .r_create(0x1, MonitorAPI::all(), InterruptPolicy::default_none())
+        .unwrap();
+
+    let mut policy = InterruptPolicy::default_none();
+    let all = InterruptPolicy::default_all().vectors[0];
+    policy.vectors[0..32].fill(all);
+    policy.vectors[32..64].fill(VectorPolicy {
+        visibility: VectorVisibility::ALLOWED,
+        read_set: 0xff,
+        write_set: !0,
+    });
+    policy.vectors[200] = all;
+
+    client.platform.server.enable_audit();
+    let calls = client.r_set_interrupts(&child, &policy).unwrap();
+    // One call per run instead of three per vector.
+    assert_eq!(calls, 5);
+    assert_eq!(client.platform.server.take_audit().len(), calls);
+    assert!(calls * 100 < 3 * NB_INTERRUPTS);
+
+    let handle = child.borrow().owned.handle;
+    let remote = client
+        .platform
+        .current
+        .borrow()
+        .data
+        .capabilities
+        .get(&handle)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    assert_eq!(remote.borrow().data.policies.interrupts, policy);
+    assert_eq!(child.borrow().data.policies.interrupts, policy);
+
+    client.r_seal(&child).unwrap();
+    assert!(matches!(
+        client.r_set_interrupts(&child, &policy),
+        Err(ClientError::CapaError(CapaError::DomainSealed))
+    ));
+}
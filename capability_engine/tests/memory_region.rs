@@ -1,5 +1,6 @@
 use capa_engine::core::capability::*;
 use capa_engine::core::memory_region::*;
+use capa_engine::core::update::{OperationUpdate, Update};
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -91,6 +92,18 @@ fn test_alias_with_extra_rights_rejected() {
     assert_eq!(result, Err(CapaError::InvalidAccess));
 }
 
+#[test]
+fn test_carve_with_extra_rights_rejected() {
+    let mut root = create_root();
+    root.data.access.rights = Rights::READ;
+
+    assert!(Rights::READ.is_subset_of(&root.data.access.rights));
+    assert!(!Rights::WRITE.is_subset_of(&root.data.access.rights));
+    let access = Access::new(0x1000, 0x1000, Rights::WRITE);
+    assert_eq!(root.carve(&access), Err(CapaError::InvalidAccess));
+    assert!(root.children.is_empty());
+}
+
 // ——————————————————————————— Nested operations ———————————————————————————— //
 
 #[test]
@@ -208,19 +221,11 @@ fn test_carve_then_alias_then_carve() {
         assert_eq!(alias_borrow.data.status, Status::Aliased);
     }
 
-    // Step 3: Carve from the alias
+    // Step 3: Carve from the alias, shared memory cannot be made exclusive.
     let carve_from_alias_access = Access::new(0x2000, 0x0800, Rights::READ);
-    let carved_from_alias = alias
-        .borrow_mut()
-        .carve(&carve_from_alias_access)
-        .expect("Carving from alias failed");
-
-    // Check carve kind and status
-    {
-        let carved_borrow = carved_from_alias.borrow();
-        assert_eq!(carved_borrow.data.kind, RegionKind::Carve);
-        assert_eq!(carved_borrow.data.status, Status::Aliased); // inherits from parent
-    }
+    let carved_from_alias = alias.borrow_mut().carve(&carve_from_alias_access);
+    assert_eq!(carved_from_alias.err(), Some(CapaError::CarveOfAlias));
+    assert_eq!(alias.borrow().children.len(), 0);
 }
 
 // ——————————————————————————————— Revocation ——————————————————————————————— //
@@ -267,7 +272,7 @@ fn test_revoke_complex_subtree() {
         .unwrap();
     let _b1a1 = b1a
         .borrow_mut()
-        .carve(&Access::new(0x1000, 0x0800, Rights::READ))
+        .alias(&Access::new(0x1800, 0x0800, Rights::READ))
         .unwrap();
 
     // Branch 2 (will not be revoked)
@@ -294,7 +299,7 @@ fn test_revoke_complex_subtree() {
     // Check the callback was called for b1a1 then b1a
     assert!(revoked.is_ok());
     assert_eq!(seen.len(), 2);
-    assert!(seen.contains(&(RegionKind::Carve, Status::Aliased, 0x1000)));
+    assert!(seen.contains(&(RegionKind::Alias, Status::Aliased, 0x1800)));
     assert!(seen.contains(&(RegionKind::Alias, Status::Aliased, 0x1000)));
 
     // b1 should still be there, but now empty
@@ -326,7 +331,7 @@ fn test_revoke_deep_leaf_order() {
         .unwrap();
     let _c4 = c3
         .borrow_mut()
-        .carve(&Access::new(0x2000, 0x0800, Rights::READ))
+        .alias(&Access::new(0x2000, 0x0800, Rights::READ))
         .unwrap();
 
     let mut seen = Vec::new();
@@ -380,3 +385,202 @@ fn test_revoke_nonexistent() {
         .any(|c| c.borrow().data.access.start == 0x0000);
     assert!(valid_region_found);
 }
+
+#[test]
+fn test_carve_of_alias_rejected() {
+    let mut root = create_root();
+    let alias = root
+        .alias(&Access::new(0x1000, 0x4000, Rights::READ | Rights::WRITE))
+        .unwrap();
+
+    let carve = alias
+        .borrow_mut()
+        .carve(&Access::new(0x1000, 0x1000, Rights::READ));
+    assert_eq!(carve.err(), Some(CapaError::CarveOfAlias));
+
+    // Aliasing an alias is still allowed.
+    let nested = alias
+        .borrow_mut()
+        .alias(&Access::new(0x1000, 0x1000, Rights::READ));
+    assert!(nested.is_ok());
+}
+
+#[test]
+fn test_revoke_partial_rights_delta() {
+    let root = Rc::new(RefCell::new(create_root()));
+    let child = root
+        .borrow_mut()
+        .carve(&Access::new(0x2000, 0x1000, Rights::READ))
+        .unwrap();
+    child.borrow_mut().parent = Rc::downgrade(&root);
+
+    let mut updates = OperationUpdate::new();
+    child.borrow().on_revoke(&mut updates).unwrap();
+
+    // One change for the owner of the carve, one for the parent's owner.
+    assert_eq!(updates.changes.len(), 2);
+    for u in &updates.changes {
+        match u {
+            Update::ChangeMemory { range, rights, .. } => {
+                assert_eq!(range.start, 0x2000);
+                assert_eq!(range.size, 0x1000);
+                assert_eq!(*rights, Rights::READ);
+            }
+            _ => panic!("Unexpected update"),
+        }
+    }
+}
+
+#[test]
+fn test_carve_overflowing_access_rejected() {
+    let mut root = create_root();
+    let wrapping = Access::new(u64::MAX - 0x100, 0x1000, Rights::READ);
+    assert!(!wrapping.is_valid());
+    assert_eq!(wrapping.end(), u64::MAX);
+    assert_eq!(root.carve(&wrapping).err(), Some(CapaError::InvalidAccess));
+    assert_eq!(root.alias(&wrapping).err(), Some(CapaError::InvalidAccess));
+
+    // Empty accesses are rejected too.
+    let empty = Access::new(0x1000, 0, Rights::READ);
+    assert!(!empty.is_valid());
+    assert_eq!(root.carve(&empty).err(), Some(CapaError::InvalidAccess));
+}
+
+#[test]
+fn test_dfs_deep_chain() {
+    const DEPTH: usize = 10_000;
+    let root = Rc::new(RefCell::new(create_root()));
+    let access = Access::new(0x0, 0x1000, Rights::READ);
+    let mut current = root.clone();
+    for _ in 0..DEPTH {
+        let next = current.borrow_mut().carve(&access).unwrap();
+        current = next;
+    }
+
+    let mut count = 0;
+    root.borrow()
+        .dfs(&mut |_c: &Capability<MemoryRegion>| {
+            count += 1;
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(count, DEPTH + 1);
+
+    // Detach children one level at a time, dropping the chain at once would recurse as deep.
+    drop(current);
+    let mut nodes = vec![root];
+    while let Some(node) = nodes.pop() {
+        nodes.append(&mut node.borrow_mut().children);
+    }
+}
+
+#[test]
+fn test_status_never_upgrades_from_alias() {
+    let mut root = create_root();
+    let alias = root
+        .alias(&Access::new(0x0, 0x8000, Rights::all()))
+        .unwrap();
+
+    // Try every alias/carve chain of up to 4 steps, halving the range each time.
+    for mask in 0..(1u32 << 4) {
+        let mut current = alias.clone();
+        for step in 0..4 {
+            let size = 0x8000 >> (step + 1);
+            let access = Access::new(0x0, size, Rights::READ);
+            // Carves of an alias are rejected, alias chains stay aliased.
+            if mask & (1 << step) != 0 {
+                let carve = current.borrow_mut().carve(&access);
+                assert_eq!(carve.unwrap_err(), CapaError::CarveOfAlias);
+                break;
+            }
+            let next = current.borrow_mut().alias(&access).unwrap();
+            assert_eq!(next.borrow().data.status, Status::Aliased);
+            current = next;
+        }
+    }
+}
+
+#[test]
+fn test_same_capa_identity() {
+    let a = Rc::new(RefCell::new(create_root()));
+    let b = Rc::new(RefCell::new(create_root()));
+    // Structurally equal but distinct capabilities.
+    assert!(*a.borrow() == *b.borrow());
+    assert!(!same_capa(&a, &b));
+    assert!(same_capa(&a, &a.clone()));
+}
+
+#[test]
+fn test_view_with_aliases() {
+    let mut root = create_root();
+    let rwx = Rights::READ | Rights::WRITE | Rights::EXECUTE;
+    root.carve(&Access::new(0x2000, 0x1000, rwx)).unwrap();
+    root.alias(&Access::new(0x4000, 0x2000, Rights::READ))
+        .unwrap();
+
+    let view = |start, size| ViewRegion::new(Access::new(start, size, rwx), Remapped::Identity);
+    // The carve is removed, the alias does not change the plain view.
+    assert_eq!(root.view(), vec![view(0x0, 0x2000), view(0x3000, 0xd000)]);
+    // The aliased range is kept but stands on its own, flagged as aliased.
+    assert_eq!(
+        root.view_with_aliases(),
+        vec![
+            (view(0x0, 0x2000), false),
+            (view(0x3000, 0x1000), false),
+            (view(0x4000, 0x2000), true),
+            (view(0x6000, 0xa000), false),
+        ]
+    );
+}
+
+#[test]
+fn test_region_contains_gva() {
+    let mut root = create_root();
+    root.data.remapped = Remapped::Remapped(0x30000);
+    root.carve(&Access::new(0x4000, 0x2000, Rights::READ))
+        .unwrap();
+
+    // Solid parts before and after the hole.
+    assert!(root.contains_gva(0x30000, 0x4000));
+    assert!(root.contains_gva(0x36000, 0xa000));
+    // Over the hole, or partially outside of the region.
+    assert!(!root.contains_gva(0x33000, 0x2000));
+    assert!(!root.contains_gva(0x30000, 0x10000));
+    assert!(!root.contains_gva(0x3f000, 0x2000));
+    // Physical addresses are not guest addresses here.
+    assert!(!root.contains_gva(0x0, 0x1000));
+}
+
+#[test]
+fn test_revoke_coalesces_cleans() {
+    let root = Rc::new(RefCell::new(create_root()));
+    let parent = root
+        .borrow_mut()
+        .carve(&Access::new(0x2000, 0x4000, Rights::READ))
+        .unwrap();
+    parent.borrow_mut().parent = Rc::downgrade(&root);
+    for start in [0x2000, 0x3000] {
+        let child = parent
+            .borrow_mut()
+            .carve(&Access::new(start, 0x1000, Rights::READ))
+            .unwrap();
+        child.borrow_mut().parent = Rc::downgrade(&parent);
+        child.borrow_mut().data.attributes = Attributes::CLEAN;
+    }
+
+    let mut updates = OperationUpdate::new();
+    parent.borrow().on_revoke(&mut updates).unwrap();
+    assert_eq!(updates.to_clean.len(), 2);
+    updates.compute().unwrap();
+
+    let cleans: Vec<(u64, u64)> = updates
+        .take_updates()
+        .iter()
+        .filter_map(|u| match u {
+            Update::Clean { start, size } => Some((*start, *size)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(cleans, vec![(0x2000, 0x2000)]);
+    assert!(updates.take_updates().is_empty());
+}
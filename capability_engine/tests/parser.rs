@@ -509,3 +509,136 @@ r0 = Exclusive 0x0 0x10000 with RWX mapped Identity
 "#;
     assert_eq!(attestation, expected);
 }
+
+#[test]
+fn test_invalid_indices() {
+    let header = r#"td0 = Sealed domain(r0)
+|cores: 0xffff
+|mon.api: 0x1fff
+|vec0-255: ALLOWED|VISIBLE, r: 0x0, w: 0x0
+r0 = Exclusive 0x0 0x10000 with RWX mapped Identity
+"#;
+    let invalid = [
+        // Missing the arrow.
+        "|indices: 1r0",
+        // Non numeric handle.
+        "|indices: a->r0",
+        // Negative handle.
+        "|indices: -1->r0",
+        // Dangling name.
+        "|indices: 1->r0 2->r7",
+        "|indices: 1->r0 2->td3",
+        // Not a capability name.
+        "|indices: 1->x0",
+    ];
+    for line in invalid.iter() {
+        let mut parser = Parser::new();
+        let attestation = format!("{}{}\n", header, line);
+        assert_eq!(
+            parser.parse_attestation(attestation),
+            Err(CapaError::ParserCapability),
+            "{}",
+            line
+        );
+    }
+
+    // Malformed vectors do not panic either.
+    let invalid_vectors = [
+        "|vec0-256: ALLOWED|VISIBLE, r: 0x0, w: 0x0",
+        "|vec5-2: ALLOWED|VISIBLE, r: 0x0, w: 0x0",
+        "|vec0-255 ALLOWED|VISIBLE, r: 0x0, w: 0x0",
+        "|vec0-255: ALLOWED|VISIBLE, r: 0xz, w: 0x0",
+    ];
+    for line in invalid_vectors.iter() {
+        let mut policy = InterruptPolicy::default_none();
+        assert!(policy.parse_one(line.to_string()).is_err(), "{}", line);
+    }
+
+    // A well formed one still parses.
+    let mut parser = Parser::new();
+    let attestation = format!("{}|indices: 1->r0\n", header);
+    parser.parse_attestation(attestation).unwrap();
+}
+
+#[test]
+fn test_rights_from_display() {
+    assert_eq!(Rights::from_display("RWX"), Ok(Rights::all()));
+    assert_eq!(
+        Rights::from_display("R_X"),
+        Ok(Rights::READ | Rights::EXECUTE)
+    );
+    assert_eq!(Rights::from_display("___"), Ok(Rights::empty()));
+    assert_eq!(Rights::from_display("RW"), Err(CapaError::ParserRegion));
+    assert_eq!(Rights::from_display("RWXY"), Err(CapaError::ParserRegion));
+    // Letters must be in their position.
+    assert_eq!(Rights::from_display("WR_"), Err(CapaError::ParserRegion));
+
+    // Write no longer implies execute.
+    assert_eq!(Parser::parse_rights("_W_"), Ok(Rights::WRITE));
+    for r in [Rights::READ, Rights::WRITE | Rights::EXECUTE, Rights::all()] {
+        assert_eq!(Rights::from_display(&format!("{}", r)), Ok(r));
+    }
+}
+
+#[test]
+fn test_parse_signed_attestation() {
+    let (mut engine, td0, _r0, _td0_r0) = setup_engine_with_root();
+    let plain = engine.attest(td0.clone(), None).unwrap();
+    let signed = engine.attest_signed(td0.clone(), None).unwrap();
+    assert!(signed.starts_with(&plain));
+    assert!(signed.lines().last().unwrap().starts_with("|checksum: 0x"));
+
+    let mut parser = Parser::new();
+    parser.parse_signed_attestation(signed.clone()).unwrap();
+    let reconstructed = parser.domains.get("td0").unwrap();
+    assert_eq!(format!("{}", reconstructed.borrow()), plain);
+
+    // Flip a single byte of the body.
+    let tampered = signed.replacen("RWX", "RWx", 1);
+    assert_ne!(tampered, signed);
+    let mut parser = Parser::new();
+    assert_eq!(
+        parser.parse_signed_attestation(tampered),
+        Err(CapaError::ParserCapability)
+    );
+
+    // Unsigned attestations are parsed as before.
+    let mut parser = Parser::new();
+    parser.parse_signed_attestation(plain).unwrap();
+}
+
+#[test]
+fn test_parse_remapped() {
+    assert_eq!(
+        Parser::parse_remapped("Remapped(0x2000)"),
+        Ok(Remapped::Remapped(0x2000))
+    );
+    assert_eq!(
+        Parser::parse_remapped(&format!("{}", Remapped::Remapped(0x2000))),
+        Ok(Remapped::Remapped(0x2000))
+    );
+    assert_eq!(
+        Parser::parse_remapped("Remapped(0x2000) "),
+        Ok(Remapped::Remapped(0x2000))
+    );
+    assert_eq!(Parser::parse_remapped(" Identity "), Ok(Remapped::Identity));
+
+    let malformed = [
+        "Remapped(0x2000",
+        "Remapped0x2000)",
+        "Remapped(2000)",
+        "Remapped(0x2000)x",
+        "NotRemapped(0x2000)",
+        "Remapped(0x)",
+        "Identityx",
+        ")Remapped(",
+    ];
+    for input in malformed {
+        assert_eq!(
+            Parser::parse_remapped(input),
+            Err(CapaError::ParserRegion),
+            "{}",
+            input
+        );
+    }
+}
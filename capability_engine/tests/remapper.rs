@@ -221,8 +221,8 @@ fn test_remap_illegal_in_hole() {
         .unwrap();
 
     // Create a region and send it with a remap
-    let alias_access = Access::new(0x0, 0x3000, Rights::all());
-    let alias = engine.alias(td0.clone(), td0_r0, &alias_access).unwrap();
+    let carve_access = Access::new(0x0, 0x3000, Rights::all());
+    let carved = engine.carve(td0.clone(), td0_r0, &carve_access).unwrap();
     let plug_access = Access::new(0x4000, 0x1000, Rights::all());
     let plug = engine.alias(td0.clone(), td0_r0, &plug_access).unwrap();
 
@@ -231,7 +231,7 @@ fn test_remap_illegal_in_hole() {
         .send(
             td0.clone(),
             child_td,
-            alias,
+            carved,
             Remapped::Remapped(0x10000),
             Attributes::empty(),
         )
@@ -328,3 +328,32 @@ fn test_remap_illegal_in_hole() {
     )];
     assert_eq!(view, expected);
 }
+
+#[test]
+fn test_remap_send_attributes() {
+    let (mut engine, td0, r0, td0_r0) = setup_engine_with_root();
+    let child_td = engine
+        .create(
+            &td0.clone(),
+            1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    let carve_access = Access::new(0x0, 0x1000, Rights::all());
+    let carved = engine.carve(td0.clone(), td0_r0, &carve_access).unwrap();
+
+    // The remap and attributes travel together with the region.
+    engine
+        .send(
+            td0.clone(),
+            child_td,
+            carved,
+            Remapped::Remapped(0x2000),
+            Attributes::CLEAN,
+        )
+        .unwrap();
+    let sent = r0.borrow().children[0].clone();
+    assert_eq!(sent.borrow().data.remapped, Remapped::Remapped(0x2000));
+    assert_eq!(sent.borrow().data.attributes, Attributes::CLEAN);
+}
@@ -1,4 +1,5 @@
 use capa_engine::core::capability::*;
+use capa_engine::core::coalesced::{diff_views, ViewDelta};
 use capa_engine::core::domain::*;
 use capa_engine::core::memory_region::{
     Access, Attributes, MemoryRegion, RegionKind, Remapped, Rights, Status as MStatus, ViewRegion,
@@ -666,3 +667,322 @@ fn test_view_child_end_overlap_remap() {
     let obtained = child.borrow().view().unwrap();
     assert_eq!(obtained, expected);
 }
+
+#[test]
+fn test_view_root_td_carve_guard() {
+    // Initial setup
+    let (mut engine, td0, r0, td0_r0) = setup_engine_with_root();
+
+    // Reserve a guard region with no access.
+    let guard_access = Access::new(0x1000, 0x1000, Rights::empty());
+    let _guard = engine.carve(td0.clone(), td0_r0, &guard_access).unwrap();
+
+    // The guard appears on its own, it never merges with its readable neighbors.
+    let expected = vec![
+        ViewRegion::new(Access::new(0x0, 0x1000, Rights::all()), Remapped::Identity),
+        ViewRegion::new(guard_access, Remapped::Identity),
+        ViewRegion::new(
+            Access::new(0x2000, 0xe000, Rights::all()),
+            Remapped::Identity,
+        ),
+    ];
+
+    let obtained = td0.borrow().view().unwrap();
+    assert_eq!(obtained, expected);
+    assert_eq!(obtained[1].access.rights, Rights::empty());
+    assert!(obtained[1].access.is_guard());
+
+    // The parent's own view has a hole where the guard is.
+    let expected_parent = vec![
+        ViewRegion::new(Access::new(0x0, 0x1000, Rights::all()), Remapped::Identity),
+        ViewRegion::new(
+            Access::new(0x2000, 0xe000, Rights::all()),
+            Remapped::Identity,
+        ),
+    ];
+    assert_eq!(r0.borrow().view(), expected_parent);
+}
+
+#[test]
+fn test_view_overlapping_remaps_rejected() {
+    let policies = Policies::new(1, MonitorAPI::all(), InterruptPolicy::default_none());
+    let mut domain = Capability::<Domain>::new(Domain::new(policies));
+
+    // Two distinct physical ranges remapped onto overlapping guest ranges.
+    for (start, gva) in [(0x0, 0x30000), (0x8000, 0x31000)] {
+        let region = Capability::<MemoryRegion>::new(MemoryRegion {
+            kind: RegionKind::Carve,
+            status: MStatus::Exclusive,
+            access: Access::new(start, 0x2000, Rights::READ | Rights::WRITE),
+            attributes: Attributes::NONE,
+            remapped: Remapped::Remapped(gva),
+        });
+        domain
+            .data
+            .install(CapaWrapper::Region(Rc::new(RefCell::new(region))))
+            .unwrap();
+    }
+
+    assert_eq!(domain.view(), Err(CapaError::DoubleRemapping));
+
+    // The overlap is caught even with a consistent region in between.
+    let rw = Rights::READ | Rights::WRITE;
+    let a = ViewRegion::new(Access::new(0x0, 0x3000, rw), Remapped::Remapped(0x10000));
+    let b = ViewRegion::new(Access::new(0x1000, 0x1000, rw), Remapped::Remapped(0x11000));
+    let c = ViewRegion::new(Access::new(0x8000, 0x1000, rw), Remapped::Remapped(0x12000));
+    assert_eq!(
+        Capability::<Domain>::validate_view(&[a, c]),
+        Err(CapaError::DoubleRemapping)
+    );
+    assert_eq!(
+        Capability::<Domain>::validate_view(&[a, b, c]),
+        Err(CapaError::DoubleRemapping)
+    );
+    assert_eq!(Capability::<Domain>::validate_view(&[a, b]), Ok(()));
+}
+
+#[test]
+fn test_view_diff_carve_sent_away() {
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let before = td0.borrow().view().unwrap();
+
+    let carve_access = Access::new(0x2000, 0x1000, Rights::all());
+    let carve = engine.carve(td0.clone(), td0_r0, &carve_access).unwrap();
+    let child_td = engine
+        .create(
+            &td0.clone(),
+            1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            child_td,
+            carve,
+            Remapped::Identity,
+            Attributes::empty(),
+        )
+        .unwrap();
+
+    let after = td0.borrow().view().unwrap();
+    assert_eq!(
+        diff_views(&before, &after),
+        vec![ViewDelta::Removed(ViewRegion::new(
+            carve_access,
+            Remapped::Identity
+        ))]
+    );
+}
+
+#[test]
+fn test_view_gva_order() {
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let child_td = engine
+        .create(
+            &td0.clone(),
+            1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+
+    // Physical order is the reverse of the guest order.
+    let low = Access::new(0x0, 0x1000, Rights::all());
+    let high = Access::new(0x2000, 0x1000, Rights::READ);
+    for (access, gva) in [(low, 0x50000), (high, 0x40000)] {
+        let carve = engine.carve(td0.clone(), td0_r0, &access).unwrap();
+        engine
+            .send(
+                td0.clone(),
+                child_td,
+                carve,
+                Remapped::Remapped(gva),
+                Attributes::empty(),
+            )
+            .unwrap();
+    }
+
+    let child = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&child_td)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    let low_view = ViewRegion::new(low, Remapped::Remapped(0x50000));
+    let high_view = ViewRegion::new(high, Remapped::Remapped(0x40000));
+    assert_eq!(child.borrow().view().unwrap(), vec![low_view, high_view]);
+    assert_eq!(
+        child.borrow().gva_view().unwrap(),
+        vec![high_view, low_view]
+    );
+}
+
+#[test]
+fn test_view_check_conflict() {
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let child_td = engine
+        .create(
+            &td0.clone(),
+            1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    let child = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&child_td)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+
+    // An empty domain accepts anything.
+    let first = Access::new(0x0, 0x2000, Rights::all());
+    assert_eq!(
+        child
+            .borrow()
+            .check_conflict(&ViewRegion::new(first, Remapped::Remapped(0x40000))),
+        Ok(())
+    );
+    let carve = engine.carve(td0.clone(), td0_r0, &first).unwrap();
+    engine
+        .send(
+            td0.clone(),
+            child_td,
+            carve,
+            Remapped::Remapped(0x40000),
+            Attributes::empty(),
+        )
+        .unwrap();
+
+    // Disjoint in the guest space.
+    let other = Access::new(0x4000, 0x1000, Rights::all());
+    assert_eq!(
+        child
+            .borrow()
+            .check_conflict(&ViewRegion::new(other, Remapped::Remapped(0x42000))),
+        Ok(())
+    );
+    // Partially overlapping with a different backing.
+    assert_eq!(
+        child
+            .borrow()
+            .check_conflict(&ViewRegion::new(other, Remapped::Remapped(0x41000))),
+        Err(CapaError::IncompatibleRemap)
+    );
+    let carve = engine.carve(td0.clone(), td0_r0, &other).unwrap();
+    assert_eq!(
+        engine.send(
+            td0.clone(),
+            child_td,
+            carve,
+            Remapped::Remapped(0x41000),
+            Attributes::empty(),
+        ),
+        Err(CapaError::IncompatibleRemap)
+    );
+}
+
+#[test]
+fn test_view_check_access() {
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    let child_td = engine
+        .create(
+            &td0.clone(),
+            1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    let access = Access::new(0x1000, 0x2000, Rights::READ | Rights::WRITE);
+    let carve = engine.carve(td0.clone(), td0_r0, &access).unwrap();
+    engine
+        .send(
+            td0.clone(),
+            child_td,
+            carve,
+            Remapped::Remapped(0x40000),
+            Attributes::empty(),
+        )
+        .unwrap();
+    let child = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&child_td)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+    let child = child.borrow();
+
+    // Mapped with enough rights, checked on the guest address.
+    assert_eq!(
+        child.check_access(0x41fff, Rights::READ | Rights::WRITE),
+        Ok(())
+    );
+    // Mapped, but not executable.
+    assert_eq!(
+        child.check_access(0x40000, Rights::EXECUTE),
+        Err(CapaError::InsufficientRights)
+    );
+    // The physical address and past the end are not mapped.
+    assert_eq!(
+        child.check_access(0x1000, Rights::READ),
+        Err(CapaError::InvalidAccess)
+    );
+    assert_eq!(
+        child.check_access(0x42000, Rights::READ),
+        Err(CapaError::InvalidAccess)
+    );
+}
+
+#[test]
+fn test_view_footprint() {
+    let (mut engine, td0, _r0, td0_r0) = setup_engine_with_root();
+    assert_eq!(td0.borrow().footprint(), Ok(0x10000));
+
+    // Same configuration as `test_view_sending_carve`.
+    let carve_access = Access::new(0x1000, 0x5000, Rights::READ | Rights::WRITE);
+    let carved = engine.carve(td0.clone(), td0_r0, &carve_access).unwrap();
+    let child_td = engine
+        .create(
+            &td0.clone(),
+            1,
+            MonitorAPI::all(),
+            InterruptPolicy::default_none(),
+        )
+        .unwrap();
+    engine
+        .send(
+            td0.clone(),
+            child_td,
+            carved,
+            Remapped::Identity,
+            Attributes::empty(),
+        )
+        .unwrap();
+    engine.seal(td0.clone(), child_td).unwrap();
+    let child = td0
+        .borrow()
+        .data
+        .capabilities
+        .get(&child_td)
+        .unwrap()
+        .as_domain()
+        .unwrap();
+
+    // The footprints add up to the root region.
+    assert_eq!(td0.borrow().footprint(), Ok(0xb000));
+    assert_eq!(child.borrow().footprint(), Ok(0x5000));
+
+    // An alias over memory the domain already sees is not counted twice.
+    engine
+        .alias(td0.clone(), td0_r0, &Access::new(0x0, 0x1000, Rights::READ))
+        .unwrap();
+    assert_eq!(td0.borrow().footprint(), Ok(0xb000));
+}
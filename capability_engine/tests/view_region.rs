@@ -0,0 +1,126 @@
+use capa_engine::core::memory_region::{Access, Remapped, Rights, ViewRegion};
+
+fn create_view() -> ViewRegion {
+    ViewRegion::new(
+        Access::new(0x1000, 0x4000, Rights::READ | Rights::WRITE),
+        Remapped::Remapped(0x10000),
+    )
+}
+
+#[test]
+fn test_subtract_hole_at_start() {
+    let view = create_view();
+    let obtained = view.subtract(&Access::new(0x1000, 0x1000, Rights::READ));
+    let expected = vec![ViewRegion::new(
+        Access::new(0x2000, 0x3000, Rights::READ | Rights::WRITE),
+        Remapped::Remapped(0x11000),
+    )];
+    assert_eq!(obtained, expected);
+}
+
+#[test]
+fn test_subtract_hole_at_end() {
+    let view = create_view();
+    let obtained = view.subtract(&Access::new(0x4000, 0x1000, Rights::READ));
+    let expected = vec![ViewRegion::new(
+        Access::new(0x1000, 0x3000, Rights::READ | Rights::WRITE),
+        Remapped::Remapped(0x10000),
+    )];
+    assert_eq!(obtained, expected);
+}
+
+#[test]
+fn test_subtract_hole_in_middle() {
+    let view = create_view();
+    let obtained = view.subtract(&Access::new(0x2000, 0x1000, Rights::READ));
+    let expected = vec![
+        ViewRegion::new(
+            Access::new(0x1000, 0x1000, Rights::READ | Rights::WRITE),
+            Remapped::Remapped(0x10000),
+        ),
+        ViewRegion::new(
+            Access::new(0x3000, 0x2000, Rights::READ | Rights::WRITE),
+            Remapped::Remapped(0x12000),
+        ),
+    ];
+    assert_eq!(obtained, expected);
+
+    // Identity stays identity.
+    let view = ViewRegion::new(view.access, Remapped::Identity);
+    let obtained = view.subtract(&Access::new(0x2000, 0x1000, Rights::READ));
+    assert_eq!(obtained[1].remap, Remapped::Identity);
+}
+
+#[test]
+fn test_subtract_hole_covering_everything() {
+    let view = create_view();
+    let obtained = view.subtract(&Access::new(0x0, 0x10000, Rights::READ));
+    assert!(obtained.is_empty());
+
+    // A disjoint hole leaves the view untouched.
+    let obtained = view.subtract(&Access::new(0x8000, 0x1000, Rights::READ));
+    assert_eq!(obtained, vec![view]);
+}
+
+#[test]
+fn test_sort_views_and_accesses() {
+    let a = ViewRegion::new(
+        Access::new(0x5000, 0x1000, Rights::READ),
+        Remapped::Identity,
+    );
+    let b = ViewRegion::new(
+        Access::new(0x1000, 0x1000, Rights::READ),
+        Remapped::Remapped(0x3000),
+    );
+    let c = ViewRegion::new(
+        Access::new(0x2000, 0x1000, Rights::WRITE),
+        Remapped::Identity,
+    );
+    let mut views = vec![a, b, c];
+    views.sort();
+    // Ordered by the active start.
+    assert_eq!(views, vec![c, b, a]);
+
+    let mut accesses = vec![
+        Access::new(0x2000, 0x1000, Rights::WRITE),
+        Access::new(0x1000, 0x2000, Rights::READ),
+        Access::new(0x1000, 0x1000, Rights::WRITE),
+        Access::new(0x1000, 0x1000, Rights::READ),
+    ];
+    accesses.sort();
+    assert_eq!(
+        accesses,
+        vec![
+            Access::new(0x1000, 0x1000, Rights::READ),
+            Access::new(0x1000, 0x1000, Rights::WRITE),
+            Access::new(0x1000, 0x2000, Rights::READ),
+            Access::new(0x2000, 0x1000, Rights::WRITE),
+        ]
+    );
+}
+
+#[test]
+fn test_remapped_offset_and_translate() {
+    assert_eq!(Remapped::Identity.offset(0x1000), Remapped::Identity);
+    assert_eq!(
+        Remapped::Remapped(0x10000).offset(0x1000),
+        Remapped::Remapped(0x11000)
+    );
+    assert_eq!(Remapped::Identity.translate(0x2000, 0x1000), 0x2000);
+    assert_eq!(
+        Remapped::Remapped(0x10000).translate(0x2000, 0x1000),
+        0x11000
+    );
+
+    // Never wrap around the address space.
+    assert_eq!(
+        Remapped::Remapped(u64::MAX - 0x10).offset(0x1000),
+        Remapped::Remapped(u64::MAX)
+    );
+    assert_eq!(
+        Remapped::Remapped(u64::MAX - 0x10).translate(0x2000, 0x1000),
+        u64::MAX
+    );
+    // An address before the region does not underflow.
+    assert_eq!(Remapped::Remapped(0x10000).translate(0x0, 0x1000), 0x10000);
+}